@@ -1,54 +1,107 @@
-use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, patch, post, web, App, HttpResponse, HttpServer, Responder, ResponseError};
 
 use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::TcpListener;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-mod order_matching_engine;
-use order_matching_engine::orderbook::{Order,  OrderBook, BidOrAsk};
-use order_matching_engine::engine::{TradingPair, MatchEngine};
+use std::time::Duration;
+use trading_engine::order_matching_engine;
+use order_matching_engine::orderbook::{Order,  OrderBook, BidOrAsk, MarketOrderPolicy, PostOnly, TimeInForce};
+use order_matching_engine::clock::Timestamp;
+use order_matching_engine::engine::{TradingPair, MatchEngine, NewOrderRequest};
+use order_matching_engine::error::{error_response, EngineError};
+use order_matching_engine::journal::{Command, CommandOutput, FsyncPolicy, Journal};
+use order_matching_engine::market_spec::MarketSpec;
+use order_matching_engine::replication::{self, Followers, StateHash};
+use order_matching_engine::snapshot;
 
+#[derive(Deserialize)]
+struct MarketOrderQuery {
+    // ImmediateOrCancel or FillOrKill, the engine default when missing
+    policy: Option<MarketOrderPolicy>,
+}
 
 #[post("/create_market_order/{base}_{quote}/{buy_or_sell}/{size}/{user_id}")]
 async fn create_market_order(data: web::Data<Arc<Mutex<MatchEngine>>>,
-    params: web::Path<(String, String, String, String, String)>) -> impl Responder {
+    params: web::Path<(String, String, String, String, String)>,
+    query: web::Query<MarketOrderQuery>) -> impl Responder {
         let size_or_wrong: String = params.3.to_string();
         let mut engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
-        match size_or_wrong.parse::<f64>() {
+        let policy = query.policy.unwrap_or(engine.market_order_policy());
+        match size_or_wrong.parse::<Decimal>() {
             Ok(size) => {
+                let pair: TradingPair = TradingPair::new(params.0.to_string() , params.1.to_string());
+                // Sizes come in base units, the engine works in whole lots of the market
+                let lots = match engine.size_to_lots(&pair, size) {
+                    Ok(lots) => lots,
+                    Err(err) => return err.error_response(),
+                };
                 match params.2.as_str(){
                     "buy"  => {
-                        let pair: TradingPair = TradingPair::new(params.0.to_string() , params.1.to_string());
-                                let mut order: Order  = Order::new(size, BidOrAsk::Bid);
+                                let mut order: Order  = Order::new(lots, BidOrAsk::Bid);
                                 order.set_user_id(params.4.to_string());
                                 
-                                match engine.fill_market_order_with_response(&pair, &mut order) {
-                                    Ok(answ) => {return HttpResponse::Ok().json(answ);},
-                                    Err(err) => {return HttpResponse::Ok().body(err)}
+                                match engine.execute(Command::FillMarketOrder { pair: pair.clone(), order, policy }) {
+                                    // A market order the engine turned away answers like one it refused outright
+                                    Ok(CommandOutput::Order(answ)) => match engine.rejection_error(&answ) {
+                                        Some(err) => err.error_response(),
+                                        None => HttpResponse::Ok().json(answ),
+                                    },
+                                    Ok(answ) => {HttpResponse::Ok().json(answ)},
+                                    Err(err) => {err.error_response()}
 
                                 }
                     },
                     "sell" => {
-                        let pair: TradingPair = TradingPair::new(params.0.to_string() , params.1.to_string());
-                                let mut order: Order  = Order::new(size, BidOrAsk::Ask);
+                                let mut order: Order  = Order::new(lots, BidOrAsk::Ask);
                                 order.set_user_id(params.4.to_string());
                                 
-                                match engine.fill_market_order_with_response(&pair, &mut order) {
-                                    Ok(answ) => {return HttpResponse::Ok().json(answ);},
-                                    Err(err) => {return HttpResponse::Ok().body(err)}
+                                match engine.execute(Command::FillMarketOrder { pair: pair.clone(), order, policy }) {
+                                    // A market order the engine turned away answers like one it refused outright
+                                    Ok(CommandOutput::Order(answ)) => match engine.rejection_error(&answ) {
+                                        Some(err) => err.error_response(),
+                                        None => HttpResponse::Ok().json(answ),
+                                    },
+                                    Ok(answ) => {HttpResponse::Ok().json(answ)},
+                                    Err(err) => {err.error_response()}
                                     
                                 }
                     },
-                    _ => {return HttpResponse::Ok().body("Wrong order type");}
+                    _ => {error_response(StatusCode::BAD_REQUEST, "invalid_side", "Wrong order type (should be buy or sell)")}
 
 
-                };
+                }
             }
                 
-            Err(_) => HttpResponse::Ok().body("Wrong price format")  }
+            Err(_) => error_response(StatusCode::BAD_REQUEST, "invalid_size", "Wrong size format")  }
     }
 
+#[derive(Deserialize)]
+struct LimitOrderQuery {
+    // GoodTillCancel (default), ImmediateOrCancel, FillOrKill or Day
+    time_in_force: Option<TimeInForce>,
+    // Makes the order good-till-date, ms since epoch
+    good_till: Option<Timestamp>,
+    // Reject (or Slide) instead of taking liquidity
+    post_only: Option<PostOnly>,
+}
+
+impl LimitOrderQuery {
+    fn time_in_force(&self) -> TimeInForce {
+        match self.good_till {
+            Some(deadline) => TimeInForce::GoodTillDate(deadline),
+            None => self.time_in_force.unwrap_or_default(),
+        }
+    }
+}
+
 #[post("/create_limit_order/{base}_{quote}/{buy_or_sell}/{price}/{size}/{user_id}")]
 async fn create_limit_order(data: web::Data<Arc<Mutex<MatchEngine>>>,
-    params: web::Path<(String, String, String, String, String, String)>) -> impl Responder {
+    params: web::Path<(String, String, String, String, String, String)>,
+    query: web::Query<LimitOrderQuery>) -> impl Responder {
         let mut engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
         //let order: Order  = Order::new();
         let price_or_wrong: String = params.3.to_string();
@@ -56,44 +109,61 @@ async fn create_limit_order(data: web::Data<Arc<Mutex<MatchEngine>>>,
         match price_or_wrong.parse::<Decimal>() {
             Ok(price) => {
                 let size_or_wrong = params.4.to_string();
-                match size_or_wrong.parse::<f64>() {
+                match size_or_wrong.parse::<Decimal>() {
                     Ok(size) => {
+                        let pair: TradingPair = TradingPair::new(params.0.to_string() , params.1.to_string());
+                        let lots = match engine.size_to_lots(&pair, size) {
+                            Ok(lots) => lots,
+                            Err(err) => return err.error_response(),
+                        };
                         match params.2.as_str(){
                             "buy" => {
-                                let pair: TradingPair = TradingPair::new(params.0.to_string() , params.1.to_string());
-                                let mut order: Order  = Order::new(size, BidOrAsk::Bid);
+                                let mut order: Order  = Order::new(lots, BidOrAsk::Bid);
                                 order.set_user_id(params.5.to_string());
+                                order.set_time_in_force(query.time_in_force());
+                                order.set_post_only(query.post_only.unwrap_or_default());
                                 
                                 
                                 
                                 
-                                match engine.place_limit_order_with_response(&pair, price, order){
-                                    Ok(answ) => {return HttpResponse::Ok().json(answ);}
-                                    Err(error_msg) => {return HttpResponse::Ok().body(error_msg);}
+                                match engine.execute(Command::PlaceLimitOrder { pair: pair.clone(), price, order }) {
+                                    // So does a rejected post-only or unfilled fill-or-kill order
+                                    Ok(CommandOutput::Order(answ)) => match engine.rejection_error(&answ) {
+                                        Some(err) => err.error_response(),
+                                        None => HttpResponse::Ok().json(answ),
+                                    },
+                                    Ok(answ) => {HttpResponse::Ok().json(answ)}
+                                    Err(error_msg) => {error_msg.error_response()}
                                 } 
                             },
                             "sell" => {
-                                let pair: TradingPair = TradingPair::new(params.0.to_string() , params.1.to_string());
-                                let mut order: Order  = Order::new(size, BidOrAsk::Ask);
+                                let mut order: Order  = Order::new(lots, BidOrAsk::Ask);
                                 order.set_user_id(params.5.to_string());
+                                order.set_time_in_force(query.time_in_force());
+                                order.set_post_only(query.post_only.unwrap_or_default());
                                 
                                 
                                 
-                                match engine.place_limit_order_with_response(&pair, price, order){
-                                    Ok(answ) => {return HttpResponse::Ok().json(answ);}
-                                    Err(error_msg) => {return HttpResponse::Ok().body(error_msg);}
+                                match engine.execute(Command::PlaceLimitOrder { pair: pair.clone(), price, order }) {
+                                    // So does a rejected post-only or unfilled fill-or-kill order
+                                    Ok(CommandOutput::Order(answ)) => match engine.rejection_error(&answ) {
+                                        Some(err) => err.error_response(),
+                                        None => HttpResponse::Ok().json(answ),
+                                    },
+                                    Ok(answ) => {HttpResponse::Ok().json(answ)}
+                                    Err(error_msg) => {error_msg.error_response()}
                                 } 
                             },
-                            _ => return HttpResponse::Ok().body("Wrong order type (should be buy or sell)"),
+                            _ => error_response(StatusCode::BAD_REQUEST, "invalid_side", "Wrong order type (should be buy or sell)"),
                         }
                     }
-                    Err(_) => {return HttpResponse::Ok().body("Wrong size format")  ;}
+                    Err(_) => {error_response(StatusCode::BAD_REQUEST, "invalid_size", "Wrong size format")}
                     }
                 
             }
             Err(_) => {
                 // Parsing failed, return an HTTP response with an error message.
-                return HttpResponse::Ok().body("Invalid price format");
+                error_response(StatusCode::BAD_REQUEST, "invalid_price", "Invalid price format")
             }
         }
             
@@ -101,6 +171,22 @@ async fn create_limit_order(data: web::Data<Arc<Mutex<MatchEngine>>>,
        
     }
 
+// Order entry with a JSON body, so symbols and optional fields don't have to fit in the path
+#[post("/v2/orders")]
+async fn create_order_v2(data: web::Data<Arc<Mutex<MatchEngine>>>,
+    request: web::Json<NewOrderRequest>) -> impl Responder {
+        let mut engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
+        match engine.execute(Command::SubmitOrder(request.into_inner())) {
+            // An order the engine turned away answers like one it refused outright
+            Ok(CommandOutput::Order(answ)) => match engine.rejection_error(&answ) {
+                Some(err) => err.error_response(),
+                None => HttpResponse::Created().json(answ),
+            },
+            Ok(answ) => HttpResponse::Created().json(answ),
+            Err(err) => err.error_response(),
+        }
+    }
+
 #[get("/get_list_of_pairs")]
 async fn get_list_of_pairs(data: web::Data<Arc<Mutex<MatchEngine>>>) -> impl Responder {
     let answ: Vec<Vec<String>> = {
@@ -118,16 +204,33 @@ async fn get_limits_for_a_pair(data: web::Data<Arc<Mutex<MatchEngine>>>,
     let engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
     let order_book: Option<&OrderBook> = engine.get_limits_for_a_pair(&pair);
 
-    // Serialize OrderBook to JSON and then deserialize it back to create a deep copy
+    // Hidden iceberg quantity stays out of the public copy
     match order_book {
-        None => HttpResponse::Ok().json(""),
-        _ => {
-            let order_book_json = serde_json::to_string(&order_book).unwrap();
-            let cloned_order_book: OrderBook = serde_json::from_str(&order_book_json).unwrap();
+        None => EngineError::UnknownMarket(pair).error_response(),
+        Some(order_book) => HttpResponse::Ok().json(order_book.public_view()),
+    }
+    }
 
-            HttpResponse::Ok().json(cloned_order_book)
+#[get("/markets/{base}_{quote}")]
+async fn get_market_spec(data: web::Data<Arc<Mutex<MatchEngine>>>,
+    params: web::Path<(String, String)>) -> impl Responder {
+        let pair: TradingPair = TradingPair::new(params.0.to_string(), params.1.to_string());
+        let engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
+        match engine.market_spec_for_pair(&pair) {
+            Some(spec) => HttpResponse::Ok().json(spec),
+            None => EngineError::UnknownMarket(pair).error_response(),
         }
     }
+
+#[get("/trades/{base}_{quote}")]
+async fn get_trades_for_a_pair(data: web::Data<Arc<Mutex<MatchEngine>>>,
+    params: web::Path<(String, String)>) -> impl Responder {
+        let pair: TradingPair = TradingPair::new(params.0.to_string(), params.1.to_string());
+        let engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
+        match engine.get_trades_for_pair(&pair) {
+            Some(trades) => HttpResponse::Ok().json(trades),
+            None => EngineError::UnknownMarket(pair).error_response(),
+        }
     }
 
 #[get("/orders/{order_id}")]
@@ -136,7 +239,47 @@ async fn get_order_status(data: web::Data<Arc<Mutex<MatchEngine>>>,
         let engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
         match engine.get_order(params.into_inner()) {
             Some(order) => HttpResponse::Ok().json(order),
-            None => HttpResponse::NotFound().body("Order not found"),
+            None => error_response(StatusCode::NOT_FOUND, "unknown_order", "Order not found"),
+        }
+    }
+
+#[get("/orders/{order_id}/fills")]
+async fn get_order_fills(data: web::Data<Arc<Mutex<MatchEngine>>>,
+    params: web::Path<u64>) -> impl Responder {
+        let engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
+        match engine.get_order_fills(params.into_inner()) {
+            Some(fills) => HttpResponse::Ok().json(fills),
+            None => error_response(StatusCode::NOT_FOUND, "unknown_order", "Order not found"),
+        }
+    }
+
+#[delete("/orders/{order_id}")]
+async fn cancel_order(data: web::Data<Arc<Mutex<MatchEngine>>>,
+    params: web::Path<u64>) -> impl Responder {
+        let mut engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
+        match engine.execute(Command::CancelOrder { order_id: params.into_inner() }) {
+            Ok(answ) => HttpResponse::Ok().json(answ),
+            Err(err) => err.error_response(),
+        }
+    }
+
+#[derive(Deserialize)]
+struct AmendOrderRequest {
+    // New limit price, unchanged when missing
+    price: Option<Decimal>,
+    // New open size, unchanged when missing
+    size: Option<Decimal>,
+}
+
+// Reducing the size keeps the order's place in the queue, anything else replaces it
+#[patch("/orders/{order_id}")]
+async fn amend_order(data: web::Data<Arc<Mutex<MatchEngine>>>,
+    params: web::Path<u64>, request: web::Json<AmendOrderRequest>) -> impl Responder {
+        let mut engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
+        let command = Command::AmendOrder { order_id: params.into_inner(), price: request.price, size: request.size };
+        match engine.execute(command) {
+            Ok(answ) => HttpResponse::Ok().json(answ),
+            Err(err) => err.error_response(),
         }
     }
 
@@ -148,6 +291,171 @@ async fn get_orders_for_user(data: web::Data<Arc<Mutex<MatchEngine>>>,
         HttpResponse::Ok().json(orders)
     }
 
+#[get("/users/{user_id}/client_orders/{client_order_id}")]
+async fn get_order_by_client_id(data: web::Data<Arc<Mutex<MatchEngine>>>,
+    params: web::Path<(String, String)>) -> impl Responder {
+        let (user_id, client_order_id) = params.into_inner();
+        let engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
+        match engine.get_order_by_client_id(&user_id, &client_order_id) {
+            Ok(order) => HttpResponse::Ok().json(order),
+            Err(err) => err.error_response(),
+        }
+    }
+
+#[delete("/users/{user_id}/client_orders/{client_order_id}")]
+async fn cancel_order_by_client_id(data: web::Data<Arc<Mutex<MatchEngine>>>,
+    params: web::Path<(String, String)>) -> impl Responder {
+        let (user_id, client_order_id) = params.into_inner();
+        let mut engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
+        match engine.execute(Command::CancelOrderByClientId { user_id, client_order_id }) {
+            Ok(answ) => HttpResponse::Ok().json(answ),
+            Err(err) => err.error_response(),
+        }
+    }
+
+#[get("/users/{user_id}/balances")]
+async fn get_balances(data: web::Data<Arc<Mutex<MatchEngine>>>,
+    params: web::Path<String>) -> impl Responder {
+        let engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
+        HttpResponse::Ok().json(engine.balances(params.as_str()))
+    }
+
+#[get("/users/{user_id}/balances/{asset}")]
+async fn get_balance(data: web::Data<Arc<Mutex<MatchEngine>>>,
+    params: web::Path<(String, String)>) -> impl Responder {
+        let (user_id, asset) = params.into_inner();
+        let engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
+        HttpResponse::Ok().json(engine.balance(&user_id, &asset))
+    }
+
+
+#[derive(Deserialize)]
+struct AdjustmentRequest {
+    asset: String,
+    amount: Decimal,
+}
+
+// Admin: deposit into a user's available balance, answers with the journal entry id
+#[post("/admin/users/{user_id}/credit")]
+async fn credit_user(data: web::Data<Arc<Mutex<MatchEngine>>>,
+    params: web::Path<String>, request: web::Json<AdjustmentRequest>) -> impl Responder {
+        let mut engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
+        let AdjustmentRequest { asset, amount } = request.into_inner();
+        match engine.execute(Command::Deposit { user_id: params.into_inner(), asset, amount }) {
+            Ok(entry_id) => HttpResponse::Ok().json(entry_id),
+            Err(err) => err.error_response(),
+        }
+    }
+
+// Admin: withdraw from a user's available balance
+#[post("/admin/users/{user_id}/debit")]
+async fn debit_user(data: web::Data<Arc<Mutex<MatchEngine>>>,
+    params: web::Path<String>, request: web::Json<AdjustmentRequest>) -> impl Responder {
+        let mut engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
+        let AdjustmentRequest { asset, amount } = request.into_inner();
+        match engine.execute(Command::Withdraw { user_id: params.into_inner(), asset, amount }) {
+            Ok(entry_id) => HttpResponse::Ok().json(entry_id),
+            Err(err) => err.error_response(),
+        }
+    }
+
+#[get("/admin/ledger")]
+async fn get_ledger(data: web::Data<Arc<Mutex<MatchEngine>>>) -> impl Responder {
+    let engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
+    HttpResponse::Ok().json(engine.ledger().entries())
+}
+
+#[get("/admin/ledger/check")]
+async fn check_ledger(data: web::Data<Arc<Mutex<MatchEngine>>>) -> impl Responder {
+    let engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
+    match engine.check_ledger() {
+        Ok(()) => HttpResponse::Ok().json("consistent"),
+        Err(err) => err.error_response(),
+    }
+}
+
+// Where the engine keeps itself, from SNAPSHOT_DIR, JOURNAL_PATH and JOURNAL_FSYNC
+struct Storage {
+    snapshot_dir: Option<PathBuf>,
+    journal_path: Option<PathBuf>,
+    fsync: FsyncPolicy,
+}
+
+// Admin: write a snapshot of the engine now, answers with its path, sequence and checksum
+#[post("/admin/snapshot")]
+async fn create_snapshot(data: web::Data<Arc<Mutex<MatchEngine>>>,
+    storage: web::Data<Storage>) -> impl Responder {
+        let Some(dir) = storage.snapshot_dir.as_deref() else {
+            return EngineError::InvalidRequest("snapshots are off, set SNAPSHOT_DIR".to_string()).error_response();
+        };
+        // Copy the state under the lock, write it outside
+        let state = data.lock().unwrap().state();
+        match snapshot::write(dir, &state) {
+            Ok(info) => HttpResponse::Ok().json(info),
+            Err(err) => EngineError::Internal(format!("can't write a snapshot to {}: {}", dir.display(), err)).error_response(),
+        }
+    }
+
+#[derive(Serialize)]
+struct ReplicationStatus {
+    role: &'static str,
+    sequence: u64,
+    state_hash: String,
+    // Peer address -> last sequence it acked
+    followers: BTreeMap<String, u64>,
+}
+
+fn replication_status(engine: &MatchEngine, followers: &Followers) -> ReplicationStatus {
+    let StateHash { sequence, state_hash } = StateHash::of(engine);
+    ReplicationStatus {
+        role: if engine.is_following() { "follower" } else { "leader" },
+        sequence,
+        state_hash: format!("{:016x}", state_hash),
+        followers: followers.lock().unwrap().clone(),
+    }
+}
+
+#[get("/admin/replication")]
+async fn get_replication(data: web::Data<Arc<Mutex<MatchEngine>>>,
+    followers: web::Data<Followers>) -> impl Responder {
+        let engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
+        HttpResponse::Ok().json(replication_status(&engine, &followers))
+    }
+
+// Admin: make a follower the leader. With JOURNAL_PATH set it journals from here on,
+// after a snapshot of what it replicated
+#[post("/admin/promote")]
+async fn promote(data: web::Data<Arc<Mutex<MatchEngine>>>,
+    storage: web::Data<Storage>, followers: web::Data<Followers>) -> impl Responder {
+        let mut engine: std::sync::MutexGuard<'_, MatchEngine> = data.lock().unwrap();
+        if !engine.is_following() {
+            return EngineError::InvalidRequest("this engine is already the leader".to_string()).error_response();
+        }
+        if let Err(err) = start_journal(&mut engine, &storage) {
+            return err.error_response();
+        }
+        engine.set_following(false);
+        HttpResponse::Ok().json(replication_status(&engine, &followers))
+    }
+
+fn start_journal(engine: &mut MatchEngine, storage: &Storage) -> Result<(), EngineError> {
+    let Some(path) = storage.journal_path.as_deref() else {
+        return Ok(());
+    };
+    let internal = |err: std::io::Error| EngineError::Internal(format!("can't open journal {}: {}", path.display(), err));
+    let mut journal = Journal::open(path, storage.fsync).map_err(internal)?;
+    if journal.last_sequence() != engine.last_sequence() {
+        // The replicated commands go into a snapshot and the journal carries on after it
+        let dir = storage.snapshot_dir.as_deref().ok_or_else(|| {
+            EngineError::InvalidRequest("a promoted follower needs SNAPSHOT_DIR to journal".to_string())
+        })?;
+        engine.write_snapshot(dir)?;
+        journal
+            .start_after(engine.last_sequence())
+            .map_err(|err| EngineError::InvalidRequest(err.to_string()))?;
+    }
+    engine.set_journal(journal)
+}
 
 #[post("/echo")]
 async fn echo(_req_body: String) -> impl Responder {
@@ -162,28 +470,242 @@ async fn manual_hello() -> impl Responder {
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    // `--check-replication <addr> <addr>...` compares the state hashes of engines serving
+    // replication at those addresses, once they are at the same command, and exits
+    if let Some(position) = args.iter().position(|arg| arg == "--check-replication") {
+        let addrs = &args[position + 1..];
+        if addrs.len() < 2 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "--check-replication needs two or more addresses"));
+        }
+        let hashes = replication::check(addrs, Duration::from_secs(10))?;
+        for (addr, hash) in addrs.iter().zip(&hashes) {
+            println!("{} at command {}: state hash {:016x}", addr, hash.sequence, hash.state_hash);
+        }
+        if hashes.windows(2).any(|pair| pair[0] != pair[1]) {
+            return Err(std::io::Error::other("the engines are not in sync"));
+        }
+        println!("in sync");
+        return Ok(());
+    }
+    // `--follow <addr>` replicates the leader serving replication at that address and takes
+    // no commands of its own until promoted through /admin/promote
+    let leader: Option<String> = match args.iter().position(|arg| arg == "--follow") {
+        Some(position) => Some(args.get(position + 1).cloned().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "--follow needs the leader's replication address")
+        })?),
+        None => None,
+    };
+
     let mut engine: MatchEngine = MatchEngine::new();
+    engine.set_following(leader.is_some());
+    let snapshot_dir: Option<PathBuf> = std::env::var("SNAPSHOT_DIR").ok().map(PathBuf::from);
+    let journal_path: Option<PathBuf> = std::env::var("JOURNAL_PATH").ok().map(PathBuf::from);
+    let fsync: FsyncPolicy = match std::env::var("JOURNAL_FSYNC") {
+        Ok(policy) => policy.parse().map_err(|err: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?,
+        Err(_) => FsyncPolicy::default(),
+    };
+    let replay: Option<usize> = args.iter().position(|arg| arg == "--replay");
+    if snapshot_dir.is_some() && replay.is_some() {
+        // The snapshot and JOURNAL_PATH already decide where the engine starts from
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--replay can't be used with SNAPSHOT_DIR, which restores from JOURNAL_PATH",
+        ));
+    }
+    if let Some(dir) = snapshot_dir.as_deref() {
+        // With SNAPSHOT_DIR set, start from the latest snapshot there plus whatever
+        // JOURNAL_PATH has after it
+        let restored = engine
+            .restore(dir, journal_path.as_deref())
+            .map_err(|err| std::io::Error::other(err.to_string()))?;
+        if let Some(path) = &restored.snapshot {
+            println!("restored snapshot {}", path.display());
+        }
+        println!("replayed {} commands, at command {}", restored.replayed, restored.sequence);
+    } else if let Some(position) = replay {
+        // `--replay <journal>` rebuilds the engine from a journal before serving; to carry on
+        // journaling, point JOURNAL_PATH at the same file
+        let path = args.get(position + 1).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "--replay needs a journal file")
+        })?;
+        let replayed = engine.replay_file(path).map_err(|err| std::io::Error::other(err.to_string()))?;
+        println!("replayed {} commands from {}", replayed, path);
+    }
+    // With JOURNAL_PATH set, every command is appended there before it is applied;
+    // JOURNAL_FSYNC is always (default), never or a batch size. A follower starts
+    // journaling when it is promoted
+    if let (Some(path), None) = (journal_path.as_deref(), &leader) {
+        engine
+            .set_journal(Journal::open(path, fsync)?)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err.to_string()))?;
+    }
     let btc_usd: TradingPair = TradingPair::new(String::from("btc"), String::from("usd"));
     let btc_eth: TradingPair = TradingPair::new(String::from("btc"), String::from("eth"));
-    {
-        engine.add_new_market(btc_usd.clone());
-        engine.add_new_market(btc_eth.clone());
+    // A replayed engine already has its markets and settings, a follower gets the leader's
+    if engine.last_sequence() == 0 && leader.is_none() {
+        let startup = [
+            Command::AddMarket { pair: btc_usd.clone(), spec: MarketSpec::default() },
+            Command::AddMarket { pair: btc_eth.clone(), spec: MarketSpec::default() },
+            // Orders must be covered by balances funded through /admin/users/{id}/credit
+            Command::SetEnforceBalances { enabled: std::env::var("ENFORCE_BALANCES").is_ok_and(|value| value == "1") },
+        ];
+        for command in startup {
+            engine
+                .execute(command)
+                .map_err(|err| std::io::Error::other(err.to_string()))?;
+        }
     }
     let data: web::Data<Arc<Mutex<MatchEngine>>> = web::Data::new(Arc::new(Mutex::new(engine)));
+
+    // With REPLICATION_LISTEN set, followers and checkers connect there
+    let followers: Followers = Followers::default();
+    if let Ok(addr) = std::env::var("REPLICATION_LISTEN") {
+        replication::serve(TcpListener::bind(&addr)?, Arc::clone(data.get_ref()), followers.clone());
+    }
+    if let Some(leader) = leader {
+        let engine = Arc::clone(data.get_ref());
+        std::thread::spawn(move || {
+            // Reconnects until promoted; a follower that fell out of step gets the leader's state
+            while engine.lock().unwrap().is_following() {
+                if let Err(err) = replication::follow(&leader, &engine) {
+                    eprintln!("following {} failed: {}", leader, err);
+                }
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        });
+    }
+
+    // With SNAPSHOT_INTERVAL_SECS set too, snapshot every so often when commands came in
+    if let (Some(dir), Ok(interval)) = (snapshot_dir.clone(), std::env::var("SNAPSHOT_INTERVAL_SECS")) {
+        let interval: u64 = interval
+            .parse()
+            .ok()
+            .filter(|secs| *secs > 0)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "SNAPSHOT_INTERVAL_SECS must be a positive number of seconds"))?;
+        let snapshot_data = data.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(Duration::from_secs(interval));
+            let mut last_snapshot = None;
+            loop {
+                interval.tick().await;
+                let state = {
+                    let engine = snapshot_data.lock().unwrap();
+                    if last_snapshot == Some(engine.last_sequence()) {
+                        continue;
+                    }
+                    engine.state()
+                };
+                match snapshot::write(&dir, &state) {
+                    Ok(info) => last_snapshot = Some(info.sequence),
+                    Err(err) => eprintln!("snapshot failed: {}", err),
+                }
+            }
+        });
+    }
+    let storage = web::Data::new(Storage { snapshot_dir, journal_path, fsync });
+    let followers = web::Data::new(followers);
+    let port: u16 = match std::env::var("PORT") {
+        Ok(port) => port.parse().map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "PORT must be a port number"))?,
+        Err(_) => 8081,
+    };
+
+    // Sweep DAY and good-till-date orders once their deadline has passed
+    let expiry_data = data.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let mut engine = expiry_data.lock().unwrap();
+            // Only sweeps that expire something are worth a journal record; followers
+            // get the leader's sweeps
+            if !engine.is_following() && engine.has_due_expiries() {
+                if let Err(err) = engine.execute(Command::ExpireOrders) {
+                    eprintln!("expiry sweep failed: {}", err);
+                }
+            }
+        }
+    });
     
     HttpServer::new(move || {
         App::new()
             .app_data(data.clone())
+            .app_data(storage.clone())
+            .app_data(followers.clone())
+            .app_data(web::JsonConfig::default().error_handler(|err, _req| {
+                let response = error_response(StatusCode::BAD_REQUEST, "invalid_request", err.to_string());
+                actix_web::error::InternalError::from_response(err, response).into()
+            }))
+            .service(create_order_v2)
             .service(create_limit_order)
             .service(get_list_of_pairs)
             .service(get_limits_for_a_pair)
+            .service(get_trades_for_a_pair)
+            .service(get_market_spec)
             .service(get_order_status)
+            .service(get_order_fills)
+            .service(cancel_order)
+            .service(amend_order)
             .service(get_orders_for_user)
+            .service(get_order_by_client_id)
+            .service(cancel_order_by_client_id)
+            .service(get_balances)
+            .service(get_balance)
+            .service(credit_user)
+            .service(debit_user)
+            .service(get_ledger)
+            .service(check_ledger)
+            .service(create_snapshot)
+            .service(get_replication)
+            .service(promote)
             .service(echo)     
             .service(create_market_order)
             .route("/hey", web::get().to(manual_hello))
     })
-    .bind(("0.0.0.0", 8081))?
+    .bind(("0.0.0.0", port))?
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    fn test_engine() -> web::Data<Arc<Mutex<MatchEngine>>> {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        engine.execute(Command::AddMarket { pair: btc_usd, spec: MarketSpec::default() }).unwrap();
+        web::Data::new(Arc::new(Mutex::new(engine)))
+    }
+
+    #[actix_web::test]
+    async fn test_legacy_routes_answer_rejected_orders_with_an_error() {
+        let app = test::init_service(
+            App::new().app_data(test_engine()).service(create_market_order).service(create_limit_order),
+        )
+        .await;
+
+        // Nothing to fill against: a fill-or-kill market order is turned away
+        let request = test::TestRequest::post()
+            .uri("/create_market_order/btc_usd/buy/1/alice?policy=FillOrKill")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(body["code"], "insufficient_liquidity");
+
+        let request = test::TestRequest::post().uri("/create_limit_order/btc_usd/sell/100/1/bob").to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+
+        // A post-only bid at the ask would take liquidity
+        let request = test::TestRequest::post()
+            .uri("/create_limit_order/btc_usd/buy/100/1/alice?post_only=Reject")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let request = test::TestRequest::post().uri("/create_market_order/btc_usd/buy/1/alice").to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,136 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::error::EngineError;
+
+/// Account that collects trading fees and pays maker rebates.
+pub const HOUSE_ACCOUNT: &str = "house";
+
+/// Funds of one user in one asset. `locked` is held by working orders.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Balance {
+    pub available: Decimal,
+    pub locked: Decimal,
+}
+
+impl Balance {
+    pub fn total(&self) -> Decimal {
+        self.available + self.locked
+    }
+}
+
+/// Per-user, per-asset balances. Amounts are in asset units.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Accounts {
+    // user id -> asset -> balance
+    balances: BTreeMap<String, BTreeMap<String, Balance>>,
+}
+
+impl Accounts {
+    pub fn new() -> Accounts {
+        Accounts::default()
+    }
+
+    pub fn balance(&self, user_id: &str, asset: &str) -> Balance {
+        self.balances
+            .get(user_id)
+            .and_then(|assets| assets.get(asset))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Every asset the user holds or has held.
+    pub fn balances(&self, user_id: &str) -> BTreeMap<String, Balance> {
+        self.balances.get(user_id).cloned().unwrap_or_default()
+    }
+
+    /// Sum of every user's balance in `asset`.
+    pub fn asset_total(&self, asset: &str) -> Decimal {
+        self.balances
+            .values()
+            .filter_map(|assets| assets.get(asset))
+            .map(Balance::total)
+            .sum()
+    }
+
+    /// Every (user, asset, balance) held.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str, &Balance)> + '_ {
+        self.balances.iter().flat_map(|(user_id, assets)| {
+            assets
+                .iter()
+                .map(move |(asset, balance)| (user_id.as_str(), asset.as_str(), balance))
+        })
+    }
+
+    pub fn assets(&self) -> BTreeSet<String> {
+        self.balances.values().flat_map(|assets| assets.keys().cloned()).collect()
+    }
+
+    fn entry(&mut self, user_id: &str, asset: &str) -> &mut Balance {
+        self.balances
+            .entry(user_id.to_string())
+            .or_default()
+            .entry(asset.to_string())
+            .or_default()
+    }
+
+    fn check_amount(amount: Decimal) -> Result<(), EngineError> {
+        if amount <= Decimal::ZERO {
+            return Err(EngineError::InvalidRequest(format!("amount {} must be positive", amount)));
+        }
+        Ok(())
+    }
+
+    pub fn deposit(&mut self, user_id: &str, asset: &str, amount: Decimal) -> Result<(), EngineError> {
+        Self::check_amount(amount)?;
+        self.entry(user_id, asset).available += amount;
+        Ok(())
+    }
+
+    /// Takes `amount` out of the available balance; locked funds can't be withdrawn.
+    pub fn withdraw(&mut self, user_id: &str, asset: &str, amount: Decimal) -> Result<(), EngineError> {
+        Self::check_amount(amount)?;
+        let balance = self.entry(user_id, asset);
+        if balance.available < amount {
+            return Err(EngineError::InsufficientFunds {
+                asset: asset.to_string(),
+                required: amount,
+                available: balance.available,
+            });
+        }
+        balance.available -= amount;
+        Ok(())
+    }
+
+    /// Moves `amount` from available to locked.
+    pub fn lock(&mut self, user_id: &str, asset: &str, amount: Decimal) -> Result<(), EngineError> {
+        let balance = self.entry(user_id, asset);
+        if balance.available < amount {
+            return Err(EngineError::InsufficientFunds {
+                asset: asset.to_string(),
+                required: amount,
+                available: balance.available,
+            });
+        }
+        balance.available -= amount;
+        balance.locked += amount;
+        Ok(())
+    }
+
+    /// Moves `amount` from locked back to available.
+    pub fn unlock(&mut self, user_id: &str, asset: &str, amount: Decimal) {
+        let balance = self.entry(user_id, asset);
+        balance.locked -= amount;
+        balance.available += amount;
+    }
+
+    /// Pays `amount` out of the locked balance.
+    pub fn spend_locked(&mut self, user_id: &str, asset: &str, amount: Decimal) {
+        self.entry(user_id, asset).locked -= amount;
+    }
+
+    pub fn credit(&mut self, user_id: &str, asset: &str, amount: Decimal) {
+        self.entry(user_id, asset).available += amount;
+    }
+}
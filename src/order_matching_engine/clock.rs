@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Milliseconds since the Unix epoch.
+pub type Timestamp = u64;
+
+pub const MILLIS_PER_DAY: Timestamp = 24 * 60 * 60 * 1000;
+
+/// Source of time for the engine, injectable so expiry can be tested deterministically.
+pub trait Clock: fmt::Debug + Send {
+    fn now(&self) -> Timestamp;
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as Timestamp)
+            .unwrap_or(0)
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    now: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn new(now: Timestamp) -> ManualClock {
+        ManualClock { now: Arc::new(AtomicU64::new(now)) }
+    }
+
+    pub fn set(&self, now: Timestamp) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, millis: Timestamp) {
+        self.now.fetch_add(millis, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Timestamp {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+/// End of the UTC day `now` falls in, i.e. the next midnight.
+pub fn end_of_day(now: Timestamp) -> Timestamp {
+    (now / MILLIS_PER_DAY + 1) * MILLIS_PER_DAY
+}
@@ -1,14 +1,35 @@
 use rust_decimal::Decimal;
 use rust_decimal::prelude::ToPrimitive;
 
-use super::orderbook::{BidOrAsk, FillReport, Order, OrderBook, Tick};
+use super::accounts::{Accounts, Balance, HOUSE_ACCOUNT};
+use super::clock::{end_of_day, Clock, SystemClock, Timestamp, MILLIS_PER_DAY};
+use super::error::EngineError;
+use super::ledger::{EntryKind, Ledger, LedgerAccount, Posting};
+use super::market_spec::{FeeSchedule, MarketSpec, OrderRejection};
+use super::journal::{Command, CommandOutput, Journal, JournalRecord};
+use super::snapshot::{self, Restored, SnapshotInfo};
+use super::trigger_book::{PendingStop, TrailingOffset, TriggerBook};
+use super::orderbook::{
+    BidOrAsk, Fill, FillReport, MarketOrderPolicy, Order, OrderBook, OrderId, PostOnly, Qty, SelfTradePrevention, Tick,
+    TimeInForce,
+};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
 
 pub type MarketId = u32;
 
 pub const PRICE_SCALE: i64 = 10_000;
 
+/// How many days of traded volume count towards a user's fee tier: the current day and
+/// the ones before it.
+pub const FEE_VOLUME_DAYS: usize = 30;
+
+/// How far back traded volume counts towards a user's fee tier, in whole days.
+pub const FEE_VOLUME_WINDOW: Timestamp = FEE_VOLUME_DAYS as Timestamp * MILLIS_PER_DAY;
+
 pub fn price_to_tick(price: Decimal) -> Tick {
     let scaled = price * Decimal::from(PRICE_SCALE);
     let rounded = scaled.round_dp(0);
@@ -21,6 +42,30 @@ pub fn tick_to_price(tick: Tick) -> Decimal {
     Decimal::from(tick) / Decimal::from(PRICE_SCALE)
 }
 
+/// Lot size of markets added without one, matching the precision of `PRICE_SCALE`.
+pub const DEFAULT_LOT_SIZE: Decimal = Decimal::from_parts(1, 0, 0, false, 4);
+
+/// Converts a size into whole lots. Sizes that are negative or not a multiple of the lot
+/// size are refused rather than rounded, so no quantity is ever created or lost.
+pub fn size_to_lots(size: Decimal, lot_size: Decimal) -> Result<Qty, EngineError> {
+    if size.is_sign_negative() {
+        return Err(EngineError::InvalidSize(format!("size {} can't be negative", size)));
+    }
+    let lots = size / lot_size;
+    if !lots.fract().is_zero() {
+        return Err(EngineError::InvalidSize(format!(
+            "size {} is not a multiple of the lot size {}",
+            size, lot_size
+        )));
+    }
+    lots.to_u64()
+        .ok_or_else(|| EngineError::InvalidSize(format!("size {} is too large", size)))
+}
+
+pub fn lots_to_size(lots: Qty, lot_size: Decimal) -> Decimal {
+    Decimal::from(lots) * lot_size
+}
+
 #[derive(Debug, Hash, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub struct TradingPair {
     base: String,
@@ -30,12 +75,21 @@ impl TradingPair {
     pub fn new(base: String, quote: String) -> TradingPair {
         TradingPair { base, quote }
     }
-    pub fn to_string(&self) -> String {
-        format!("{}_{}", self.base, self.quote)
-    }
     pub fn get_pair(&self) -> Vec<String> {
         vec![self.base.clone(), self.quote.clone()]
     }
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+    pub fn quote(&self) -> &str {
+        &self.quote
+    }
+}
+
+impl fmt::Display for TradingPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.base, self.quote)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -45,12 +99,57 @@ pub enum OrderStatus {
     PartiallyFilled,
     Filled,
     Rejected,
+    Canceled,
+    Expired,
+    /// A stop order whose stop price was reached; it now works as `triggered_order_id`.
+    Triggered,
+    /// Amended by cancel/replace; the rest of the order works on as `replaced_by_order_id`.
+    Replaced,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum OrderType {
     Market,
     Limit,
+    /// Becomes a market order once the last trade price reaches its stop price.
+    StopMarket,
+    /// Becomes a limit order at `price` once the last trade price reaches its stop price.
+    StopLimit,
+    /// A stop-market order whose stop price trails the last trade price.
+    TrailingStop,
+}
+
+/// An order as submitted to the JSON API: sizes and prices in base and quote units.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewOrderRequest {
+    pub market: TradingPair,
+    pub user_id: String,
+    pub side: BidOrAsk,
+    #[serde(rename = "type")]
+    pub order_type: OrderType,
+    /// Required for limit and stop-limit orders, not allowed for market and stop-market orders.
+    #[serde(default)]
+    pub price: Option<Decimal>,
+    /// Required for stop orders only.
+    #[serde(default)]
+    pub stop_price: Option<Decimal>,
+    /// Required for trailing stops only.
+    #[serde(default)]
+    pub trailing_offset: Option<TrailingOffset>,
+    /// Makes a limit order an iceberg that shows only this much of its size at a time.
+    #[serde(default)]
+    pub display_size: Option<Decimal>,
+    pub size: Decimal,
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
+    #[serde(default)]
+    pub client_order_id: Option<String>,
+    /// The market's mode when missing.
+    #[serde(default)]
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+    /// Limit orders only.
+    #[serde(default)]
+    pub post_only: PostOnly,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -61,16 +160,143 @@ pub struct OrderSnapshot {
     pub side: BidOrAsk,
     pub order_type: OrderType,
     pub price: Option<Tick>,
-    pub original_size: f64,
-    pub remaining_size: f64,
-    pub filled_size: f64,
+    /// Quantities are in lots of the order's market.
+    pub original_size: Qty,
+    pub remaining_size: Qty,
+    pub filled_size: Qty,
+    /// Quantity that was dropped without trading (canceled or the unfilled part of a market order).
+    pub canceled_size: Qty,
+    /// Part of `canceled_size` canceled by self-trade prevention.
+    pub self_trade_canceled_size: Qty,
     pub status: OrderStatus,
+    pub time_in_force: TimeInForce,
+    /// When a DAY or good-till-date order leaves the book (ms since epoch).
+    pub expires_at: Option<Timestamp>,
+    pub client_order_id: Option<String>,
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+    pub post_only: PostOnly,
+    /// Visible peak of an iceberg order.
+    pub display_size: Option<Qty>,
+    /// Stop price of a stop order; for a trailing stop, where it currently is.
+    pub trigger_price: Option<Tick>,
+    pub trailing_offset: Option<TrailingOffset>,
+    /// Order a triggered stop was converted into.
+    pub triggered_order_id: Option<u64>,
+    /// Order this one replaced when an amend changed its price or raised its size.
+    pub replaces_order_id: Option<u64>,
+    /// Order that replaced this one.
+    pub replaced_by_order_id: Option<u64>,
+    /// Executions of the order, oldest first.
+    pub fills: Vec<OrderFill>,
+    /// Sum of price * size over all fills, in quote units.
+    pub filled_notional: Decimal,
+    /// Sum of the fees of all fills in quote units, negative when rebates outweigh them.
+    pub fees_paid: Decimal,
+    /// Volume weighted price of the fills, `None` until the order trades.
+    pub average_price: Option<Decimal>,
+    pub last_fill_at: Option<Timestamp>,
+}
+
+/// Whether an order provided liquidity (rested in the book) or took it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Liquidity {
+    Maker,
+    Taker,
+}
+
+/// One execution of an order, seen from that order's side.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderFill {
+    pub trade_id: u64,
+    pub price: Tick,
+    pub qty: Qty,
+    pub liquidity: Liquidity,
+    pub counterparty_order_id: OrderId,
+    pub counterparty_user_id: String,
+    /// Fee charged to the order for this fill in quote units; negative for a rebate.
+    pub fee: Decimal,
+    pub timestamp: Timestamp,
+}
+
+/// A single execution between a resting (maker) order and an incoming (taker) order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Trade {
+    pub trade_id: u64,
+    pub market_id: MarketId,
+    pub price: Tick,
+    pub qty: Qty,
+    pub maker_order_id: OrderId,
+    pub taker_order_id: OrderId,
+    pub maker_user_id: String,
+    pub taker_user_id: String,
+    pub aggressor_side: BidOrAsk,
+    /// Position of the trade in its market's history, starting at 1.
+    pub sequence: u64,
+    /// Fees in quote units at each side's tier rate; a negative maker fee is a rebate.
+    pub maker_fee: Decimal,
+    pub taker_fee: Decimal,
+    pub timestamp: Timestamp,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OrderResponse {
     pub order: OrderSnapshot,
     pub message: String,
+    pub trades: Vec<Trade>,
+    /// Why the order was rejected without touching the book, e.g. a crossing post-only order.
+    pub rejection: Option<OrderRejection>,
+}
+
+// Outcome of running an incoming order through its book.
+struct Execution {
+    snapshot: OrderSnapshot,
+    report: FillReport,
+    trades: Vec<Trade>,
+    rejection: Option<OrderRejection>,
+}
+
+// Quote notional traded per day over the last `FEE_VOLUME_DAYS` days, so the state stays the
+// same size however much is traded
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct VolumeWindow {
+    // Day (since epoch) of the latest trade
+    day: u64,
+    // Indexed by day modulo `FEE_VOLUME_DAYS`
+    days: [Decimal; FEE_VOLUME_DAYS],
+}
+
+impl VolumeWindow {
+    fn record(&mut self, day: u64, notional: Decimal) {
+        if day + (FEE_VOLUME_DAYS as u64) <= self.day {
+            return;
+        }
+        // Clears the days that left the window since the latest trade
+        let fresh = self.day + 1..=day.min(self.day + FEE_VOLUME_DAYS as u64);
+        for stale in fresh {
+            self.days[stale as usize % FEE_VOLUME_DAYS] = Decimal::ZERO;
+        }
+        self.day = self.day.max(day);
+        self.days[day as usize % FEE_VOLUME_DAYS] += notional;
+    }
+
+    // Volume of the window ending on `day`
+    fn total(&self, day: u64) -> Decimal {
+        let first = day.saturating_sub(FEE_VOLUME_DAYS as u64 - 1).max(self.day.saturating_sub(FEE_VOLUME_DAYS as u64 - 1));
+        (first..=day.min(self.day)).map(|day| self.days[day as usize % FEE_VOLUME_DAYS]).sum()
+    }
+}
+
+// Funds an order holds locked while it works. Buys lock quote, sells lock base.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Reservation {
+    user_id: String,
+    side: BidOrAsk,
+    asset: String,
+    remaining: Decimal,
+    // Price a buy locked its quote at; market buys lock their exact cost and have none
+    buy_price: Option<Decimal>,
+    // Fee rate buys lock on top of the notional
+    fee_rate: Decimal,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -78,31 +304,412 @@ pub struct EngineStats {
     pub fills_total: u64,
     pub resting_orders_consumed_total: u64,
     pub levels_crossed_total: u64,
-    pub total_matched_qty: f64,
+    pub total_matched_qty: Qty,
+}
+
+/// Everything a `MatchEngine` holds apart from its clock and journal, as written to a
+/// snapshot. Maps are kept sorted, so the same state always serializes the same way.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EngineState {
+    sequence: u64,
+    markets: Vec<MarketState>,
+    orders: Vec<OrderSnapshot>,
+    next_order_id: u64,
+    next_trade_id: u64,
+    stats: EngineStats,
+    market_order_policy: MarketOrderPolicy,
+    expiries: BTreeSet<(Timestamp, u64)>,
+    accounts: Accounts,
+    ledger: Ledger,
+    enforce_balances: bool,
+    reservations: Vec<(u64, Reservation)>,
+    volumes: Vec<((String, MarketId), VolumeWindow)>,
+    client_orders: Vec<((String, String), OrderResponse)>,
+}
+
+impl EngineState {
+    /// Sequence of the last command the state includes.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+// One market of an `EngineState`, in market id order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MarketState {
+    pair: TradingPair,
+    spec: MarketSpec,
+    book: OrderBook,
+    trigger_book: TriggerBook,
+    trades: Vec<Trade>,
 }
 
 #[derive(Debug)]
 pub struct MatchEngine {
     orderbooks: Vec<OrderBook>,
     markets: Vec<TradingPair>,
+    // Trading rules per market, indexed like `orderbooks`
+    specs: Vec<MarketSpec>,
     market_index: HashMap<TradingPair, MarketId>,
     orders: HashMap<u64, OrderSnapshot>,
+    // Trade history per market, indexed like `orderbooks`
+    trades: Vec<Vec<Trade>>,
     next_order_id: u64,
+    next_trade_id: u64,
     stats: EngineStats,
+    market_order_policy: MarketOrderPolicy,
+    clock: Box<dyn Clock>,
+    // (deadline, order id) of resting DAY / good-till-date orders
+    expiries: BTreeSet<(Timestamp, u64)>,
+    accounts: Accounts,
+    // Journal of every deposit, withdrawal and settlement applied to `accounts`
+    ledger: Ledger,
+    // Whether orders must be covered by the user's balance, off by default
+    enforce_balances: bool,
+    reservations: HashMap<u64, Reservation>,
+    // (user, market) -> daily quote notional inside `FEE_VOLUME_WINDOW`
+    volumes: HashMap<(String, MarketId), VolumeWindow>,
+    // Pending stop orders per market, indexed like `orderbooks`
+    trigger_books: Vec<TriggerBook>,
+    // Set while stops are being fired, so orders they place don't start another round
+    triggering: bool,
+    // (user id, client order id) -> response to the order's first submission
+    client_orders: HashMap<(String, String), OrderResponse>,
+    // Sequence of the last command applied through `execute` / `apply`
+    sequence: u64,
+    // Time of the command being applied, so it doesn't depend on when it's applied
+    pinned_now: Option<Timestamp>,
+    // Write-ahead log every executed command goes to before it's applied
+    journal: Option<Journal>,
+    // Whether commands come from a leader's replication stream rather than `execute`
+    following: bool,
+    // Receive every record the engine applies through `execute` or `replicate`
+    subscribers: Vec<Sender<JournalRecord>>,
+}
+
+impl Default for MatchEngine {
+    fn default() -> MatchEngine {
+        MatchEngine::new()
+    }
 }
 
 impl MatchEngine {
     pub fn new() -> MatchEngine {
+        MatchEngine::with_clock(Box::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Box<dyn Clock>) -> MatchEngine {
         MatchEngine {
             orderbooks: Vec::new(),
             markets: Vec::new(),
+            specs: Vec::new(),
             market_index: HashMap::new(),
             orders: HashMap::new(),
+            trades: Vec::new(),
             next_order_id: 1,
+            next_trade_id: 1,
             stats: EngineStats::default(),
+            market_order_policy: MarketOrderPolicy::default(),
+            clock,
+            expiries: BTreeSet::new(),
+            accounts: Accounts::new(),
+            ledger: Ledger::new(),
+            enforce_balances: false,
+            reservations: HashMap::new(),
+            volumes: HashMap::new(),
+            trigger_books: Vec::new(),
+            triggering: false,
+            client_orders: HashMap::new(),
+            sequence: 0,
+            pinned_now: None,
+            journal: None,
+            following: false,
+            subscribers: Vec::new(),
         }
     }
 
+    pub fn now(&self) -> Timestamp {
+        self.pinned_now.unwrap_or_else(|| self.clock.now())
+    }
+
+    /// Sequence of the last command applied, 0 before the first.
+    pub fn last_sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Journals every command `execute` applies from now on. The journal must end where
+    /// the engine's command sequence is, i.e. a non-empty journal has to be replayed first.
+    pub fn set_journal(&mut self, journal: Journal) -> Result<(), EngineError> {
+        if journal.last_sequence() != self.sequence {
+            return Err(EngineError::InvalidRequest(format!(
+                "journal {} ends at command {} but the engine is at command {}",
+                journal.path().display(),
+                journal.last_sequence(),
+                self.sequence
+            )));
+        }
+        self.journal = Some(journal);
+        Ok(())
+    }
+
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
+
+    /// Gives the command the next sequence number and the current time, writes it to the
+    /// journal, if there is one, and applies it. Nothing is applied if it can't be journaled.
+    /// Commands that fail are journaled all the same: applying them again fails the same way.
+    pub fn execute(&mut self, command: Command) -> Result<CommandOutput, EngineError> {
+        if self.following {
+            return Err(EngineError::NotLeader);
+        }
+        let record = JournalRecord::new(self.sequence + 1, self.clock.now(), command);
+        self.commit(record)
+    }
+
+    /// Applies a record another engine sequenced, journaling and publishing it like one of
+    /// this engine's own commands.
+    pub fn replicate(&mut self, record: &JournalRecord) -> Result<CommandOutput, EngineError> {
+        self.commit(record.clone())
+    }
+
+    fn commit(&mut self, record: JournalRecord) -> Result<CommandOutput, EngineError> {
+        if record.sequence != self.sequence + 1 {
+            return Err(EngineError::Internal(format!(
+                "command {} doesn't follow command {}",
+                record.sequence, self.sequence
+            )));
+        }
+        if let Some(journal) = self.journal.as_mut() {
+            journal
+                .append(&record)
+                .map_err(|err| EngineError::Internal(format!("can't write the journal: {}", err)))?;
+        }
+        let output = self.apply(&record);
+        // Subscribers that went away are dropped
+        self.subscribers.retain(|subscriber| subscriber.send(record.clone()).is_ok());
+        output
+    }
+
+    /// Records applied through `execute` or `replicate` from now on, e.g. to stream to followers.
+    pub fn subscribe(&mut self) -> Receiver<JournalRecord> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    pub fn is_following(&self) -> bool {
+        self.following
+    }
+
+    /// A following engine refuses `execute` with `NotLeader`; its commands come through
+    /// `replicate`. Promoting a follower to leader turns this off.
+    pub fn set_following(&mut self, following: bool) {
+        self.following = following;
+    }
+
+    /// Applies a sequenced command as of its recorded time. Records must come in sequence.
+    pub fn apply(&mut self, record: &JournalRecord) -> Result<CommandOutput, EngineError> {
+        if record.sequence != self.sequence + 1 {
+            return Err(EngineError::Internal(format!(
+                "command {} doesn't follow command {}",
+                record.sequence, self.sequence
+            )));
+        }
+        self.sequence = record.sequence;
+        self.pinned_now = Some(record.timestamp);
+        let output = self.apply_command(&record.command);
+        self.pinned_now = None;
+        output
+    }
+
+    /// Rebuilds state by applying journaled commands, in order and as of their recorded
+    /// times, on top of the current state; on a fresh engine this reproduces the books,
+    /// orders, trades, balances and id counters of the run that wrote them. Commands that
+    /// failed when they were journaled fail again and are passed over. Returns how many
+    /// records were replayed.
+    pub fn replay<'a>(&mut self, records: impl IntoIterator<Item = &'a JournalRecord>) -> Result<u64, EngineError> {
+        let mut replayed = 0;
+        for record in records {
+            if record.sequence != self.sequence + 1 {
+                return Err(EngineError::Internal(format!(
+                    "journal skips from command {} to command {}",
+                    self.sequence, record.sequence
+                )));
+            }
+            // Its outcome was already answered when it was first applied
+            let _ = self.apply(record);
+            replayed += 1;
+        }
+        Ok(replayed)
+    }
+
+    /// Replays the journal file at `path`, see `replay`.
+    pub fn replay_file(&mut self, path: impl AsRef<Path>) -> Result<u64, EngineError> {
+        let records = Journal::read(path.as_ref()).map_err(|err| {
+            EngineError::Internal(format!("can't read journal {}: {}", path.as_ref().display(), err))
+        })?;
+        self.replay(&records)
+    }
+
+    /// Copy of the engine's state, see `EngineState`.
+    pub fn state(&self) -> EngineState {
+        let markets = (0..self.markets.len())
+            .map(|market| MarketState {
+                pair: self.markets[market].clone(),
+                spec: self.specs[market].clone(),
+                book: self.orderbooks[market].clone(),
+                trigger_book: self.trigger_books[market].clone(),
+                trades: self.trades[market].clone(),
+            })
+            .collect();
+        let mut reservations: Vec<(u64, Reservation)> =
+            self.reservations.iter().map(|(id, reservation)| (*id, reservation.clone())).collect();
+        reservations.sort_unstable_by_key(|(id, _)| *id);
+        let mut volumes: Vec<((String, MarketId), VolumeWindow)> =
+            self.volumes.iter().map(|(key, window)| (key.clone(), window.clone())).collect();
+        volumes.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        let mut client_orders: Vec<((String, String), OrderResponse)> =
+            self.client_orders.iter().map(|(key, response)| (key.clone(), response.clone())).collect();
+        client_orders.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        EngineState {
+            sequence: self.sequence,
+            markets,
+            orders: self.get_orders(),
+            next_order_id: self.next_order_id,
+            next_trade_id: self.next_trade_id,
+            stats: self.stats.clone(),
+            market_order_policy: self.market_order_policy,
+            expiries: self.expiries.clone(),
+            accounts: self.accounts.clone(),
+            ledger: self.ledger.clone(),
+            enforce_balances: self.enforce_balances,
+            reservations,
+            volumes,
+            client_orders,
+        }
+    }
+
+    /// Replaces the engine's state with `state`, keeping its clock. Done before a journal is
+    /// attached, since the journal has to continue from the state's sequence.
+    pub fn load_state(&mut self, state: EngineState) -> Result<(), EngineError> {
+        if self.journal.is_some() {
+            return Err(EngineError::InvalidRequest(
+                "can't load a state into an engine that is journaling".to_string(),
+            ));
+        }
+        self.orderbooks.clear();
+        self.markets.clear();
+        self.specs.clear();
+        self.market_index.clear();
+        self.trades.clear();
+        self.trigger_books.clear();
+        for (market_id, market) in state.markets.into_iter().enumerate() {
+            self.market_index.insert(market.pair.clone(), market_id as MarketId);
+            self.markets.push(market.pair);
+            self.specs.push(market.spec);
+            self.orderbooks.push(market.book);
+            self.trigger_books.push(market.trigger_book);
+            self.trades.push(market.trades);
+        }
+        self.orders = state.orders.into_iter().map(|order| (order.id, order)).collect();
+        self.next_order_id = state.next_order_id;
+        self.next_trade_id = state.next_trade_id;
+        self.stats = state.stats;
+        self.market_order_policy = state.market_order_policy;
+        self.expiries = state.expiries;
+        self.accounts = state.accounts;
+        self.ledger = state.ledger;
+        self.enforce_balances = state.enforce_balances;
+        self.reservations = state.reservations.into_iter().collect();
+        self.volumes = state.volumes.into_iter().collect();
+        self.client_orders = state.client_orders.into_iter().collect();
+        self.sequence = state.sequence;
+        self.triggering = false;
+        self.pinned_now = None;
+        Ok(())
+    }
+
+    /// Checksum of the serialized `state()`. Engines that applied the same commands have
+    /// the same hash.
+    pub fn state_hash(&self) -> u64 {
+        snapshot::state_checksum(&self.state())
+    }
+
+    /// Writes a snapshot of the engine's state into `dir`.
+    pub fn write_snapshot(&self, dir: impl AsRef<Path>) -> Result<SnapshotInfo, EngineError> {
+        snapshot::write(dir.as_ref(), &self.state()).map_err(|err| {
+            EngineError::Internal(format!("can't write a snapshot to {}: {}", dir.as_ref().display(), err))
+        })
+    }
+
+    /// Loads the latest intact snapshot in `snapshot_dir`, if there is one, then replays the
+    /// records of the journal at `journal_path` that come after it.
+    pub fn restore(&mut self, snapshot_dir: impl AsRef<Path>, journal_path: Option<&Path>) -> Result<Restored, EngineError> {
+        let latest = snapshot::latest(snapshot_dir.as_ref()).map_err(|err| {
+            EngineError::Internal(format!("can't read snapshots in {}: {}", snapshot_dir.as_ref().display(), err))
+        })?;
+        let snapshot = match latest {
+            Some((path, state)) => {
+                self.load_state(state)?;
+                Some(path)
+            }
+            None => None,
+        };
+        let replayed = match journal_path.filter(|path| path.exists()) {
+            Some(path) => {
+                let records = Journal::read(path).map_err(|err| {
+                    EngineError::Internal(format!("can't read journal {}: {}", path.display(), err))
+                })?;
+                let sequence = self.sequence;
+                self.replay(records.iter().filter(|record| record.sequence > sequence))?
+            }
+            None => 0,
+        };
+        Ok(Restored { snapshot, sequence: self.sequence, replayed })
+    }
+
+    fn apply_command(&mut self, command: &Command) -> Result<CommandOutput, EngineError> {
+        let response = match command {
+            Command::AddMarket { pair, spec } => {
+                return self.add_market_with_spec(pair.clone(), spec.clone()).map(CommandOutput::Market);
+            }
+            Command::SetEnforceBalances { enabled } => {
+                self.set_enforce_balances(*enabled);
+                return Ok(CommandOutput::Done);
+            }
+            Command::Deposit { user_id, asset, amount } => {
+                return self.deposit(user_id, asset, *amount).map(CommandOutput::LedgerEntry);
+            }
+            Command::Withdraw { user_id, asset, amount } => {
+                return self.withdraw(user_id, asset, *amount).map(CommandOutput::LedgerEntry);
+            }
+            Command::ExpireOrders => return Ok(CommandOutput::Expired(self.expire_orders())),
+            Command::SubmitOrder(request) => self.submit_order(request),
+            Command::PlaceLimitOrder { pair, price, order } => {
+                self.place_limit_order_with_response(pair, *price, order.clone())
+            }
+            Command::FillMarketOrder { pair, order, policy } => {
+                self.fill_market_order_with_policy(pair, &mut order.clone(), *policy)
+            }
+            Command::CancelOrder { order_id } => self.cancel_order(*order_id),
+            Command::CancelOrderByClientId { user_id, client_order_id } => {
+                self.cancel_order_by_client_id(user_id, client_order_id)
+            }
+            Command::AmendOrder { order_id, price, size } => self.amend_order(*order_id, *price, *size),
+        };
+        response.map(|response| CommandOutput::Order(Box::new(response)))
+    }
+
+    pub fn market_order_policy(&self) -> MarketOrderPolicy {
+        self.market_order_policy
+    }
+
+    /// Sets how market orders that the book can't fully fill are handled by default.
+    pub fn set_market_order_policy(&mut self, policy: MarketOrderPolicy) {
+        self.market_order_policy = policy;
+    }
+
     pub fn stats(&self) -> EngineStats {
         self.stats.clone()
     }
@@ -111,6 +718,57 @@ impl MatchEngine {
         self.stats = EngineStats::default();
     }
 
+    pub fn enforce_balances(&self) -> bool {
+        self.enforce_balances
+    }
+
+    /// Makes every new order lock the funds it may spend and settles balances on each fill.
+    /// Enable it before placing orders, orders already resting hold no funds.
+    pub fn set_enforce_balances(&mut self, enabled: bool) {
+        self.enforce_balances = enabled;
+    }
+
+    pub fn accounts(&self) -> &Accounts {
+        &self.accounts
+    }
+
+    pub fn balance(&self, user_id: &str, asset: &str) -> Balance {
+        self.accounts.balance(user_id, asset)
+    }
+
+    pub fn balances(&self, user_id: &str) -> BTreeMap<String, Balance> {
+        self.accounts.balances(user_id)
+    }
+
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    /// Credits `amount` of `asset` to the user from outside the engine. Returns the journal entry id.
+    pub fn deposit(&mut self, user_id: &str, asset: &str, amount: Decimal) -> Result<u64, EngineError> {
+        self.accounts.deposit(user_id, asset, amount)?;
+        let postings = vec![
+            Posting::new(LedgerAccount::User(user_id.to_string()), asset, amount),
+            Posting::new(LedgerAccount::External, asset, -amount),
+        ];
+        self.ledger.post(EntryKind::Deposit, self.now(), postings)
+    }
+
+    /// Debits `amount` of `asset` from the user's available balance. Returns the journal entry id.
+    pub fn withdraw(&mut self, user_id: &str, asset: &str, amount: Decimal) -> Result<u64, EngineError> {
+        self.accounts.withdraw(user_id, asset, amount)?;
+        let postings = vec![
+            Posting::new(LedgerAccount::User(user_id.to_string()), asset, -amount),
+            Posting::new(LedgerAccount::External, asset, amount),
+        ];
+        self.ledger.post(EntryKind::Withdrawal, self.now(), postings)
+    }
+
+    /// Checks the balances against the journal, see `Ledger::reconcile`.
+    pub fn check_ledger(&self) -> Result<(), EngineError> {
+        self.ledger.reconcile(&self.accounts)
+    }
+
     pub fn next_order_id(&mut self) -> u64 {
         let id = self.next_order_id;
         self.next_order_id = self.next_order_id.saturating_add(1);
@@ -126,15 +784,104 @@ impl MatchEngine {
         }
     }
 
+    /// Adds a market with the default `MarketSpec`.
     pub fn add_new_market(&mut self, pair: TradingPair) -> MarketId {
+        self.add_market_with_spec(pair, MarketSpec::default())
+            .expect("the default market spec is valid")
+    }
+
+    /// Adds a market whose order sizes are whole multiples of `lot_size`.
+    pub fn add_new_market_with_lot_size(&mut self, pair: TradingPair, lot_size: Decimal) -> Result<MarketId, EngineError> {
+        self.add_market_with_spec(pair, MarketSpec { lot_size, ..MarketSpec::default() })
+    }
+
+    /// Adds a market trading under `spec`. An existing market keeps its id and spec.
+    pub fn add_market_with_spec(&mut self, pair: TradingPair, spec: MarketSpec) -> Result<MarketId, EngineError> {
         if let Some(existing) = self.market_index.get(&pair) {
-            return *existing;
+            return Ok(*existing);
         }
+        spec.validate_spec().map_err(EngineError::InvalidMarketSpec)?;
         let market_id = self.markets.len() as MarketId;
         self.markets.push(pair.clone());
+        self.specs.push(spec);
         self.orderbooks.push(OrderBook::new());
+        self.trades.push(Vec::new());
+        self.trigger_books.push(TriggerBook::new());
         self.market_index.insert(pair, market_id);
-        market_id
+        Ok(market_id)
+    }
+
+    /// Replaces the fee schedule of a market; applies to trades from now on. Volume is only
+    /// tracked while a market has more than one tier, so new tiers start from what trades next.
+    pub fn set_fee_schedule(&mut self, market_id: MarketId, fees: FeeSchedule) -> Result<(), EngineError> {
+        fees.validate().map_err(EngineError::InvalidMarketSpec)?;
+        let spec = self
+            .specs
+            .get_mut(market_id as usize)
+            .ok_or(EngineError::UnknownMarketId(market_id))?;
+        spec.fees = fees;
+        Ok(())
+    }
+
+    /// Quote volume the user traded in the market today and the `FEE_VOLUME_DAYS - 1` days
+    /// before, while it was tiered.
+    pub fn trailing_volume(&self, user_id: &str, market_id: MarketId) -> Decimal {
+        self.volumes
+            .get(&(user_id.to_string(), market_id))
+            .map(|window| window.total(self.now() / MILLIS_PER_DAY))
+            .unwrap_or_default()
+    }
+
+    fn record_volume(&mut self, user_id: &str, market_id: MarketId, timestamp: Timestamp, notional: Decimal) {
+        let window = self.volumes.entry((user_id.to_string(), market_id)).or_default();
+        window.record(timestamp / MILLIS_PER_DAY, notional);
+    }
+
+    pub fn market_spec(&self, market_id: MarketId) -> Option<&MarketSpec> {
+        self.specs.get(market_id as usize)
+    }
+
+    pub fn market_spec_for_pair(&self, pair: &TradingPair) -> Option<&MarketSpec> {
+        let market_id = self.get_market_id(pair)?;
+        self.market_spec(market_id)
+    }
+
+    pub fn lot_size(&self, market_id: MarketId) -> Option<Decimal> {
+        self.specs.get(market_id as usize).map(|spec| spec.lot_size)
+    }
+
+    /// Checks an order against its market's spec without placing it.
+    pub fn validate_order(&self, market_id: MarketId, price: Option<Tick>, qty: Qty) -> Result<(), OrderRejection> {
+        match self.specs.get(market_id as usize) {
+            Some(spec) => spec.validate_order(price, qty),
+            None => Ok(()),
+        }
+    }
+
+    /// Converts a price into ticks, refusing prices finer than the engine can represent
+    /// instead of rounding them onto a tick.
+    fn checked_price_to_tick(&self, market_id: MarketId, price: Decimal) -> Result<Tick, EngineError> {
+        if !(price * Decimal::from(PRICE_SCALE)).fract().is_zero() {
+            let tick_size = self
+                .market_spec(market_id)
+                .map(|spec| spec.tick_size)
+                .unwrap_or_default();
+            return Err(OrderRejection::PriceNotOnTick { price, tick_size }.into());
+        }
+        Ok(price_to_tick(price))
+    }
+
+    /// Converts a size in base units into lots of the pair's market.
+    pub fn size_to_lots(&self, pair: &TradingPair, size: Decimal) -> Result<Qty, EngineError> {
+        let market_id = self
+            .get_market_id(pair)
+            .ok_or_else(|| EngineError::UnknownMarket(pair.clone()))?;
+        size_to_lots(size, self.specs[market_id as usize].lot_size)
+    }
+
+    pub fn lots_to_size(&self, pair: &TradingPair, lots: Qty) -> Option<Decimal> {
+        let market_id = self.get_market_id(pair)?;
+        Some(lots_to_size(lots, self.specs[market_id as usize].lot_size))
     }
 
     pub fn get_market_id(&self, pair: &TradingPair) -> Option<MarketId> {
@@ -150,7 +897,7 @@ impl MatchEngine {
         order: &Order,
         order_type: OrderType,
         price: Option<Tick>,
-        original_size: f64,
+        original_size: Qty,
         status: OrderStatus,
     ) -> OrderSnapshot {
         let remaining_size = order.size();
@@ -163,39 +910,358 @@ impl MatchEngine {
             price,
             original_size,
             remaining_size,
-            filled_size: (original_size - remaining_size).max(0.0),
+            filled_size: original_size.saturating_sub(remaining_size),
+            canceled_size: 0,
+            self_trade_canceled_size: 0,
             status,
+            time_in_force: order.time_in_force(),
+            expires_at: None,
+            client_order_id: order.client_order_id().map(str::to_string),
+            self_trade_prevention: order.self_trade_prevention(),
+            post_only: order.post_only(),
+            display_size: order.display_qty(),
+            trigger_price: None,
+            trailing_offset: None,
+            triggered_order_id: None,
+            replaces_order_id: None,
+            replaced_by_order_id: None,
+            fills: Vec::new(),
+            filled_notional: Decimal::ZERO,
+            fees_paid: Decimal::ZERO,
+            average_price: None,
+            last_fill_at: None,
         }
     }
 
-    fn apply_fill_snapshot(snapshot: &mut OrderSnapshot, filled_qty: f64) {
-        let mut remaining = snapshot.remaining_size - filled_qty;
-        if remaining < 0.0 {
-            remaining = 0.0;
-        }
+    fn apply_fill_snapshot(snapshot: &mut OrderSnapshot, filled_qty: Qty) {
+        let remaining = snapshot.remaining_size.saturating_sub(filled_qty);
         snapshot.remaining_size = remaining;
-        snapshot.filled_size = (snapshot.original_size - remaining).max(0.0);
-        snapshot.status = if remaining == 0.0 {
+        snapshot.filled_size = snapshot.original_size - remaining - snapshot.canceled_size;
+        snapshot.status = if remaining == 0 {
             OrderStatus::Filled
         } else {
             OrderStatus::PartiallyFilled
         };
     }
 
-    fn market_message(side: BidOrAsk, report: &FillReport) -> String {
-        if report.insufficient_liquidity {
+    /// Turns the fills of `taker` into trades and appends them to the market's history.
+    fn record_trades(&mut self, market_id: MarketId, taker: &Order, fills: &[Fill]) -> Vec<Trade> {
+        let timestamp = self.now();
+        let lot_size = self.specs[market_id as usize].lot_size;
+        let mut trades = Vec::with_capacity(fills.len());
+        for fill in fills {
+            let maker_user_id = self
+                .orders
+                .get(&fill.maker_order_id)
+                .map(|maker| maker.user_id.clone())
+                .unwrap_or_else(|| "unknown".to_string());
+
+            // Rates come from the volume traded before this trade
+            let notional = tick_to_price(fill.price) * lots_to_size(fill.qty, lot_size);
+            let fees = &self.specs[market_id as usize].fees;
+            let (maker_rate, taker_rate) = if fees.tiers.len() > 1 {
+                let maker_rate = fees.tier_for(self.trailing_volume(&maker_user_id, market_id)).maker_rate;
+                let taker_rate = fees.tier_for(self.trailing_volume(taker.user_id(), market_id)).taker_rate;
+                self.record_volume(&maker_user_id, market_id, timestamp, notional);
+                self.record_volume(taker.user_id(), market_id, timestamp, notional);
+                (maker_rate, taker_rate)
+            } else {
+                (fees.tiers[0].maker_rate, fees.tiers[0].taker_rate)
+            };
+
+            let history = &mut self.trades[market_id as usize];
+            let trade = Trade {
+                trade_id: self.next_trade_id,
+                market_id,
+                price: fill.price,
+                qty: fill.qty,
+                maker_order_id: fill.maker_order_id,
+                taker_order_id: taker.id(),
+                maker_user_id,
+                taker_user_id: taker.user_id().to_string(),
+                aggressor_side: taker.bid_or_ask(),
+                sequence: history.len() as u64 + 1,
+                maker_fee: notional * maker_rate,
+                taker_fee: notional * taker_rate,
+                timestamp,
+            };
+            self.next_trade_id += 1;
+            history.push(trade.clone());
+            trades.push(trade);
+        }
+        trades
+    }
+
+    fn add_order_fill(snapshot: &mut OrderSnapshot, fill: OrderFill, lot_size: Decimal) {
+        snapshot.filled_notional += tick_to_price(fill.price) * lots_to_size(fill.qty, lot_size);
+        snapshot.fees_paid += fill.fee;
+        snapshot.last_fill_at = Some(fill.timestamp);
+        snapshot.fills.push(fill);
+        if snapshot.filled_size > 0 {
+            snapshot.average_price = Some(snapshot.filled_notional / lots_to_size(snapshot.filled_size, lot_size));
+        }
+    }
+
+    /// Adds `trades` to the fills of the taker and of every maker it traded with.
+    /// The taker's `filled_size` must already be final.
+    fn record_order_fills(&mut self, market_id: MarketId, taker: &mut OrderSnapshot, trades: &[Trade]) {
+        let lot_size = self.specs[market_id as usize].lot_size;
+        for trade in trades {
+            if let Some(maker) = self.orders.get_mut(&trade.maker_order_id) {
+                let fill = OrderFill {
+                    trade_id: trade.trade_id,
+                    price: trade.price,
+                    qty: trade.qty,
+                    liquidity: Liquidity::Maker,
+                    counterparty_order_id: trade.taker_order_id,
+                    counterparty_user_id: trade.taker_user_id.clone(),
+                    fee: trade.maker_fee,
+                    timestamp: trade.timestamp,
+                };
+                Self::add_order_fill(maker, fill, lot_size);
+            }
+            let fill = OrderFill {
+                trade_id: trade.trade_id,
+                price: trade.price,
+                qty: trade.qty,
+                liquidity: Liquidity::Taker,
+                counterparty_order_id: trade.maker_order_id,
+                counterparty_user_id: trade.maker_user_id.clone(),
+                fee: trade.taker_fee,
+                timestamp: trade.timestamp,
+            };
+            Self::add_order_fill(taker, fill, lot_size);
+        }
+    }
+
+    /// Quote needed to buy `qty` lots from the market's asks, walking them best first.
+    /// Only what the book can fill is priced.
+    fn market_buy_cost(&self, market_id: MarketId, qty: Qty) -> Decimal {
+        let lot_size = self.specs[market_id as usize].lot_size;
+        let mut left = qty;
+        let mut cost = Decimal::ZERO;
+        for limit in self.orderbooks[market_id as usize].ask_limits() {
+            if left == 0 {
+                break;
+            }
+            let take = left.min(limit.total_qty());
+            cost += tick_to_price(limit.price()) * lots_to_size(take, lot_size);
+            left -= take;
+        }
+        cost
+    }
+
+    /// Locks what `order` may spend: quote at its limit price (or the cost of the book for
+    /// a market buy) plus the highest fee rate for buys, its size in base for sells.
+    fn reserve_funds(&mut self, market_id: MarketId, order: &Order, price: Option<Tick>) -> Result<(), EngineError> {
+        let reservation = self.reservation_for(market_id, order, price);
+        self.accounts.lock(order.user_id(), &reservation.asset, reservation.remaining)?;
+        self.reservations.insert(order.id(), reservation);
+        Ok(())
+    }
+
+    // What `reserve_funds` locks for `order`
+    fn reservation_for(&self, market_id: MarketId, order: &Order, price: Option<Tick>) -> Reservation {
+        let pair = &self.markets[market_id as usize];
+        let spec = &self.specs[market_id as usize];
+        let size = lots_to_size(order.size(), spec.lot_size);
+        let fee_rate = spec.fees.max_rate();
+        let (asset, amount, buy_price) = match (order.bid_or_ask(), price) {
+            (BidOrAsk::Bid, Some(price)) => (
+                pair.quote(),
+                tick_to_price(price) * size * (Decimal::ONE + fee_rate),
+                Some(tick_to_price(price)),
+            ),
+            (BidOrAsk::Bid, None) => (
+                pair.quote(),
+                self.market_buy_cost(market_id, order.size()) * (Decimal::ONE + fee_rate),
+                None,
+            ),
+            (BidOrAsk::Ask, _) => (pair.base(), size, None),
+        };
+        Reservation {
+            user_id: order.user_id().to_string(),
+            side: order.bid_or_ask(),
+            asset: asset.to_string(),
+            remaining: amount,
+            buy_price,
+            fee_rate,
+        }
+    }
+
+    /// Unlocks whatever the order still holds; called once it stops working.
+    fn release_funds(&mut self, order_id: u64) {
+        if let Some(reservation) = self.reservations.remove(&order_id) {
+            if !reservation.remaining.is_zero() {
+                self.accounts.unlock(&reservation.user_id, &reservation.asset, reservation.remaining);
+            }
+        }
+    }
+
+    /// Unlocks what `qty` lots of the order held, for quantity canceled while the rest works on.
+    fn release_funds_for_qty(&mut self, order_id: u64, qty: Qty, lot_size: Decimal) {
+        let Some(reservation) = self.reservations.get_mut(&order_id) else {
+            return;
+        };
+        let size = lots_to_size(qty, lot_size);
+        let amount = match (reservation.side, reservation.buy_price) {
+            (BidOrAsk::Ask, _) => size,
+            (BidOrAsk::Bid, Some(price)) => price * size * (Decimal::ONE + reservation.fee_rate),
+            // Market buys are released in full once they finish
+            (BidOrAsk::Bid, None) => return,
+        };
+        reservation.remaining -= amount;
+        let (user_id, asset) = (reservation.user_id.clone(), reservation.asset.clone());
+        self.accounts.unlock(&user_id, &asset, amount);
+    }
+
+    /// Takes `amount` out of the order's reservation; a buy that locked at a worse price than
+    /// it traded at gets the difference back.
+    fn consume_reservation(&mut self, order_id: u64, user_id: &str, asset: &str, amount: Decimal, locked: Decimal) {
+        if let Some(reservation) = self.reservations.get_mut(&order_id) {
+            reservation.remaining -= locked;
+        }
+        self.accounts.spend_locked(user_id, asset, amount);
+        if locked > amount {
+            self.accounts.unlock(user_id, asset, locked - amount);
+        }
+    }
+
+    /// Moves funds for every trade: the buyer pays quote out of its lock and receives base,
+    /// the seller pays base out of its lock and receives quote. Fees are paid in quote to
+    /// the house account, the buyer on top of the notional and the seller out of its proceeds.
+    fn settle_trades(&mut self, market_id: MarketId, trades: &[Trade]) -> Result<(), EngineError> {
+        let pair = self.markets[market_id as usize].clone();
+        let lot_size = self.specs[market_id as usize].lot_size;
+        for trade in trades {
+            let size = lots_to_size(trade.qty, lot_size);
+            let price = tick_to_price(trade.price);
+            let notional = price * size;
+            let ((buyer_order, buyer, buyer_fee), (seller_order, seller, seller_fee)) = match trade.aggressor_side {
+                BidOrAsk::Bid => (
+                    (trade.taker_order_id, &trade.taker_user_id, trade.taker_fee),
+                    (trade.maker_order_id, &trade.maker_user_id, trade.maker_fee),
+                ),
+                BidOrAsk::Ask => (
+                    (trade.maker_order_id, &trade.maker_user_id, trade.maker_fee),
+                    (trade.taker_order_id, &trade.taker_user_id, trade.taker_fee),
+                ),
+            };
+
+            let (buy_price, fee_rate) = self
+                .reservations
+                .get(&buyer_order)
+                .map(|reservation| (reservation.buy_price.unwrap_or(price), reservation.fee_rate))
+                .unwrap_or((price, Decimal::ZERO));
+            let locked = buy_price * size * (Decimal::ONE + fee_rate);
+            self.consume_reservation(buyer_order, buyer, pair.quote(), notional + buyer_fee, locked);
+            self.accounts.credit(buyer, pair.base(), size);
+
+            self.consume_reservation(seller_order, seller, pair.base(), size, size);
+            self.accounts.credit(seller, pair.quote(), notional - seller_fee);
+            self.accounts.credit(HOUSE_ACCOUNT, pair.quote(), buyer_fee + seller_fee);
+
+            let postings = vec![
+                Posting::new(LedgerAccount::User(buyer.clone()), pair.quote(), -notional),
+                Posting::new(LedgerAccount::User(seller.clone()), pair.quote(), notional),
+                Posting::new(LedgerAccount::User(seller.clone()), pair.base(), -size),
+                Posting::new(LedgerAccount::User(buyer.clone()), pair.base(), size),
+            ];
+            self.ledger
+                .post(EntryKind::Trade { trade_id: trade.trade_id }, trade.timestamp, postings)?;
+            if !buyer_fee.is_zero() || !seller_fee.is_zero() {
+                let postings = vec![
+                    Posting::new(LedgerAccount::User(buyer.clone()), pair.quote(), -buyer_fee),
+                    Posting::new(LedgerAccount::User(seller.clone()), pair.quote(), -seller_fee),
+                    Posting::new(LedgerAccount::User(HOUSE_ACCOUNT.to_string()), pair.quote(), buyer_fee + seller_fee),
+                ];
+                self.ledger
+                    .post(EntryKind::Fee { trade_id: trade.trade_id }, trade.timestamp, postings)?;
+            }
+        }
+        for trade in trades {
+            let maker_done = self
+                .orders
+                .get(&trade.maker_order_id)
+                .is_none_or(|maker| maker.remaining_size == 0);
+            if maker_done {
+                self.release_funds(trade.maker_order_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Books what self-trade prevention canceled: the taker's part on `taker`, whose
+    /// `remaining_size` already excludes it, and every resting order it canceled or reduced.
+    fn apply_self_trade_cancels(&mut self, market_id: MarketId, taker: &mut OrderSnapshot, report: &FillReport) {
+        let lot_size = self.specs[market_id as usize].lot_size;
+        taker.filled_size -= report.self_trade_canceled_qty;
+        taker.canceled_size += report.self_trade_canceled_qty;
+        taker.self_trade_canceled_size += report.self_trade_canceled_qty;
+        if self.enforce_balances && report.self_trade_canceled_qty > 0 {
+            self.release_funds_for_qty(taker.id, report.self_trade_canceled_qty, lot_size);
+        }
+
+        for cancel in &report.self_trade_cancels {
+            if let Some(maker) = self.orders.get_mut(&cancel.order_id) {
+                maker.remaining_size -= cancel.canceled_qty;
+                maker.canceled_size += cancel.canceled_qty;
+                maker.self_trade_canceled_size += cancel.canceled_qty;
+                if cancel.resting_qty == 0 {
+                    maker.status = OrderStatus::Canceled;
+                    if let Some(expires_at) = maker.expires_at {
+                        self.expiries.remove(&(expires_at, cancel.order_id));
+                    }
+                }
+            }
+            if cancel.resting_qty == 0 {
+                self.release_funds(cancel.order_id);
+            } else {
+                self.release_funds_for_qty(cancel.order_id, cancel.canceled_qty, lot_size);
+            }
+        }
+    }
+
+    fn record_fill_stats(&mut self, report: &FillReport) {
+        self.stats.fills_total += report.fills_total;
+        self.stats.resting_orders_consumed_total += report.resting_orders_consumed;
+        self.stats.levels_crossed_total += report.levels_crossed;
+        self.stats.total_matched_qty += report.total_matched_qty;
+    }
+
+    /// Moves whatever is still open on the order to `canceled_size`.
+    fn cancel_remainder_snapshot(snapshot: &mut OrderSnapshot) {
+        snapshot.canceled_size += snapshot.remaining_size;
+        snapshot.remaining_size = 0;
+    }
+
+    fn market_message(side: BidOrAsk, report: &FillReport, lot_size: Decimal) -> String {
+        let side_label = match side {
+            BidOrAsk::Bid => "Bid",
+            BidOrAsk::Ask => "Ask",
+        };
+        if report.insufficient_liquidity && report.filled_qty == 0 {
             match side {
                 BidOrAsk::Bid => "Not enough ask orders to fill this buy".to_string(),
                 BidOrAsk::Ask => "Not enough bid orders to fill this sell".to_string(),
             }
-        } else {
-            let side_label = match side {
-                BidOrAsk::Bid => "Bid",
-                BidOrAsk::Ask => "Ask",
+        } else if report.insufficient_liquidity {
+            let opposite_label = match side {
+                BidOrAsk::Bid => "ask",
+                BidOrAsk::Ask => "bid",
             };
+            format!(
+                "Partially filled {} {} market orders. Not enough {} orders to fill the remaining {}, it was canceled",
+                lots_to_size(report.filled_qty, lot_size),
+                side_label,
+                opposite_label,
+                lots_to_size(report.canceled_qty, lot_size)
+            )
+        } else {
             format!(
                 "Successfully filled {} {} market orders",
-                report.filled_qty, side_label
+                lots_to_size(report.filled_qty, lot_size),
+                side_label
             )
         }
     }
@@ -204,45 +1270,59 @@ impl MatchEngine {
         &mut self,
         market_id: MarketId,
         order: &mut Order,
-    ) -> Result<(OrderSnapshot, FillReport), String> {
+        policy: MarketOrderPolicy,
+    ) -> Result<Execution, EngineError> {
+        self.validate_order(market_id, None, order.size())?;
         self.ensure_order_identity(order);
+        self.expire_orders();
         let original_size = order.size();
 
         let pair = self
             .markets
             .get(market_id as usize)
             .cloned()
-            .ok_or_else(|| format!("market id {} doesn't exist", market_id))?;
+            .ok_or(EngineError::UnknownMarketId(market_id))?;
+        if order.self_trade_prevention().is_none() {
+            order.set_self_trade_prevention(Some(self.specs[market_id as usize].self_trade_prevention));
+        }
+        if self.enforce_balances {
+            self.reserve_funds(market_id, order, None)?;
+        }
 
+        let mut fills = Vec::new();
         let report = {
             let (orderbooks, orders) = (&mut self.orderbooks, &mut self.orders);
             let orderbook = orderbooks
                 .get_mut(market_id as usize)
-                .ok_or_else(|| format!("market id {} doesn't exist", market_id))?;
+                .ok_or(EngineError::UnknownMarketId(market_id))?;
 
-            let mut on_fill = |order_id: u64, filled_qty: f64| {
-                if let Some(snapshot) = orders.get_mut(&order_id) {
-                    Self::apply_fill_snapshot(snapshot, filled_qty);
+            let mut on_fill = |fill: Fill| {
+                if let Some(snapshot) = orders.get_mut(&fill.maker_order_id) {
+                    Self::apply_fill_snapshot(snapshot, fill.qty);
                 }
+                fills.push(fill);
             };
 
-            orderbook.fill_order_book_with_report(order, &mut on_fill)
+            orderbook.fill_order_book_with_policy(order, policy, &mut on_fill)
         };
 
-        self.stats.fills_total += report.fills_total;
-        self.stats.resting_orders_consumed_total += report.resting_orders_consumed;
-        self.stats.levels_crossed_total += report.levels_crossed;
-        self.stats.total_matched_qty += report.total_matched_qty;
+        self.record_fill_stats(&report);
+        let trades = self.record_trades(market_id, order, &fills);
+        if self.enforce_balances {
+            self.settle_trades(market_id, &trades)?;
+            self.release_funds(order.id());
+        }
 
-        let status = if report.filled_qty == 0.0 {
+        let status = if report.filled_qty == 0 && report.self_trade_canceled_qty == 0 {
             OrderStatus::Rejected
         } else if report.fully_filled {
             OrderStatus::Filled
         } else {
-            OrderStatus::PartiallyFilled
+            // Immediate-or-cancel: the unfilled part is dropped
+            OrderStatus::Canceled
         };
 
-        let snapshot = Self::snapshot_from_order(
+        let mut snapshot = Self::snapshot_from_order(
             pair,
             order,
             OrderType::Market,
@@ -250,46 +1330,94 @@ impl MatchEngine {
             original_size,
             status,
         );
+        self.apply_self_trade_cancels(market_id, &mut snapshot, &report);
+        Self::cancel_remainder_snapshot(&mut snapshot);
+        self.record_order_fills(market_id, &mut snapshot, &trades);
         self.orders.insert(snapshot.id, snapshot.clone());
+        if !trades.is_empty() {
+            self.run_triggers(market_id);
+        }
 
-        Ok((snapshot, report))
+        Ok(Execution { snapshot, report, trades, rejection: None })
     }
 
-    pub fn fill_market_order_by_id(&mut self, market_id: MarketId, order: &mut Order) -> Result<String, String> {
+    pub fn fill_market_order_by_id(&mut self, market_id: MarketId, order: &mut Order) -> Result<String, EngineError> {
         let response = self.fill_market_order_with_response_by_id(market_id, order)?;
         Ok(response.message)
     }
 
-    pub fn fill_market_order_raw_by_id(&mut self, market_id: MarketId, order: &mut Order) -> Result<(), String> {
-        let _ = self.execute_market_order_by_id(market_id, order)?;
-        Ok(())
+    /// Like `fill_market_order_by_id`, but a market order that fills nothing at all is an
+    /// `InsufficientLiquidity` error, since there is no response to report it in.
+    pub fn fill_market_order_raw_by_id(&mut self, market_id: MarketId, order: &mut Order) -> Result<(), EngineError> {
+        let response = self.fill_market_order_with_response_by_id(market_id, order)?;
+        match self.rejection_error(&response) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// The error an order the engine turned away stands for: its rejection, or
+    /// `InsufficientLiquidity` for a market or fill-or-kill order that found too little
+    /// to trade. `None` for orders that were accepted.
+    pub fn rejection_error(&self, response: &OrderResponse) -> Option<EngineError> {
+        if let Some(rejection) = &response.rejection {
+            return Some(EngineError::Rejected(rejection.clone()));
+        }
+        let snapshot = &response.order;
+        if !matches!(snapshot.status, OrderStatus::Rejected) {
+            return None;
+        }
+        let market_id = self.get_market_id(&snapshot.pair)?;
+        let mut order = Order::new_with_meta(snapshot.id, snapshot.user_id.clone(), snapshot.original_size, snapshot.side);
+        order.set_self_trade_prevention(snapshot.self_trade_prevention);
+        let available = self.orderbooks[market_id as usize].fill_or_kill_liquidity(&order, snapshot.price);
+        Some(EngineError::InsufficientLiquidity { requested: snapshot.original_size, available })
     }
 
     pub fn fill_market_order_with_response_by_id(
         &mut self,
         market_id: MarketId,
         order: &mut Order,
-    ) -> Result<OrderResponse, String> {
-        let (snapshot, report) = self.execute_market_order_by_id(market_id, order)?;
-        let message = Self::market_message(snapshot.side, &report);
+    ) -> Result<OrderResponse, EngineError> {
+        let policy = order.time_in_force().market_order_policy().unwrap_or(self.market_order_policy);
+        self.fill_market_order_with_policy_by_id(market_id, order, policy)
+    }
+
+    pub fn fill_market_order_with_policy_by_id(
+        &mut self,
+        market_id: MarketId,
+        order: &mut Order,
+        policy: MarketOrderPolicy,
+    ) -> Result<OrderResponse, EngineError> {
+        let Execution { snapshot, report, trades, .. } = self.execute_market_order_by_id(market_id, order, policy)?;
+        let lot_size = self.specs[market_id as usize].lot_size;
+        let mut message = Self::market_message(snapshot.side, &report, lot_size);
+        if report.self_trade_canceled_qty > 0 {
+            message.push_str(&format!(
+                ", {} canceled by self-trade prevention",
+                lots_to_size(report.self_trade_canceled_qty, lot_size)
+            ));
+        }
 
         Ok(OrderResponse {
             order: snapshot,
             message,
+            trades,
+            rejection: None,
         })
     }
 
-    pub fn fill_market_order(&mut self, pair: &TradingPair, order: &mut Order) -> Result<String, String> {
+    pub fn fill_market_order(&mut self, pair: &TradingPair, order: &mut Order) -> Result<String, EngineError> {
         let market_id = self
             .get_market_id(pair)
-            .ok_or_else(|| format!("the orderbook {} doesn't exist ", pair.to_string()))?;
+            .ok_or_else(|| EngineError::UnknownMarket(pair.clone()))?;
         self.fill_market_order_by_id(market_id, order)
     }
 
-    pub fn fill_market_order_raw(&mut self, pair: &TradingPair, order: &mut Order) -> Result<(), String> {
+    pub fn fill_market_order_raw(&mut self, pair: &TradingPair, order: &mut Order) -> Result<(), EngineError> {
         let market_id = self
             .get_market_id(pair)
-            .ok_or_else(|| format!("the orderbook {} doesn't exist ", pair.to_string()))?;
+            .ok_or_else(|| EngineError::UnknownMarket(pair.clone()))?;
         self.fill_market_order_raw_by_id(market_id, order)
     }
 
@@ -297,13 +1425,25 @@ impl MatchEngine {
         &mut self,
         pair: &TradingPair,
         order: &mut Order,
-    ) -> Result<OrderResponse, String> {
+    ) -> Result<OrderResponse, EngineError> {
         let market_id = self
             .get_market_id(pair)
-            .ok_or_else(|| format!("the orderbook {} doesn't exist ", pair.to_string()))?;
+            .ok_or_else(|| EngineError::UnknownMarket(pair.clone()))?;
         self.fill_market_order_with_response_by_id(market_id, order)
     }
 
+    pub fn fill_market_order_with_policy(
+        &mut self,
+        pair: &TradingPair,
+        order: &mut Order,
+        policy: MarketOrderPolicy,
+    ) -> Result<OrderResponse, EngineError> {
+        let market_id = self
+            .get_market_id(pair)
+            .ok_or_else(|| EngineError::UnknownMarket(pair.clone()))?;
+        self.fill_market_order_with_policy_by_id(market_id, order, policy)
+    }
+
     pub fn get_limits_for_a_pair(&self, pair: &TradingPair) -> Option<&OrderBook> {
         let market_id = self.get_market_id(pair)?;
         self.orderbooks.get(market_id as usize)
@@ -320,10 +1460,32 @@ impl MatchEngine {
             .collect::<Vec<_>>()
     }
 
+    /// Trade history of a market, oldest first.
+    pub fn get_trades(&self, market_id: MarketId) -> Option<&[Trade]> {
+        self.trades.get(market_id as usize).map(|trades| trades.as_slice())
+    }
+
+    pub fn get_trades_for_pair(&self, pair: &TradingPair) -> Option<&[Trade]> {
+        let market_id = self.get_market_id(pair)?;
+        self.get_trades(market_id)
+    }
+
     pub fn get_order(&self, order_id: u64) -> Option<OrderSnapshot> {
         self.orders.get(&order_id).cloned()
     }
 
+    /// Executions of an order, oldest first.
+    pub fn get_order_fills(&self, order_id: u64) -> Option<&[OrderFill]> {
+        self.orders.get(&order_id).map(|order| order.fills.as_slice())
+    }
+
+    /// Every order the engine knows, by id.
+    pub fn get_orders(&self) -> Vec<OrderSnapshot> {
+        let mut orders: Vec<OrderSnapshot> = self.orders.values().cloned().collect();
+        orders.sort_unstable_by_key(|order| order.id);
+        orders
+    }
+
     pub fn get_orders_for_user(&self, user_id: &str) -> Vec<OrderSnapshot> {
         self.orders
             .values()
@@ -332,67 +1494,631 @@ impl MatchEngine {
             .collect()
     }
 
-    pub fn make_limit(&mut self, size: f64, bid_or_ask: BidOrAsk) -> Order {
+    /// Current order of the user's client order id. An order amended by cancel/replace
+    /// keeps its client order id, so this follows it to its replacement.
+    pub fn get_order_by_client_id(&self, user_id: &str, client_order_id: &str) -> Result<OrderSnapshot, EngineError> {
+        let unknown = || EngineError::UnknownClientOrder {
+            user_id: user_id.to_string(),
+            client_order_id: client_order_id.to_string(),
+        };
+        let response = self
+            .client_orders
+            .get(&(user_id.to_string(), client_order_id.to_string()))
+            .ok_or_else(unknown)?;
+        let mut order = self.orders.get(&response.order.id).ok_or_else(unknown)?;
+        while let Some(replacement) = order.replaced_by_order_id.and_then(|id| self.orders.get(&id)) {
+            order = replacement;
+        }
+        Ok(order.clone())
+    }
+
+    pub fn cancel_order_by_client_id(&mut self, user_id: &str, client_order_id: &str) -> Result<OrderResponse, EngineError> {
+        let order_id = self.get_order_by_client_id(user_id, client_order_id)?.id;
+        self.cancel_order(order_id)
+    }
+
+    /// Takes a resting limit order out of its book and closes its snapshot with `status`.
+    /// Returns the updated snapshot and the quantity that was still resting.
+    fn remove_resting_order(&mut self, order_id: u64, status: OrderStatus) -> Result<(OrderSnapshot, Qty), EngineError> {
+        let snapshot = self
+            .orders
+            .get(&order_id)
+            .ok_or(EngineError::UnknownOrder(order_id))?;
+
+        if !matches!(
+            (&snapshot.order_type, &snapshot.status),
+            (OrderType::Limit, OrderStatus::Open | OrderStatus::PartiallyFilled)
+        ) {
+            return Err(EngineError::OrderNotResting(order_id));
+        }
+        let market_id = self
+            .get_market_id(&snapshot.pair)
+            .ok_or_else(|| EngineError::UnknownMarket(snapshot.pair.clone()))?;
+        if let Some(expires_at) = snapshot.expires_at {
+            self.expiries.remove(&(expires_at, order_id));
+        }
+
+        let removed_qty = self
+            .orderbooks
+            .get_mut(market_id as usize)
+            .and_then(|orderbook| orderbook.cancel_order(order_id))
+            .ok_or_else(|| EngineError::Internal(format!("order {} is not in the orderbook", order_id)))?;
+
+        let snapshot = self
+            .orders
+            .get_mut(&order_id)
+            .ok_or(EngineError::UnknownOrder(order_id))?;
+        snapshot.status = status;
+        Self::cancel_remainder_snapshot(snapshot);
+        let snapshot = snapshot.clone();
+        self.release_funds(order_id);
+        Ok((snapshot, removed_qty))
+    }
+
+    /// Takes a stop order that hasn't triggered out of its trigger book.
+    fn cancel_pending_stop(&mut self, order_id: u64) -> Result<(OrderSnapshot, Qty), EngineError> {
+        let snapshot = self
+            .orders
+            .get(&order_id)
+            .ok_or(EngineError::UnknownOrder(order_id))?;
+        let market_id = self
+            .get_market_id(&snapshot.pair)
+            .ok_or_else(|| EngineError::UnknownMarket(snapshot.pair.clone()))?;
+        let stop = self.trigger_books[market_id as usize]
+            .remove(order_id)
+            .ok_or(EngineError::OrderNotResting(order_id))?;
+
+        let snapshot = self
+            .orders
+            .get_mut(&order_id)
+            .ok_or(EngineError::UnknownOrder(order_id))?;
+        snapshot.status = OrderStatus::Canceled;
+        Self::cancel_remainder_snapshot(snapshot);
+        Ok((snapshot.clone(), stop.order.size()))
+    }
+
+    /// Changes a resting limit order; `size` is the new open size. Lowering only the size
+    /// reduces the order in place, keeping its time priority. A new price or a larger size
+    /// cancels it and places a replacement with a new id instead, which goes through
+    /// matching like any new order and is linked to it through `replaces_order_id` /
+    /// `replaced_by_order_id`. An amend whose replacement would be refused or rejected,
+    /// e.g. for funds or a crossing post-only price, fails and leaves the order as it was.
+    pub fn amend_order(
+        &mut self,
+        order_id: u64,
+        price: Option<Decimal>,
+        size: Option<Decimal>,
+    ) -> Result<OrderResponse, EngineError> {
+        let snapshot = self
+            .orders
+            .get(&order_id)
+            .cloned()
+            .ok_or(EngineError::UnknownOrder(order_id))?;
+        let old_tick = match (snapshot.order_type, snapshot.status, snapshot.price) {
+            (OrderType::Limit, OrderStatus::Open | OrderStatus::PartiallyFilled, Some(price)) => price,
+            _ => return Err(EngineError::OrderNotResting(order_id)),
+        };
+        let market_id = self
+            .get_market_id(&snapshot.pair)
+            .ok_or_else(|| EngineError::UnknownMarket(snapshot.pair.clone()))?;
+        let lot_size = self.specs[market_id as usize].lot_size;
+        let new_tick = match price {
+            Some(price) => self.checked_price_to_tick(market_id, price)?,
+            None => old_tick,
+        };
+        let new_qty = match size {
+            Some(size) => size_to_lots(size, lot_size)?,
+            None => snapshot.remaining_size,
+        };
+        if new_qty == 0 {
+            return Err(EngineError::InvalidSize(format!(
+                "can't amend order {} to a size of 0, cancel it instead",
+                order_id
+            )));
+        }
+        if new_tick == old_tick && new_qty == snapshot.remaining_size {
+            return Err(EngineError::InvalidRequest(format!("amending order {} changes nothing", order_id)));
+        }
+        self.validate_order(market_id, Some(new_tick), new_qty)?;
+
+        if new_tick == old_tick && new_qty < snapshot.remaining_size {
+            let reduce_by = snapshot.remaining_size - new_qty;
+            self.orderbooks[market_id as usize]
+                .reduce_order(order_id, reduce_by)
+                .ok_or_else(|| EngineError::Internal(format!("order {} is not in the orderbook", order_id)))?;
+            self.release_funds_for_qty(order_id, reduce_by, lot_size);
+            let snapshot = self
+                .orders
+                .get_mut(&order_id)
+                .ok_or(EngineError::UnknownOrder(order_id))?;
+            snapshot.remaining_size = new_qty;
+            snapshot.canceled_size += reduce_by;
+            let message = format!(
+                "reduced order {} in pair {} to {} open",
+                order_id,
+                snapshot.pair,
+                lots_to_size(new_qty, lot_size)
+            );
+            return Ok(OrderResponse { order: snapshot.clone(), message, trades: Vec::new(), rejection: None });
+        }
+
+        let mut order = Order::new_with_meta(0, snapshot.user_id.clone(), new_qty, snapshot.side);
+        order.set_time_in_force(snapshot.time_in_force);
+        order.set_client_order_id(snapshot.client_order_id.clone());
+        order.set_self_trade_prevention(snapshot.self_trade_prevention);
+        order.set_post_only(snapshot.post_only);
+        order.set_display_qty(snapshot.display_size);
+        // The order is only replaced once nothing can stop the replacement being placed
+        self.check_replacement(market_id, order_id, &order, new_tick)?;
+        self.remove_resting_order(order_id, OrderStatus::Replaced)?;
+
+        let mut response = self.place_limit_order_with_response_by_id_tick(market_id, new_tick, order)?;
+        let new_id = response.order.id;
+        response.order.replaces_order_id = Some(order_id);
+        if let Some(new) = self.orders.get_mut(&new_id) {
+            new.replaces_order_id = Some(order_id);
+        }
+        if let Some(old) = self.orders.get_mut(&order_id) {
+            old.replaced_by_order_id = Some(new_id);
+        }
+        response.message = format!("replaced order {} with order {}:{}", order_id, new_id, response.message);
+        Ok(response)
+    }
+
+    // What placing `order` in place of `order_id` at `price_tick` could fail on, past the
+    // spec: an expiry that has passed, a post-only price that would cross or slide off the
+    // spec and funds, counting those the replaced order releases.
+    fn check_replacement(
+        &self,
+        market_id: MarketId,
+        order_id: u64,
+        order: &Order,
+        mut price_tick: Tick,
+    ) -> Result<(), EngineError> {
+        if let TimeInForce::GoodTillDate(deadline) = order.time_in_force() {
+            if deadline <= self.now() {
+                return Err(EngineError::ExpiryInPast(deadline));
+            }
+        }
+        if let Some(best_price) = self.post_only_touch(market_id, order.bid_or_ask(), price_tick) {
+            match order.post_only() {
+                PostOnly::Off => {}
+                PostOnly::Reject => {
+                    return Err(EngineError::Rejected(OrderRejection::WouldCross {
+                        price: tick_to_price(price_tick),
+                        best_price: tick_to_price(best_price),
+                    }));
+                }
+                PostOnly::Slide => {
+                    price_tick = self.slide_price(market_id, order.bid_or_ask(), best_price);
+                    self.validate_order(market_id, Some(price_tick), order.size())?;
+                }
+            }
+        }
+        if self.enforce_balances {
+            let needed = self.reservation_for(market_id, order, Some(price_tick));
+            let released = self
+                .reservations
+                .get(&order_id)
+                .filter(|held| held.asset == needed.asset)
+                .map_or(Decimal::ZERO, |held| held.remaining);
+            let available = self.accounts.balance(order.user_id(), &needed.asset).available + released;
+            if available < needed.remaining {
+                return Err(EngineError::InsufficientFunds {
+                    asset: needed.asset,
+                    required: needed.remaining,
+                    available,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn cancel_order(&mut self, order_id: u64) -> Result<OrderResponse, EngineError> {
+        let pending_stop = self.orders.get(&order_id).is_some_and(|snapshot| {
+            matches!(snapshot.order_type, OrderType::StopMarket | OrderType::StopLimit | OrderType::TrailingStop)
+                && matches!(snapshot.status, OrderStatus::New)
+        });
+        let (snapshot, canceled_qty) = if pending_stop {
+            self.cancel_pending_stop(order_id)?
+        } else {
+            self.remove_resting_order(order_id, OrderStatus::Canceled)?
+        };
+
+        let canceled_size = self.lots_to_size(&snapshot.pair, canceled_qty).unwrap_or_default();
+        let message = format!(
+            "canceled order {} in pair {} with {} unfilled",
+            order_id, snapshot.pair, canceled_size
+        );
+        Ok(OrderResponse {
+            order: snapshot,
+            message,
+            trades: Vec::new(),
+            rejection: None,
+        })
+    }
+
+    /// Whether a DAY or good-till-date order's deadline has passed on the engine clock.
+    pub fn has_due_expiries(&self) -> bool {
+        self.expiries.first().is_some_and(|(deadline, _)| *deadline <= self.now())
+    }
+
+    /// Removes DAY and good-till-date orders whose deadline has passed on the engine clock.
+    /// Returns the snapshots of the orders that expired.
+    pub fn expire_orders(&mut self) -> Vec<OrderSnapshot> {
+        let now = self.now();
+        let mut expired = Vec::new();
+        while let Some(&(deadline, order_id)) = self.expiries.first() {
+            if deadline > now {
+                break;
+            }
+            self.expiries.remove(&(deadline, order_id));
+            // Orders filled before their deadline leave a stale entry behind, skip those
+            if let Ok((snapshot, _)) = self.remove_resting_order(order_id, OrderStatus::Expired) {
+                expired.push(snapshot);
+            }
+        }
+        expired
+    }
+
+    /// Stop orders waiting in the market's trigger book.
+    pub fn pending_stops(&self, market_id: MarketId) -> Option<&TriggerBook> {
+        self.trigger_books.get(market_id as usize)
+    }
+
+    /// Fires the stops the market's last trade price has reached, then the ones their own
+    /// trades reach, until a round triggers nothing. Each round fires in `TriggerBook` order.
+    fn run_triggers(&mut self, market_id: MarketId) {
+        if self.triggering {
+            return;
+        }
+        self.triggering = true;
+        while let Some(last) = self.orderbooks[market_id as usize].last_trade_price() {
+            self.trail_stops(market_id, last);
+            let triggered = self.trigger_books[market_id as usize].take_triggered(last);
+            if triggered.is_empty() {
+                break;
+            }
+            for stop in triggered {
+                self.trigger_stop(market_id, stop);
+            }
+        }
+        self.triggering = false;
+    }
+
+    /// Moves the market's trailing stops after a trade at `last`.
+    fn trail_stops(&mut self, market_id: MarketId, last: Tick) {
+        let tick_size = self.specs[market_id as usize].tick_size_ticks();
+        for (order_id, stop_price) in self.trigger_books[market_id as usize].trail(last, tick_size) {
+            if let Some(snapshot) = self.orders.get_mut(&order_id) {
+                snapshot.trigger_price = Some(stop_price);
+            }
+        }
+    }
+
+    /// Converts a stop into a new market or limit order and runs it through the book.
+    fn trigger_stop(&mut self, market_id: MarketId, stop: PendingStop) {
+        let stop_id = stop.order.id();
+        let mut order = stop.order;
+        order.set_id(self.next_order_id());
+        order.set_client_order_id(None);
+        let triggered_id = order.id();
+        let result = match stop.limit_price {
+            Some(price) => self.place_limit_order_internal_by_id(market_id, price, order).map(|_| ()),
+            None => {
+                let policy = order.time_in_force().market_order_policy().unwrap_or(self.market_order_policy);
+                self.execute_market_order_by_id(market_id, &mut order, policy).map(|_| ())
+            }
+        };
+
+        if let Some(snapshot) = self.orders.get_mut(&stop_id) {
+            match result {
+                Ok(()) => {
+                    snapshot.status = OrderStatus::Triggered;
+                    snapshot.triggered_order_id = Some(triggered_id);
+                }
+                // E.g. the user can no longer cover it
+                Err(_) => {
+                    snapshot.status = OrderStatus::Rejected;
+                    Self::cancel_remainder_snapshot(snapshot);
+                }
+            }
+        }
+    }
+
+    /// Holds `order` until the last trade price reaches `stop_tick`: rising to it for buys,
+    /// falling to it for sells. It then becomes a limit order at `limit_tick`, or a market
+    /// order without one. A stop the last price has already reached triggers right away.
+    pub fn place_stop_order_by_id_tick(
+        &mut self,
+        market_id: MarketId,
+        stop_tick: Tick,
+        limit_tick: Option<Tick>,
+        mut order: Order,
+    ) -> Result<OrderResponse, EngineError> {
+        self.validate_order(market_id, Some(stop_tick), order.size())?;
+        if limit_tick.is_some() {
+            self.validate_order(market_id, limit_tick, order.size())?;
+        }
+        self.ensure_order_identity(&mut order);
+        self.add_pending_stop(market_id, PendingStop { order, stop_price: stop_tick, limit_price: limit_tick, trailing: None })
+    }
+
+    /// Holds a stop-market `order` whose stop price stays `offset` behind the best price
+    /// traded since: below the highest for sells, above the lowest for buys. It starts from
+    /// the last trade price, or the best opposite price if the market hasn't traded.
+    pub fn place_trailing_stop_order_by_id(
+        &mut self,
+        market_id: MarketId,
+        offset: TrailingOffset,
+        mut order: Order,
+    ) -> Result<OrderResponse, EngineError> {
+        offset.validate()?;
+        let orderbook = self
+            .orderbooks
+            .get(market_id as usize)
+            .ok_or(EngineError::UnknownMarketId(market_id))?;
+        let side = order.bid_or_ask();
+        let reference = orderbook.last_trade_price().or(match side {
+            BidOrAsk::Bid => orderbook.first_price_ask(),
+            BidOrAsk::Ask => orderbook.first_price_bid(),
+        });
+        let reference = reference.ok_or_else(|| {
+            EngineError::InvalidRequest(format!("no price to trail in {}", self.markets[market_id as usize]))
+        })?;
+        let stop_tick = offset.stop_for(side, reference, self.specs[market_id as usize].tick_size_ticks());
+        if stop_tick <= 0 {
+            return Err(EngineError::InvalidRequest(format!("trailing offset {:?} is wider than the price", offset)));
+        }
+
+        self.validate_order(market_id, Some(stop_tick), order.size())?;
+        self.ensure_order_identity(&mut order);
+        self.add_pending_stop(market_id, PendingStop { order, stop_price: stop_tick, limit_price: None, trailing: Some(offset) })
+    }
+
+    pub fn place_trailing_stop_order(
+        &mut self,
+        pair: &TradingPair,
+        offset: TrailingOffset,
+        order: Order,
+    ) -> Result<OrderResponse, EngineError> {
+        let market_id = self
+            .get_market_id(pair)
+            .ok_or_else(|| EngineError::UnknownMarket(pair.clone()))?;
+        self.place_trailing_stop_order_by_id(market_id, offset, order)
+    }
+
+    /// Records a validated stop and puts it in the market's trigger book.
+    fn add_pending_stop(&mut self, market_id: MarketId, stop: PendingStop) -> Result<OrderResponse, EngineError> {
+        self.expire_orders();
+        let pair = self
+            .markets
+            .get(market_id as usize)
+            .cloned()
+            .ok_or(EngineError::UnknownMarketId(market_id))?;
+
+        let order_type = match (stop.trailing, stop.limit_price) {
+            (Some(_), _) => OrderType::TrailingStop,
+            (None, Some(_)) => OrderType::StopLimit,
+            (None, None) => OrderType::StopMarket,
+        };
+        let order = &stop.order;
+        let mut snapshot =
+            Self::snapshot_from_order(pair, order, order_type, stop.limit_price, order.size(), OrderStatus::New);
+        snapshot.trigger_price = Some(stop.stop_price);
+        snapshot.trailing_offset = stop.trailing;
+        let order_id = snapshot.id;
+        let stop_tick = stop.stop_price;
+        self.orders.insert(order_id, snapshot);
+        self.trigger_books[market_id as usize].insert(stop);
+        self.run_triggers(market_id);
+
+        let snapshot = self
+            .orders
+            .get(&order_id)
+            .cloned()
+            .ok_or(EngineError::UnknownOrder(order_id))?;
+        let spec = &self.specs[market_id as usize];
+        let mut message = format!(
+            " received stop {} order with size {} in pair {} triggering at {}",
+            match snapshot.side {
+                BidOrAsk::Ask => "Ask",
+                BidOrAsk::Bid => "Bid",
+            },
+            lots_to_size(snapshot.original_size, spec.lot_size),
+            snapshot.pair,
+            spec.display_price(stop_tick)
+        );
+        if let Some(triggered_id) = snapshot.triggered_order_id {
+            message.push_str(&format!(", triggered immediately as order {}", triggered_id));
+        }
+        Ok(OrderResponse {
+            order: snapshot,
+            message,
+            trades: Vec::new(),
+            rejection: None,
+        })
+    }
+
+    pub fn place_stop_order_by_id(
+        &mut self,
+        market_id: MarketId,
+        stop_price: Decimal,
+        limit_price: Option<Decimal>,
+        order: Order,
+    ) -> Result<OrderResponse, EngineError> {
+        let stop_tick = self.checked_price_to_tick(market_id, stop_price)?;
+        let limit_tick = limit_price
+            .map(|price| self.checked_price_to_tick(market_id, price))
+            .transpose()?;
+        self.place_stop_order_by_id_tick(market_id, stop_tick, limit_tick, order)
+    }
+
+    pub fn place_stop_order(
+        &mut self,
+        pair: &TradingPair,
+        stop_price: Decimal,
+        limit_price: Option<Decimal>,
+        order: Order,
+    ) -> Result<OrderResponse, EngineError> {
+        let market_id = self
+            .get_market_id(pair)
+            .ok_or_else(|| EngineError::UnknownMarket(pair.clone()))?;
+        self.place_stop_order_by_id(market_id, stop_price, limit_price, order)
+    }
+
+    pub fn make_limit(&mut self, size: Qty, bid_or_ask: BidOrAsk) -> Order {
         Order::new(size, bid_or_ask)
     }
 
+    // Best opposite price a post-only order on `side` at `price_tick` would take, if any
+    fn post_only_touch(&self, market_id: MarketId, side: BidOrAsk, price_tick: Tick) -> Option<Tick> {
+        let orderbook = &self.orderbooks[market_id as usize];
+        match side {
+            BidOrAsk::Bid => orderbook.first_price_ask().filter(|best| *best <= price_tick),
+            BidOrAsk::Ask => orderbook.first_price_bid().filter(|best| *best >= price_tick),
+        }
+    }
+
+    // Where a sliding post-only order goes: one tick away from the touch
+    fn slide_price(&self, market_id: MarketId, side: BidOrAsk, best_price: Tick) -> Tick {
+        let tick_size = self.specs[market_id as usize].tick_size_ticks();
+        match side {
+            BidOrAsk::Bid => best_price - tick_size,
+            BidOrAsk::Ask => best_price + tick_size,
+        }
+    }
+
     fn place_limit_order_internal_by_id(
         &mut self,
         market_id: MarketId,
-        price_tick: Tick,
+        mut price_tick: Tick,
         mut order: Order,
-    ) -> Result<OrderSnapshot, String> {
+    ) -> Result<Execution, EngineError> {
+        self.validate_order(market_id, Some(price_tick), order.size())?;
         self.ensure_order_identity(&mut order);
+        self.expire_orders();
+        let original_size = order.size();
 
         let pair = self
             .markets
             .get(market_id as usize)
             .cloned()
-            .ok_or_else(|| format!("market id {} doesn't exist", market_id))?;
+            .ok_or(EngineError::UnknownMarketId(market_id))?;
 
-        let snapshot = Self::snapshot_from_order(
-            pair,
-            &order,
-            OrderType::Limit,
-            Some(price_tick),
-            order.size(),
-            OrderStatus::Open,
-        );
+        let now = self.now();
+        let expires_at = match order.time_in_force() {
+            TimeInForce::Day => Some(end_of_day(now)),
+            TimeInForce::GoodTillDate(deadline) if deadline <= now => {
+                return Err(EngineError::ExpiryInPast(deadline));
+            }
+            TimeInForce::GoodTillDate(deadline) => Some(deadline),
+            _ => None,
+        };
 
-        {
-            let orderbook = self
-                .orderbooks
-                .get_mut(market_id as usize)
-                .ok_or_else(|| format!("market id {} doesn't exist", market_id))?;
-
-            match order.bid_or_ask() {
-                BidOrAsk::Ask => {
-                    if let Some(best_bid) = orderbook.first_price_bid() {
-                        if best_bid >= price_tick {
-                            return Err(format!(
-                                "You can not place a sell order on that price {:?}. Try a market order.",
-                                tick_to_price(price_tick)
-                            ));
-                        }
-                    }
+        // A post-only order that would take liquidity is rejected, or slid next to the touch
+        if order.post_only() != PostOnly::Off {
+            if let Some(best_price) = self.post_only_touch(market_id, order.bid_or_ask(), price_tick) {
+                if order.post_only() == PostOnly::Slide {
+                    price_tick = self.slide_price(market_id, order.bid_or_ask(), best_price);
+                    self.validate_order(market_id, Some(price_tick), order.size())?;
+                } else {
+                    let rejection = OrderRejection::WouldCross {
+                        price: tick_to_price(price_tick),
+                        best_price: tick_to_price(best_price),
+                    };
+                    let mut snapshot = Self::snapshot_from_order(
+                        pair,
+                        &order,
+                        OrderType::Limit,
+                        Some(price_tick),
+                        original_size,
+                        OrderStatus::Rejected,
+                    );
+                    Self::cancel_remainder_snapshot(&mut snapshot);
+                    self.orders.insert(snapshot.id, snapshot.clone());
+                    let report = FillReport {
+                        remaining_qty: original_size,
+                        canceled_qty: original_size,
+                        ..FillReport::default()
+                    };
+                    return Ok(Execution { snapshot, report, trades: Vec::new(), rejection: Some(rejection) });
                 }
-                BidOrAsk::Bid => {
-                    if let Some(best_ask) = orderbook.first_price_ask() {
-                        if best_ask <= price_tick {
-                            return Err(format!(
-                                "You can not place a buy order on that price {:?}. Try a market order.",
-                                tick_to_price(price_tick)
-                            ));
-                        }
-                    }
+            }
+        }
+
+        if order.self_trade_prevention().is_none() {
+            order.set_self_trade_prevention(Some(self.specs[market_id as usize].self_trade_prevention));
+        }
+        if self.enforce_balances {
+            self.reserve_funds(market_id, &order, Some(price_tick))?;
+        }
+
+        let mut fills = Vec::new();
+        let report = {
+            let (orderbooks, orders) = (&mut self.orderbooks, &mut self.orders);
+            let orderbook = orderbooks
+                .get_mut(market_id as usize)
+                .ok_or(EngineError::UnknownMarketId(market_id))?;
+
+            let mut on_fill = |fill: Fill| {
+                if let Some(snapshot) = orders.get_mut(&fill.maker_order_id) {
+                    Self::apply_fill_snapshot(snapshot, fill.qty);
                 }
+                fills.push(fill);
             };
 
-            orderbook.add_limit_order(price_tick, order);
+            // A marketable limit order takes liquidity up to its price first, only the rest rests
+            // (or is canceled for IOC / FOK).
+            orderbook.place_limit_order(price_tick, &mut order, &mut on_fill)
+        };
+
+        self.record_fill_stats(&report);
+        let trades = self.record_trades(market_id, &order, &fills);
+        if self.enforce_balances {
+            self.settle_trades(market_id, &trades)?;
+            if order.is_filled() || report.canceled_qty > 0 {
+                self.release_funds(order.id());
+            }
         }
 
+        let status = if report.fully_filled {
+            OrderStatus::Filled
+        } else if report.canceled_qty > 0 && order.time_in_force() == TimeInForce::FillOrKill {
+            OrderStatus::Rejected
+        } else if report.canceled_qty > 0 || order.is_filled() {
+            // `order.is_filled()` without a full fill: self-trade prevention canceled the rest
+            OrderStatus::Canceled
+        } else if report.filled_qty > 0 {
+            OrderStatus::PartiallyFilled
+        } else {
+            OrderStatus::Open
+        };
+
+        let mut snapshot = Self::snapshot_from_order(
+            pair,
+            &order,
+            OrderType::Limit,
+            Some(price_tick),
+            original_size,
+            status,
+        );
+        self.apply_self_trade_cancels(market_id, &mut snapshot, &report);
+        if report.canceled_qty > 0 {
+            Self::cancel_remainder_snapshot(&mut snapshot);
+        } else if !order.is_filled() {
+            snapshot.expires_at = expires_at;
+            if let Some(expires_at) = expires_at {
+                self.expiries.insert((expires_at, snapshot.id));
+            }
+        }
+
+        self.record_order_fills(market_id, &mut snapshot, &trades);
         self.orders.insert(snapshot.id, snapshot.clone());
-        Ok(snapshot)
+        if !trades.is_empty() {
+            self.run_triggers(market_id);
+        }
+        Ok(Execution { snapshot, report, trades, rejection: None })
     }
 
     pub fn place_limit_order_by_id_tick(
@@ -400,7 +2126,7 @@ impl MatchEngine {
         market_id: MarketId,
         price_tick: Tick,
         order: Order,
-    ) -> Result<String, String> {
+    ) -> Result<String, EngineError> {
         let response = self.place_limit_order_with_response_by_id_tick(market_id, price_tick, order)?;
         Ok(response.message)
     }
@@ -410,9 +2136,12 @@ impl MatchEngine {
         market_id: MarketId,
         price_tick: Tick,
         order: Order,
-    ) -> Result<(), String> {
-        let _ = self.place_limit_order_internal_by_id(market_id, price_tick, order)?;
-        Ok(())
+    ) -> Result<(), EngineError> {
+        let execution = self.place_limit_order_internal_by_id(market_id, price_tick, order)?;
+        match execution.rejection {
+            Some(rejection) => Err(EngineError::Rejected(rejection)),
+            None => Ok(()),
+        }
     }
 
     pub fn place_limit_order_with_response_by_id_tick(
@@ -420,24 +2149,58 @@ impl MatchEngine {
         market_id: MarketId,
         price_tick: Tick,
         order: Order,
-    ) -> Result<OrderResponse, String> {
-        let snapshot = self.place_limit_order_internal_by_id(market_id, price_tick, order)?;
+    ) -> Result<OrderResponse, EngineError> {
+        let Execution { snapshot, report, trades, rejection } =
+            self.place_limit_order_internal_by_id(market_id, price_tick, order)?;
         let side_label = match snapshot.side {
             BidOrAsk::Ask => "Ask",
             BidOrAsk::Bid => "Bid",
         };
-        let display_price = tick_to_price(price_tick);
-        let message = format!(
+        let spec = &self.specs[market_id as usize];
+        let display_price = spec.display_price(snapshot.price.unwrap_or(price_tick));
+        let lot_size = spec.lot_size;
+        let mut message = format!(
             " received {} order with size {} in pair {} on price {}",
             side_label,
-            snapshot.original_size,
-            snapshot.pair.to_string(),
+            lots_to_size(snapshot.original_size, lot_size),
+            snapshot.pair,
             display_price
         );
+        if report.filled_qty > 0 {
+            message.push_str(&format!(
+                ", filled {} immediately and {} resting",
+                lots_to_size(report.filled_qty, lot_size),
+                lots_to_size(snapshot.remaining_size, lot_size)
+            ));
+        }
+        if report.canceled_qty > 0 {
+            message.push_str(&format!(
+                ", {} canceled by time in force {:?}",
+                lots_to_size(report.canceled_qty, lot_size),
+                snapshot.time_in_force
+            ));
+        }
+        if report.self_trade_canceled_qty > 0 {
+            message.push_str(&format!(
+                ", {} canceled by self-trade prevention",
+                lots_to_size(report.self_trade_canceled_qty, lot_size)
+            ));
+        }
+        if snapshot.price != Some(price_tick) {
+            message.push_str(&format!(
+                ", repriced from {} by post-only slide",
+                spec.display_price(price_tick)
+            ));
+        }
+        if let Some(rejection) = &rejection {
+            message.push_str(&format!(", rejected: {}", rejection));
+        }
 
         Ok(OrderResponse {
             order: snapshot,
             message,
+            trades,
+            rejection,
         })
     }
 
@@ -446,8 +2209,8 @@ impl MatchEngine {
         market_id: MarketId,
         price: Decimal,
         order: Order,
-    ) -> Result<String, String> {
-        let price_tick = price_to_tick(price);
+    ) -> Result<String, EngineError> {
+        let price_tick = self.checked_price_to_tick(market_id, price)?;
         self.place_limit_order_by_id_tick(market_id, price_tick, order)
     }
 
@@ -456,8 +2219,8 @@ impl MatchEngine {
         market_id: MarketId,
         price: Decimal,
         order: Order,
-    ) -> Result<(), String> {
-        let price_tick = price_to_tick(price);
+    ) -> Result<(), EngineError> {
+        let price_tick = self.checked_price_to_tick(market_id, price)?;
         self.place_limit_order_raw_by_id_tick(market_id, price_tick, order)
     }
 
@@ -466,15 +2229,15 @@ impl MatchEngine {
         market_id: MarketId,
         price: Decimal,
         order: Order,
-    ) -> Result<OrderResponse, String> {
-        let price_tick = price_to_tick(price);
+    ) -> Result<OrderResponse, EngineError> {
+        let price_tick = self.checked_price_to_tick(market_id, price)?;
         self.place_limit_order_with_response_by_id_tick(market_id, price_tick, order)
     }
 
-    pub fn place_limit_order(&mut self, pair: &TradingPair, price: Decimal, order: Order) -> Result<String, String> {
+    pub fn place_limit_order(&mut self, pair: &TradingPair, price: Decimal, order: Order) -> Result<String, EngineError> {
         let market_id = self
             .get_market_id(pair)
-            .ok_or_else(|| format!("the orderbook {} doesn't exist ", pair.to_string()))?;
+            .ok_or_else(|| EngineError::UnknownMarket(pair.clone()))?;
         self.place_limit_order_by_id(market_id, price, order)
     }
 
@@ -483,10 +2246,10 @@ impl MatchEngine {
         pair: &TradingPair,
         price: Decimal,
         order: Order,
-    ) -> Result<(), String> {
+    ) -> Result<(), EngineError> {
         let market_id = self
             .get_market_id(pair)
-            .ok_or_else(|| format!("the orderbook {} doesn't exist ", pair.to_string()))?;
+            .ok_or_else(|| EngineError::UnknownMarket(pair.clone()))?;
         self.place_limit_order_raw_by_id(market_id, price, order)
     }
 
@@ -495,10 +2258,91 @@ impl MatchEngine {
         pair: &TradingPair,
         price: Decimal,
         order: Order,
-    ) -> Result<OrderResponse, String> {
+    ) -> Result<OrderResponse, EngineError> {
         let market_id = self
             .get_market_id(pair)
-            .ok_or_else(|| format!("the orderbook {} doesn't exist ", pair.to_string()))?;
+            .ok_or_else(|| EngineError::UnknownMarket(pair.clone()))?;
         self.place_limit_order_with_response_by_id(market_id, price, order)
     }
+
+    /// Places the order described by a JSON API request. A client order id is unique per user:
+    /// submitting one again, e.g. on a retry, places nothing and answers with the response
+    /// to its first successful submission.
+    pub fn submit_order(&mut self, request: &NewOrderRequest) -> Result<OrderResponse, EngineError> {
+        let Some(client_order_id) = request.client_order_id.clone() else {
+            return self.submit_new_order(request);
+        };
+        if client_order_id.is_empty() {
+            return Err(EngineError::InvalidRequest("a client order id can't be empty".to_string()));
+        }
+        let key = (request.user_id.clone(), client_order_id);
+        if let Some(response) = self.client_orders.get(&key) {
+            return Ok(response.clone());
+        }
+        let response = self.submit_new_order(request)?;
+        self.client_orders.insert(key, response.clone());
+        Ok(response)
+    }
+
+    fn submit_new_order(&mut self, request: &NewOrderRequest) -> Result<OrderResponse, EngineError> {
+        let market_id = self
+            .get_market_id(&request.market)
+            .ok_or_else(|| EngineError::UnknownMarket(request.market.clone()))?;
+        let lots = size_to_lots(request.size, self.specs[market_id as usize].lot_size)?;
+
+        let mut order = Order::new(lots, request.side);
+        order.set_user_id(request.user_id.clone());
+        order.set_time_in_force(request.time_in_force);
+        order.set_client_order_id(request.client_order_id.clone());
+        order.set_self_trade_prevention(request.self_trade_prevention);
+        order.set_post_only(request.post_only);
+        if let Some(display_size) = request.display_size {
+            if !matches!(request.order_type, OrderType::Limit | OrderType::StopLimit) {
+                return Err(EngineError::InvalidRequest("only limit orders can have a display size".to_string()));
+            }
+            let display_lots = size_to_lots(display_size, self.specs[market_id as usize].lot_size)?;
+            if display_lots == 0 {
+                return Err(EngineError::InvalidSize("display size must be positive".to_string()));
+            }
+            order.set_display_qty(Some(display_lots));
+        }
+
+        let is_stop = matches!(request.order_type, OrderType::StopMarket | OrderType::StopLimit);
+        if is_stop != request.stop_price.is_some() {
+            return Err(EngineError::InvalidRequest(
+                "a stop price is required for stop orders and only allowed for them".to_string(),
+            ));
+        }
+        let is_trailing = matches!(request.order_type, OrderType::TrailingStop);
+        if is_trailing != request.trailing_offset.is_some() {
+            return Err(EngineError::InvalidRequest(
+                "a trailing offset is required for trailing stops and only allowed for them".to_string(),
+            ));
+        }
+        match (request.order_type, request.price, request.stop_price, request.trailing_offset) {
+            (OrderType::Limit, Some(price), _, _) => self.place_limit_order_with_response_by_id(market_id, price, order),
+            (OrderType::Market, None, _, _) => self.fill_market_order_with_response_by_id(market_id, &mut order),
+            (OrderType::StopLimit, Some(price), Some(stop_price), _) => {
+                self.place_stop_order_by_id(market_id, stop_price, Some(price), order)
+            }
+            (OrderType::StopMarket, None, Some(stop_price), _) => {
+                self.place_stop_order_by_id(market_id, stop_price, None, order)
+            }
+            (OrderType::TrailingStop, None, _, Some(offset)) => {
+                self.place_trailing_stop_order_by_id(market_id, offset, order)
+            }
+            (OrderType::Limit | OrderType::StopLimit, None, _, _) => Err(EngineError::InvalidRequest(
+                "a limit order needs a price".to_string(),
+            )),
+            (OrderType::Market | OrderType::StopMarket | OrderType::TrailingStop, Some(_), _, _) => Err(
+                EngineError::InvalidRequest("a market order can't have a price".to_string()),
+            ),
+            (OrderType::StopMarket | OrderType::StopLimit, _, None, _) => Err(EngineError::InvalidRequest(
+                "a stop order needs a stop price".to_string(),
+            )),
+            (OrderType::TrailingStop, None, _, None) => Err(EngineError::InvalidRequest(
+                "a trailing stop needs a trailing offset".to_string(),
+            )),
+        }
+    }
 }
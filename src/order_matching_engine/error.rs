@@ -0,0 +1,137 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use std::error::Error;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::fmt;
+
+use super::clock::Timestamp;
+use super::engine::{MarketId, TradingPair};
+use super::market_spec::OrderRejection;
+use super::orderbook::{OrderId, Qty};
+
+/// Everything a `MatchEngine` call can fail with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineError {
+    UnknownMarket(TradingPair),
+    UnknownMarketId(MarketId),
+    UnknownOrder(OrderId),
+    /// No order of the user carries this client order id.
+    UnknownClientOrder { user_id: String, client_order_id: String },
+    /// The order exists but is not resting in a book (market, filled, canceled or expired).
+    OrderNotResting(OrderId),
+    /// Nothing of the order could be filled.
+    InsufficientLiquidity { requested: Qty, available: Qty },
+    /// The user's available balance can't cover the order.
+    InsufficientFunds { asset: String, required: Decimal, available: Decimal },
+    InvalidSize(String),
+    /// A request that doesn't describe a valid order, e.g. a limit order without a price.
+    InvalidRequest(String),
+    /// The order broke a rule of its market's spec.
+    Rejected(OrderRejection),
+    ExpiryInPast(Timestamp),
+    InvalidMarketSpec(String),
+    /// The engine follows a leader and only takes commands from its replication stream.
+    NotLeader,
+    /// The engine's indexes disagree with each other; never expected.
+    Internal(String),
+}
+
+impl EngineError {
+    /// Stable, machine-readable identifier of the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            EngineError::UnknownMarket(_) | EngineError::UnknownMarketId(_) => "unknown_market",
+            EngineError::UnknownOrder(_) | EngineError::UnknownClientOrder { .. } => "unknown_order",
+            EngineError::OrderNotResting(_) => "order_not_resting",
+            EngineError::InsufficientLiquidity { .. } => "insufficient_liquidity",
+            EngineError::InsufficientFunds { .. } => "insufficient_funds",
+            EngineError::InvalidSize(_) => "invalid_size",
+            EngineError::InvalidRequest(_) => "invalid_request",
+            EngineError::Rejected(rejection) => rejection.code(),
+            EngineError::ExpiryInPast(_) => "expiry_in_past",
+            EngineError::InvalidMarketSpec(_) => "invalid_market_spec",
+            EngineError::NotLeader => "not_leader",
+            EngineError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineError::UnknownMarket(pair) => write!(f, "the orderbook {} doesn't exist", pair),
+            EngineError::UnknownMarketId(market_id) => write!(f, "market id {} doesn't exist", market_id),
+            EngineError::UnknownOrder(order_id) => write!(f, "order {} doesn't exist", order_id),
+            EngineError::UnknownClientOrder { user_id, client_order_id } => {
+                write!(f, "{} has no order with client order id {}", user_id, client_order_id)
+            }
+            EngineError::OrderNotResting(order_id) => {
+                write!(f, "order {} is not resting and can not be canceled", order_id)
+            }
+            EngineError::InsufficientLiquidity { requested, available } => write!(
+                f,
+                "Not enough liquidity to fill {} lots, {} available",
+                requested, available
+            ),
+            EngineError::InsufficientFunds { asset, required, available } => write!(
+                f,
+                "Not enough {} available: {} required, {} available",
+                asset, required, available
+            ),
+            EngineError::InvalidSize(reason) => write!(f, "{}", reason),
+            EngineError::InvalidRequest(reason) => write!(f, "{}", reason),
+            EngineError::Rejected(rejection) => write!(f, "{}", rejection),
+            EngineError::ExpiryInPast(deadline) => {
+                write!(f, "good-till-date {} is already in the past", deadline)
+            }
+            EngineError::InvalidMarketSpec(reason) => write!(f, "invalid market spec: {}", reason),
+            EngineError::NotLeader => write!(f, "this engine is a follower, send commands to the leader"),
+            EngineError::Internal(reason) => write!(f, "internal error: {}", reason),
+        }
+    }
+}
+
+impl Error for EngineError {}
+
+impl From<OrderRejection> for EngineError {
+    fn from(rejection: OrderRejection) -> EngineError {
+        EngineError::Rejected(rejection)
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    // Stable identifier clients can match on, the message is for humans
+    code: &'static str,
+    message: String,
+}
+
+pub fn error_response(status: StatusCode, code: &'static str, message: impl Into<String>) -> HttpResponse {
+    HttpResponse::build(status).json(ErrorBody { code, message: message.into() })
+}
+
+impl ResponseError for EngineError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            EngineError::UnknownMarket(_)
+            | EngineError::UnknownMarketId(_)
+            | EngineError::UnknownOrder(_)
+            | EngineError::UnknownClientOrder { .. } => StatusCode::NOT_FOUND,
+            EngineError::OrderNotResting(_) => StatusCode::CONFLICT,
+            EngineError::InsufficientLiquidity { .. }
+            | EngineError::InsufficientFunds { .. }
+            | EngineError::Rejected(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            EngineError::InvalidSize(_)
+            | EngineError::InvalidRequest(_)
+            | EngineError::ExpiryInPast(_)
+            | EngineError::InvalidMarketSpec(_) => StatusCode::BAD_REQUEST,
+            EngineError::NotLeader => StatusCode::SERVICE_UNAVAILABLE,
+            EngineError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        error_response(self.status_code(), self.code(), self.to_string())
+    }
+}
@@ -0,0 +1,237 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::clock::Timestamp;
+use super::engine::{MarketId, NewOrderRequest, OrderResponse, OrderSnapshot, TradingPair};
+use super::market_spec::MarketSpec;
+use super::orderbook::{MarketOrderPolicy, Order};
+
+/// Format version written into every journal record.
+pub const JOURNAL_VERSION: u32 = 1;
+
+/// Everything that changes the state of a `MatchEngine`, as applied by `MatchEngine::execute`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Command {
+    AddMarket { pair: TradingPair, spec: MarketSpec },
+    SetEnforceBalances { enabled: bool },
+    SubmitOrder(NewOrderRequest),
+    PlaceLimitOrder { pair: TradingPair, price: Decimal, order: Order },
+    FillMarketOrder { pair: TradingPair, order: Order, policy: MarketOrderPolicy },
+    CancelOrder { order_id: u64 },
+    CancelOrderByClientId { user_id: String, client_order_id: String },
+    AmendOrder { order_id: u64, price: Option<Decimal>, size: Option<Decimal> },
+    Deposit { user_id: String, asset: String, amount: Decimal },
+    Withdraw { user_id: String, asset: String, amount: Decimal },
+    /// Sweeps DAY and good-till-date orders whose deadline has passed.
+    ExpireOrders,
+}
+
+/// What a command produced. Serializes as the bare value, e.g. the order response.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum CommandOutput {
+    Market(MarketId),
+    Order(Box<OrderResponse>),
+    /// Id of the ledger entry a deposit or withdrawal posted.
+    LedgerEntry(u64),
+    Expired(Vec<OrderSnapshot>),
+    Done,
+}
+
+/// A command with its place in the engine's command sequence and the engine time it
+/// was applied at.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalRecord {
+    pub version: u32,
+    pub sequence: u64,
+    pub timestamp: Timestamp,
+    pub command: Command,
+}
+
+impl JournalRecord {
+    pub fn new(sequence: u64, timestamp: Timestamp, command: Command) -> JournalRecord {
+        JournalRecord { version: JOURNAL_VERSION, sequence, timestamp, command }
+    }
+}
+
+/// When appended records are forced to disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FsyncPolicy {
+    /// After every record: nothing acknowledged is lost if the machine crashes.
+    #[default]
+    Always,
+    /// After every `n` records.
+    Batch(u32),
+    /// Left to the OS; a crash of the process alone still loses nothing.
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    /// `always`, `never`, or a batch size such as `100`.
+    fn from_str(value: &str) -> Result<FsyncPolicy, String> {
+        match value {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            batch => match batch.parse::<u32>() {
+                Ok(n) if n > 0 => Ok(FsyncPolicy::Batch(n)),
+                _ => Err(format!("invalid fsync policy {:?}, expected always, never or a batch size", value)),
+            },
+        }
+    }
+}
+
+/// Append-only write-ahead log of engine commands, one JSON record per line.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    fsync: FsyncPolicy,
+    // Records appended since the last fsync
+    unsynced: u32,
+    last_sequence: u64,
+    // Length of the complete records in the file
+    len: u64,
+    // Set when a failed write couldn't be cut off the end, so appending would corrupt the file
+    torn: bool,
+}
+
+impl Journal {
+    /// Opens the journal at `path` for appending, creating it if needed. A record torn
+    /// by a crash in the middle of a write is cut off the end.
+    pub fn open(path: impl AsRef<Path>, fsync: FsyncPolicy) -> io::Result<Journal> {
+        let path = path.as_ref().to_path_buf();
+        let (records, valid_len) = if path.exists() { read_records(&path)? } else { (Vec::new(), 0) };
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if file.metadata()?.len() > valid_len {
+            file.set_len(valid_len)?;
+        }
+        Ok(Journal { path, file, fsync, unsynced: 0, last_sequence: last_sequence(&records), len: valid_len, torn: false })
+    }
+
+    /// Every complete record of the journal at `path`, in order.
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Vec<JournalRecord>> {
+        read_records(path.as_ref()).map(|(records, _)| records)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn fsync_policy(&self) -> FsyncPolicy {
+        self.fsync
+    }
+
+    /// Sequence of the last record written, 0 for an empty journal.
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// Lets an empty journal carry on from `sequence`, for an engine whose earlier commands
+    /// are in a snapshot rather than in this journal.
+    pub fn start_after(&mut self, sequence: u64) -> io::Result<()> {
+        if self.last_sequence != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("journal {} already holds records", self.path.display()),
+            ));
+        }
+        self.last_sequence = sequence;
+        Ok(())
+    }
+
+    /// Writes `record` in a single write, then syncs as the fsync policy says.
+    /// Records must come in sequence. A write that fails partway is cut off again, so the
+    /// next record starts on a clean line; if even that fails, the journal refuses further
+    /// records until it is reopened.
+    pub fn append(&mut self, record: &JournalRecord) -> io::Result<()> {
+        self.append_with(record, |file, line| file.write_all(line))
+    }
+
+    /// `append` with the write done by `write`, which lets tests fail it partway.
+    pub(crate) fn append_with(
+        &mut self,
+        record: &JournalRecord,
+        write: impl FnOnce(&mut File, &[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        if self.torn {
+            return Err(io::Error::other(format!(
+                "journal {} ends in a torn record, reopen it to carry on",
+                self.path.display()
+            )));
+        }
+        if record.sequence != self.last_sequence + 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("record {} doesn't follow record {}", record.sequence, self.last_sequence),
+            ));
+        }
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        if let Err(err) = write(&mut self.file, &line) {
+            if self.file.set_len(self.len).is_err() {
+                self.torn = true;
+            }
+            return Err(err);
+        }
+        self.len += line.len() as u64;
+        self.last_sequence = record.sequence;
+
+        self.unsynced += 1;
+        let due = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Batch(n) => self.unsynced >= n,
+            FsyncPolicy::Never => false,
+        };
+        if due {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+fn last_sequence(records: &[JournalRecord]) -> u64 {
+    records.last().map_or(0, |record| record.sequence)
+}
+
+// Records up to the first torn one, with the length of the file they take up.
+fn read_records(path: &Path) -> io::Result<(Vec<JournalRecord>, u64)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    let mut valid_len = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line)?;
+        if read == 0 {
+            break;
+        }
+        if !line.ends_with('\n') {
+            // The last write didn't finish
+            break;
+        }
+        let record: JournalRecord = serde_json::from_str(&line).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("bad journal record after sequence {}: {}", last_sequence(&records), err))
+        })?;
+        if record.version != JOURNAL_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("journal record {} has version {}, expected {}", record.sequence, record.version, JOURNAL_VERSION),
+            ));
+        }
+        records.push(record);
+        valid_len += read as u64;
+    }
+    Ok((records, valid_len))
+}
@@ -0,0 +1,162 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::accounts::Accounts;
+use super::clock::Timestamp;
+use super::error::EngineError;
+
+/// Side of a posting. `External` is the outside world funds come from and go back to.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum LedgerAccount {
+    User(String),
+    External,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    Deposit,
+    Withdrawal,
+    Trade { trade_id: u64 },
+    Fee { trade_id: u64 },
+}
+
+/// One leg of a journal entry; positive amounts credit the account, negative debit it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Posting {
+    pub account: LedgerAccount,
+    pub asset: String,
+    pub amount: Decimal,
+}
+
+impl Posting {
+    pub fn new(account: LedgerAccount, asset: &str, mut amount: Decimal) -> Posting {
+        // A negated zero fee serializes as "-0", which reads back without its sign
+        if amount.is_zero() {
+            amount.set_sign_positive(true);
+        }
+        Posting { account, asset: asset.to_string(), amount }
+    }
+}
+
+/// A balanced set of postings: per asset they sum to zero.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub id: u64,
+    pub kind: EntryKind,
+    pub timestamp: Timestamp,
+    pub postings: Vec<Posting>,
+}
+
+/// Append-only double-entry journal of every movement of funds.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "LedgerData")]
+pub struct Ledger {
+    entries: Vec<JournalEntry>,
+    // (account, asset) -> sum of postings, rebuilt from the entries when deserialized
+    #[serde(skip)]
+    balances: BTreeMap<(LedgerAccount, String), Decimal>,
+}
+
+#[derive(Deserialize)]
+struct LedgerData {
+    entries: Vec<JournalEntry>,
+}
+
+impl From<LedgerData> for Ledger {
+    fn from(data: LedgerData) -> Ledger {
+        let mut balances: BTreeMap<(LedgerAccount, String), Decimal> = BTreeMap::new();
+        for posting in data.entries.iter().flat_map(|entry| &entry.postings) {
+            *balances.entry((posting.account.clone(), posting.asset.clone())).or_default() += posting.amount;
+        }
+        Ledger { entries: data.entries, balances }
+    }
+}
+
+impl Ledger {
+    pub fn new() -> Ledger {
+        Ledger::default()
+    }
+
+    pub fn entries(&self) -> &[JournalEntry] {
+        &self.entries
+    }
+
+    pub fn balance(&self, account: &LedgerAccount, asset: &str) -> Decimal {
+        self.balances
+            .get(&(account.clone(), asset.to_string()))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Deposits minus withdrawals of `asset`.
+    pub fn net_deposits(&self, asset: &str) -> Decimal {
+        -self.balance(&LedgerAccount::External, asset)
+    }
+
+    /// Appends an entry; refused unless its postings balance per asset.
+    pub fn post(&mut self, kind: EntryKind, timestamp: Timestamp, postings: Vec<Posting>) -> Result<u64, EngineError> {
+        let mut sums: BTreeMap<&str, Decimal> = BTreeMap::new();
+        for posting in &postings {
+            *sums.entry(posting.asset.as_str()).or_default() += posting.amount;
+        }
+        if let Some((asset, sum)) = sums.iter().find(|(_, sum)| !sum.is_zero()) {
+            return Err(EngineError::Internal(format!(
+                "journal entry {:?} is off by {} {}",
+                kind, sum, asset
+            )));
+        }
+
+        for posting in &postings {
+            *self
+                .balances
+                .entry((posting.account.clone(), posting.asset.clone()))
+                .or_default() += posting.amount;
+        }
+        let id = self.entries.len() as u64 + 1;
+        self.entries.push(JournalEntry { id, kind, timestamp, postings });
+        Ok(id)
+    }
+
+    /// Proves the books agree: per asset, the user accounts add up to net deposits, and
+    /// each user's journal balance equals their available plus locked balance.
+    pub fn reconcile(&self, accounts: &Accounts) -> Result<(), EngineError> {
+        let mut assets: BTreeSet<String> = self.balances.keys().map(|(_, asset)| asset.clone()).collect();
+        assets.extend(accounts.assets());
+        for asset in &assets {
+            let held = accounts.asset_total(asset);
+            let net_deposits = self.net_deposits(asset);
+            if held != net_deposits {
+                return Err(EngineError::Internal(format!(
+                    "accounts hold {} {} but net deposits are {}",
+                    held, asset, net_deposits
+                )));
+            }
+        }
+
+        for (user_id, asset, balance) in accounts.iter() {
+            let journaled = self.balance(&LedgerAccount::User(user_id.to_string()), asset);
+            if balance.total() != journaled {
+                return Err(EngineError::Internal(format!(
+                    "{} holds {} {} but the journal says {}",
+                    user_id,
+                    balance.total(),
+                    asset,
+                    journaled
+                )));
+            }
+        }
+        for ((account, asset), journaled) in &self.balances {
+            if let LedgerAccount::User(user_id) = account {
+                let held = accounts.balance(user_id, asset).total();
+                if held != *journaled {
+                    return Err(EngineError::Internal(format!(
+                        "{} holds {} {} but the journal says {}",
+                        user_id, held, asset, journaled
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
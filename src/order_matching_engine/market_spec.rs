@@ -0,0 +1,242 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use super::engine::{lots_to_size, tick_to_price, DEFAULT_LOT_SIZE, PRICE_SCALE};
+use super::orderbook::{Qty, SelfTradePrevention, Tick};
+
+/// Trading rules of a market. Prices and sizes are given in quote and base units.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarketSpec {
+    /// Smallest price increment, a multiple of `1 / PRICE_SCALE`.
+    pub tick_size: Decimal,
+    /// Quantity step; order sizes are whole multiples of it.
+    pub lot_size: Decimal,
+    pub min_qty: Decimal,
+    /// No upper bound when `None`.
+    pub max_qty: Option<Decimal>,
+    /// Smallest price * size a limit order may have, 0 for none.
+    pub min_notional: Decimal,
+    /// Decimal places prices are shown with.
+    pub price_precision: u32,
+    /// Maker and taker rates, free when missing.
+    #[serde(default)]
+    pub fees: FeeSchedule,
+    /// Applied to orders that don't choose their own.
+    #[serde(default)]
+    pub self_trade_prevention: SelfTradePrevention,
+}
+
+/// Fee rates that apply from a 30-day traded volume (in quote units) upwards.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeeTier {
+    pub min_volume: Decimal,
+    /// A negative maker rate is a rebate.
+    pub maker_rate: Decimal,
+    pub taker_rate: Decimal,
+}
+
+/// Volume tiers of a market, ascending by `min_volume`; the first starts at 0.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeeSchedule {
+    pub tiers: Vec<FeeTier>,
+}
+
+impl Default for FeeSchedule {
+    fn default() -> FeeSchedule {
+        FeeSchedule::flat(Decimal::ZERO, Decimal::ZERO)
+    }
+}
+
+impl FeeSchedule {
+    /// The same rates for every volume.
+    pub fn flat(maker_rate: Decimal, taker_rate: Decimal) -> FeeSchedule {
+        FeeSchedule {
+            tiers: vec![FeeTier { min_volume: Decimal::ZERO, maker_rate, taker_rate }],
+        }
+    }
+
+    /// Tier of a user who traded `volume` in the last 30 days.
+    pub fn tier_for(&self, volume: Decimal) -> &FeeTier {
+        self.tiers
+            .iter()
+            .rev()
+            .find(|tier| tier.min_volume <= volume)
+            .unwrap_or(&self.tiers[0])
+    }
+
+    /// Highest rate any order can be charged, what buys lock on top of their notional.
+    pub fn max_rate(&self) -> Decimal {
+        self.tiers
+            .iter()
+            .map(|tier| tier.maker_rate.max(tier.taker_rate))
+            .fold(Decimal::ZERO, Decimal::max)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let first = self.tiers.first().ok_or("a fee schedule needs at least one tier")?;
+        if !first.min_volume.is_zero() {
+            return Err(format!("the first fee tier starts at {} instead of 0", first.min_volume));
+        }
+        if self.tiers.windows(2).any(|pair| pair[0].min_volume >= pair[1].min_volume) {
+            return Err("fee tiers must be in ascending volume order".to_string());
+        }
+        let mut lowest_maker = Decimal::ZERO;
+        let mut lowest_taker = Decimal::ONE;
+        for tier in &self.tiers {
+            if tier.taker_rate.is_sign_negative() || tier.taker_rate >= Decimal::ONE || tier.maker_rate >= Decimal::ONE {
+                return Err(format!("fee rates {} / {} are out of range", tier.maker_rate, tier.taker_rate));
+            }
+            lowest_maker = lowest_maker.min(tier.maker_rate);
+            lowest_taker = lowest_taker.min(tier.taker_rate);
+        }
+        // The house pays rebates out of taker fees, so no rebate may exceed the lowest taker fee
+        if -lowest_maker > lowest_taker {
+            return Err(format!(
+                "maker rebate {} exceeds the lowest taker fee {}",
+                -lowest_maker, lowest_taker
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for MarketSpec {
+    fn default() -> MarketSpec {
+        MarketSpec {
+            tick_size: Decimal::ONE / Decimal::from(PRICE_SCALE),
+            lot_size: DEFAULT_LOT_SIZE,
+            min_qty: Decimal::ZERO,
+            max_qty: None,
+            min_notional: Decimal::ZERO,
+            price_precision: 4,
+            fees: FeeSchedule::default(),
+            self_trade_prevention: SelfTradePrevention::default(),
+        }
+    }
+}
+
+/// Why an order was refused by its market's spec.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderRejection {
+    ZeroSize,
+    PriceNotPositive { price: Decimal },
+    PriceNotOnTick { price: Decimal, tick_size: Decimal },
+    SizeBelowMinimum { size: Decimal, min_qty: Decimal },
+    SizeAboveMaximum { size: Decimal, max_qty: Decimal },
+    NotionalBelowMinimum { notional: Decimal, min_notional: Decimal },
+    /// A post-only order would have taken liquidity at `best_price`.
+    WouldCross { price: Decimal, best_price: Decimal },
+}
+
+impl fmt::Display for OrderRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderRejection::ZeroSize => write!(f, "order size must be positive"),
+            OrderRejection::PriceNotPositive { price } => write!(f, "price {} must be positive", price),
+            OrderRejection::PriceNotOnTick { price, tick_size } => {
+                write!(f, "price {} is not a multiple of the tick size {}", price, tick_size)
+            }
+            OrderRejection::SizeBelowMinimum { size, min_qty } => {
+                write!(f, "size {} is below the minimum of {}", size, min_qty)
+            }
+            OrderRejection::SizeAboveMaximum { size, max_qty } => {
+                write!(f, "size {} is above the maximum of {}", size, max_qty)
+            }
+            OrderRejection::NotionalBelowMinimum { notional, min_notional } => {
+                write!(f, "notional {} is below the minimum of {}", notional, min_notional)
+            }
+            OrderRejection::WouldCross { price, best_price } => {
+                write!(f, "post-only order at {} would cross the book at {}", price, best_price)
+            }
+        }
+    }
+}
+
+impl OrderRejection {
+    pub fn code(&self) -> &'static str {
+        match self {
+            OrderRejection::ZeroSize => "zero_size",
+            OrderRejection::PriceNotPositive { .. } => "price_not_positive",
+            OrderRejection::PriceNotOnTick { .. } => "price_not_on_tick",
+            OrderRejection::SizeBelowMinimum { .. } => "size_below_minimum",
+            OrderRejection::SizeAboveMaximum { .. } => "size_above_maximum",
+            OrderRejection::NotionalBelowMinimum { .. } => "notional_below_minimum",
+            OrderRejection::WouldCross { .. } => "post_only_would_cross",
+        }
+    }
+}
+
+impl MarketSpec {
+    /// Checks the spec itself is usable before a market is created with it.
+    pub fn validate_spec(&self) -> Result<(), String> {
+        if self.tick_size <= Decimal::ZERO {
+            return Err(format!("tick size {} must be positive", self.tick_size));
+        }
+        if !(self.tick_size * Decimal::from(PRICE_SCALE)).fract().is_zero() {
+            return Err(format!(
+                "tick size {} is finer than the engine price scale of {}",
+                self.tick_size, PRICE_SCALE
+            ));
+        }
+        if self.lot_size <= Decimal::ZERO {
+            return Err(format!("lot size {} must be positive", self.lot_size));
+        }
+        if self.min_qty.is_sign_negative() || self.min_notional.is_sign_negative() {
+            return Err("minimum size and notional can't be negative".to_string());
+        }
+        if let Some(max_qty) = self.max_qty {
+            if max_qty < self.min_qty {
+                return Err(format!("maximum size {} is below the minimum size {}", max_qty, self.min_qty));
+            }
+        }
+        self.fees.validate()
+    }
+
+    /// Tick size expressed in engine ticks.
+    pub fn tick_size_ticks(&self) -> Tick {
+        (self.tick_size * Decimal::from(PRICE_SCALE)).trunc().try_into().unwrap_or(1)
+    }
+
+    /// Price of `tick` rounded to the market's display precision.
+    pub fn display_price(&self, tick: Tick) -> Decimal {
+        tick_to_price(tick).round_dp(self.price_precision)
+    }
+
+    /// Validates an order of `qty` lots. Market orders have no `price` and skip the
+    /// price and notional checks.
+    pub fn validate_order(&self, price: Option<Tick>, qty: Qty) -> Result<(), OrderRejection> {
+        if qty == 0 {
+            return Err(OrderRejection::ZeroSize);
+        }
+        let size = lots_to_size(qty, self.lot_size);
+        if size < self.min_qty {
+            return Err(OrderRejection::SizeBelowMinimum { size, min_qty: self.min_qty });
+        }
+        if let Some(max_qty) = self.max_qty {
+            if size > max_qty {
+                return Err(OrderRejection::SizeAboveMaximum { size, max_qty });
+            }
+        }
+
+        if let Some(price) = price {
+            if price <= 0 {
+                return Err(OrderRejection::PriceNotPositive { price: tick_to_price(price) });
+            }
+            if price % self.tick_size_ticks() != 0 {
+                return Err(OrderRejection::PriceNotOnTick {
+                    price: tick_to_price(price),
+                    tick_size: self.tick_size,
+                });
+            }
+            let notional = tick_to_price(price) * size;
+            if self.min_notional > Decimal::ZERO && notional < self.min_notional {
+                return Err(OrderRejection::NotionalBelowMinimum {
+                    notional,
+                    min_notional: self.min_notional,
+                });
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,12 @@
 pub mod orderbook;
 pub mod engine;
+pub mod clock;
+pub mod market_spec;
+pub mod error;
+pub mod accounts;
+pub mod ledger;
+pub mod trigger_book;
+pub mod journal;
+pub mod snapshot;
+pub mod replication;
 pub mod testing;
\ No newline at end of file
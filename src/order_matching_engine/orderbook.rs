@@ -1,52 +1,256 @@
 #![allow(dead_code)]
 
-use serde::{Serialize,Deserialize};
-use std::collections::{BTreeMap, VecDeque};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+
+use super::clock::Timestamp;
 
 pub type OrderId = u64;
 pub type Tick = i64;
+/// Order quantities are whole lots; the lot size of each market lives in the engine.
+pub type Qty = u64;
+pub type SlotId = usize;
+/// Book-local number of the user an order belongs to, 0 for anonymous orders.
+pub type OwnerId = u32;
+
+fn is_zero(qty: &Qty) -> bool {
+    *qty == 0
+}
 
+fn is_anonymous(owner: &OwnerId) -> bool {
+    *owner == 0
+}
+
+/// An order in a `Limit` queue. For an iceberg order `qty` is the visible peak and
+/// `reserve` the hidden rest, which refills the peak up to `peak` each time it's consumed.
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct RestingOrder {
     id: OrderId,
-    qty: f64,
+    qty: Qty,
+    // 0 in the public view, which leaves it out
+    #[serde(default, skip_serializing_if = "is_anonymous")]
+    owner: OwnerId,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    reserve: Qty,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    peak: Qty,
 }
 
 impl RestingOrder {
-    pub fn new(id: OrderId, qty: f64) -> RestingOrder {
-        RestingOrder { id, qty }
+    pub fn new(id: OrderId, qty: Qty) -> RestingOrder {
+        RestingOrder { id, qty, owner: 0, reserve: 0, peak: 0 }
+    }
+    pub fn with_owner(id: OrderId, qty: Qty, owner: OwnerId) -> RestingOrder {
+        RestingOrder { id, qty, owner, reserve: 0, peak: 0 }
+    }
+    /// Shows only `display_qty` of the order at a time; a display quantity of the whole
+    /// order or more leaves it a plain order.
+    pub fn with_display_qty(self, display_qty: Option<Qty>) -> RestingOrder {
+        let total = self.total_qty();
+        match display_qty {
+            Some(peak) if peak > 0 && peak < total => RestingOrder { qty: peak, reserve: total - peak, peak, ..self },
+            _ => self,
+        }
     }
     pub fn id(&self) -> OrderId { self.id }
-    pub fn qty(&self) -> f64 { self.qty }
-    pub fn set_qty(&mut self, qty: f64) { self.qty = qty; }
+    /// Visible quantity.
+    pub fn qty(&self) -> Qty { self.qty }
+    pub fn owner(&self) -> OwnerId { self.owner }
+    /// Hidden quantity of an iceberg order.
+    pub fn reserve(&self) -> Qty { self.reserve }
+    /// Visible quantity an iceberg order refills to, 0 for plain orders.
+    pub fn peak(&self) -> Qty { self.peak }
+    pub fn is_iceberg(&self) -> bool { self.peak > 0 }
+    /// Visible plus hidden quantity.
+    pub fn total_qty(&self) -> Qty { self.qty + self.reserve }
+    pub fn set_qty(&mut self, qty: Qty) { self.qty = qty; }
+}
+
+/// What happens when an incoming order meets a resting order of the same user.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SelfTradePrevention {
+    /// The orders trade with each other.
+    #[default]
+    Allow,
+    /// The rest of the incoming order is canceled.
+    CancelNewest,
+    /// The resting order is canceled and matching goes on.
+    CancelOldest,
+    /// Both orders are canceled.
+    CancelBoth,
+    /// Both orders are reduced by the smaller quantity; whichever reaches zero is canceled.
+    DecrementAndCancel,
+}
+
+/// Whether a limit order may take liquidity.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PostOnly {
+    /// The order may match on arrival.
+    #[default]
+    Off,
+    /// An order that would match is rejected.
+    Reject,
+    /// An order that would match is repriced one tick away from the best opposite price.
+    Slide,
 }
 
 #[derive(Debug, Clone, Copy, Default)]
 pub struct FillStats {
     pub fills_total: u64,
     pub resting_orders_consumed: u64,
-    pub total_matched_qty: f64,
+    pub total_matched_qty: Qty,
+    /// Quantity of the incoming order canceled by self-trade prevention.
+    pub self_trade_canceled_qty: Qty,
 }
 
-#[derive(Debug, Clone)]
+/// A resting order canceled, fully or in part, by self-trade prevention.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SelfTradeCancel {
+    pub order_id: OrderId,
+    pub canceled_qty: Qty,
+    /// Quantity still resting, 0 once the order left the book.
+    pub resting_qty: Qty,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct FillReport {
     pub insufficient_liquidity: bool,
     pub fully_filled: bool,
-    pub filled_qty: f64,
-    pub remaining_qty: f64,
+    pub filled_qty: Qty,
+    pub remaining_qty: Qty,
     pub fills_total: u64,
     pub resting_orders_consumed: u64,
     pub levels_crossed: u64,
-    pub total_matched_qty: f64,
+    pub total_matched_qty: Qty,
+    /// Part of a market order that was dropped because the book couldn't fill it.
+    pub canceled_qty: Qty,
+    /// Quantity of the incoming order canceled by self-trade prevention; not part of `remaining_qty`.
+    pub self_trade_canceled_qty: Qty,
+    /// Resting orders self-trade prevention canceled or reduced.
+    pub self_trade_cancels: Vec<SelfTradeCancel>,
+}
+
+/// One execution of an incoming order against a resting (maker) order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fill {
+    pub maker_order_id: OrderId,
+    pub price: Tick,
+    pub qty: Qty,
+}
+
+/// What happens to a market order the book can't fully fill.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MarketOrderPolicy {
+    /// Fill whatever is available and cancel the rest.
+    #[default]
+    ImmediateOrCancel,
+    /// Fill the whole order or nothing at all.
+    FillOrKill,
+}
+
+/// How long an order stays working.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimeInForce {
+    /// Rests until filled or canceled.
+    #[default]
+    GoodTillCancel,
+    /// Takes what it can immediately, the rest is canceled.
+    ImmediateOrCancel,
+    /// Fills completely on arrival or not at all.
+    FillOrKill,
+    /// Rests until the end of the current UTC day.
+    Day,
+    /// Rests until the given timestamp (ms since epoch).
+    GoodTillDate(Timestamp),
+}
+
+impl TimeInForce {
+    /// Whether an unfilled remainder is allowed to rest in the book.
+    pub fn rests(&self) -> bool {
+        matches!(self, TimeInForce::GoodTillCancel | TimeInForce::Day | TimeInForce::GoodTillDate(_))
+    }
+
+    /// The market order handling this time in force asks for, if any.
+    pub fn market_order_policy(&self) -> Option<MarketOrderPolicy> {
+        match self {
+            TimeInForce::ImmediateOrCancel => Some(MarketOrderPolicy::ImmediateOrCancel),
+            TimeInForce::FillOrKill => Some(MarketOrderPolicy::FillOrKill),
+            _ => None,
+        }
+    }
+}
+
+/// Where a resting order lives inside an `OrderBook`: its side, its price level and
+/// its slot in that level's arena.
+#[derive(Debug, Clone, Copy)]
+pub struct OrderLocation {
+    pub side: BidOrAsk,
+    pub price: Tick,
+    pub slot: SlotId,
 }
 
-#[derive(Debug,Serialize,Deserialize)]
+#[derive(Debug,Clone,Serialize,Deserialize)]
+#[serde(from = "OrderBookData")]
 pub struct OrderBook {
     asks: BTreeMap<Tick, Limit>,
 
     bids: BTreeMap<Tick, Limit>,
-    ask_capacity: f64,
-    bid_capacity: f64,
+    ask_capacity: Qty,
+    bid_capacity: Qty,
+    // Order id -> location, so cancels and lookups don't scan the levels.
+    // Orders with id 0 are anonymous and never indexed.
+    #[serde(skip)]
+    order_index: HashMap<OrderId, OrderLocation>,
+    // User id -> owner number of resting orders, for self-trade prevention. Empty, and
+    // left out, in the public view
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    owners: BTreeMap<String, OwnerId>,
+    // Price of the most recent fill, what stop orders trigger on
+    #[serde(default)]
+    last_trade_price: Option<Tick>,
+}
+
+#[derive(Deserialize)]
+struct OrderBookData {
+    asks: BTreeMap<Tick, Limit>,
+    bids: BTreeMap<Tick, Limit>,
+    ask_capacity: Qty,
+    bid_capacity: Qty,
+    #[serde(default)]
+    owners: BTreeMap<String, OwnerId>,
+    #[serde(default)]
+    last_trade_price: Option<Tick>,
+}
+
+impl From<OrderBookData> for OrderBook {
+    fn from(data: OrderBookData) -> OrderBook {
+        let mut order_index = HashMap::new();
+        for (side, levels) in [(BidOrAsk::Ask, &data.asks), (BidOrAsk::Bid, &data.bids)] {
+            for (&price, limit) in levels {
+                for (slot, order) in limit.iter_slots() {
+                    if order.id() != 0 {
+                        order_index.insert(order.id(), OrderLocation { side, price, slot });
+                    }
+                }
+            }
+        }
+        OrderBook {
+            asks: data.asks,
+            bids: data.bids,
+            ask_capacity: data.ask_capacity,
+            bid_capacity: data.bid_capacity,
+            order_index,
+            owners: data.owners,
+            last_trade_price: data.last_trade_price,
+        }
+    }
+}
+
+impl Default for OrderBook {
+    fn default() -> OrderBook {
+        OrderBook::new()
+    }
 }
 
 impl OrderBook {
@@ -55,13 +259,49 @@ impl OrderBook {
             asks: BTreeMap::new(),
 
             bids: BTreeMap::new(),
-            ask_capacity : 0.0,
-            bid_capacity : 0.0,
+            ask_capacity : 0,
+            bid_capacity : 0,
+            order_index: HashMap::new(),
+            owners: BTreeMap::new(),
+            last_trade_price: None,
         }}
+
+    /// Owner number of `user_id`, assigning one on first use. Anonymous users are 0.
+    fn owner_for(&mut self, user_id: &str) -> OwnerId {
+        if user_id.is_empty() {
+            return 0;
+        }
+        if let Some(owner) = self.owners.get(user_id) {
+            return *owner;
+        }
+        let owner = self.owners.len() as OwnerId + 1;
+        self.owners.insert(user_id.to_string(), owner);
+        owner
+    }
     
-    pub fn bid_capacity(&self) -> f64 { return self.bid_capacity;}
+    /// Copy of the book as the public may see it: iceberg orders show only their peak,
+    /// the capacities only count visible quantity and nothing says who owns an order.
+    pub fn public_view(&self) -> OrderBook {
+        let mut view = OrderBook::new();
+        for (side, levels) in [(BidOrAsk::Ask, &self.asks), (BidOrAsk::Bid, &self.bids)] {
+            for (&price, limit) in levels {
+                for order in limit.iter() {
+                    let visible = RestingOrder::new(order.id(), order.qty());
+                    view.add_order_from_price_in_bids_or_asks(price, visible, side);
+                    match side {
+                        BidOrAsk::Ask => view.ask_capacity += order.qty(),
+                        BidOrAsk::Bid => view.bid_capacity += order.qty(),
+                    }
+                }
+            }
+        }
+        view.last_trade_price = self.last_trade_price;
+        view
+    }
+
+    pub fn bid_capacity(&self) -> Qty { self.bid_capacity}
 
-    pub fn ask_capacity(&self) -> f64 { return  self.ask_capacity; }
+    pub fn ask_capacity(&self) -> Qty { self.ask_capacity}
 
     pub fn first_price_ask(&self) -> Option<Tick>{
         self.asks.keys().next().copied()
@@ -70,8 +310,13 @@ impl OrderBook {
         self.bids.keys().next_back().copied()
     }
 
+    /// Price of the most recent fill in this book.
+    pub fn last_trade_price(&self) -> Option<Tick> {
+        self.last_trade_price
+    }
+
     pub fn fill_order_book(&mut self, market_order:&mut Order) -> String  {
-        let report = self.fill_order_book_with_report(market_order, &mut |_order_id, _qty| {});
+        let report = self.fill_order_book_with_report(market_order, &mut |_fill| {});
         if report.insufficient_liquidity {
             match market_order.bid_or_ask {
                 BidOrAsk::Bid => String::from("Not enough ask orders to fill this buy"),
@@ -86,124 +331,209 @@ impl OrderBook {
         }
     }
 
+    /// Fills a market order all-or-nothing (`MarketOrderPolicy::FillOrKill`).
     pub fn fill_order_book_with_report<F>(&mut self, market_order: &mut Order, on_fill: &mut F) -> FillReport
     where
-        F: FnMut(OrderId, f64),
+        F: FnMut(Fill),
     {
-        let amount: f64 = market_order.size;
+        self.fill_order_book_with_policy(market_order, MarketOrderPolicy::FillOrKill, on_fill)
+    }
 
-        match market_order.bid_or_ask {
-            // Bid order (buy): match against asks (sellers) - need asks available
-            BidOrAsk::Bid => {
-                if self.ask_capacity < amount {
-                    return FillReport {
-                        insufficient_liquidity: true,
-                        fully_filled: false,
-                        filled_qty: 0.0,
-                        remaining_qty: amount,
-                        fills_total: 0,
-                        resting_orders_consumed: 0,
-                        levels_crossed: 0,
-                        total_matched_qty: 0.0,
-                    };
-                }
+    pub fn fill_order_book_with_policy<F>(
+        &mut self,
+        market_order: &mut Order,
+        policy: MarketOrderPolicy,
+        on_fill: &mut F,
+    ) -> FillReport
+    where
+        F: FnMut(Fill),
+    {
+        let amount: Qty = market_order.size;
 
-                // BTreeMap::iter() naturally iterates from lowest to highest price (best asks first)
-                let mut prices_to_remove = Vec::new();
-                let mut fills_total = 0;
-                let mut resting_orders_consumed = 0;
-                let mut levels_crossed = 0;
-                let mut total_matched_qty = 0.0;
-
-                for (&price, limit) in self.asks.iter_mut() {
-                    let stats = limit.fill_order(market_order, on_fill);
-                    if stats.fills_total > 0 {
-                        levels_crossed += 1;
-                    }
-                    fills_total += stats.fills_total;
-                    resting_orders_consumed += stats.resting_orders_consumed;
-                    total_matched_qty += stats.total_matched_qty;
-                    if limit.total_volume() == 0.0 {
-                        prices_to_remove.push(price);
-                    }
-                    if market_order.is_filled() {
-                        break;
-                    }
-                }
+        if policy == MarketOrderPolicy::FillOrKill && self.fill_or_kill_liquidity(market_order, None) < amount {
+            return FillReport {
+                insufficient_liquidity: true,
+                fully_filled: false,
+                filled_qty: 0,
+                remaining_qty: amount,
+                fills_total: 0,
+                resting_orders_consumed: 0,
+                levels_crossed: 0,
+                total_matched_qty: 0,
+                canceled_qty: amount,
+                ..FillReport::default()
+            };
+        }
 
-                // Remove empty price levels
-                for price in prices_to_remove {
-                    self.asks.remove(&price);
-                }
+        let mut report = self.match_against_book(market_order, None, on_fill);
+        // Whatever is left of a market order is canceled, it never rests
+        report.insufficient_liquidity = !market_order.is_filled();
+        report.canceled_qty = report.remaining_qty;
+        report
+    }
 
-                self.ask_capacity -= total_matched_qty;
-                FillReport {
-                    insufficient_liquidity: false,
-                    fully_filled: market_order.is_filled(),
-                    filled_qty: total_matched_qty,
-                    remaining_qty: market_order.size,
-                    fills_total,
-                    resting_orders_consumed,
-                    levels_crossed,
-                    total_matched_qty,
-                }
-            },
+    /// Matches a limit order against the opposite side at prices no worse than `limit_price`.
+    /// Whatever can't be matched is left in `order.size` for the caller to rest.
+    pub fn fill_order_book_up_to_price<F>(&mut self, order: &mut Order, limit_price: Tick, on_fill: &mut F) -> FillReport
+    where
+        F: FnMut(Fill),
+    {
+        self.match_against_book(order, Some(limit_price), on_fill)
+    }
 
-            // Ask order (sell): match against bids (buyers) - need bids available
-            BidOrAsk::Ask => {
-                if self.bid_capacity < amount {
-                    return FillReport {
-                        insufficient_liquidity: true,
-                        fully_filled: false,
-                        filled_qty: 0.0,
-                        remaining_qty: amount,
-                        fills_total: 0,
-                        resting_orders_consumed: 0,
-                        levels_crossed: 0,
-                        total_matched_qty: 0.0,
-                    };
+    /// Places a limit order: matches it up to `price`, then rests or cancels the remainder
+    /// according to its time in force. A fill-or-kill order that can't be filled completely
+    /// within its price doesn't touch the book at all.
+    pub fn place_limit_order<F>(&mut self, price: Tick, order: &mut Order, on_fill: &mut F) -> FillReport
+    where
+        F: FnMut(Fill),
+    {
+        let amount = order.size;
+        if order.time_in_force == TimeInForce::FillOrKill && self.fill_or_kill_liquidity(order, Some(price)) < amount {
+            return FillReport {
+                insufficient_liquidity: true,
+                fully_filled: false,
+                filled_qty: 0,
+                remaining_qty: amount,
+                fills_total: 0,
+                resting_orders_consumed: 0,
+                levels_crossed: 0,
+                total_matched_qty: 0,
+                canceled_qty: amount,
+                ..FillReport::default()
+            };
+        }
+
+        let mut report = self.fill_order_book_up_to_price(order, price, on_fill);
+        if !order.is_filled() {
+            if order.time_in_force.rests() {
+                let owner = self.owner_for(order.user_id());
+                let resting_order =
+                    RestingOrder::with_owner(order.id(), order.size, owner).with_display_qty(order.display_qty);
+                self.add_order_from_price_in_bids_or_asks(price, resting_order, order.bid_or_ask);
+                match order.bid_or_ask {
+                    BidOrAsk::Ask => self.ask_capacity += order.size,
+                    BidOrAsk::Bid => self.bid_capacity += order.size,
                 }
+            } else {
+                report.canceled_qty = report.remaining_qty;
+            }
+        }
+        report
+    }
 
-                // Use reverse iterator: BTreeMap::iter().rev() goes from highest to lowest (best bids first)
-                let mut prices_to_remove = Vec::new();
-                let mut fills_total = 0;
-                let mut resting_orders_consumed = 0;
-                let mut levels_crossed = 0;
-                let mut total_matched_qty = 0.0;
-
-                for (&price, limit) in self.bids.iter_mut().rev() {
-                    let stats = limit.fill_order(market_order, on_fill);
-                    if stats.fills_total > 0 {
-                        levels_crossed += 1;
-                    }
-                    fills_total += stats.fills_total;
-                    resting_orders_consumed += stats.resting_orders_consumed;
-                    total_matched_qty += stats.total_matched_qty;
-                    if limit.total_volume() == 0.0 {
-                        prices_to_remove.push(price);
-                    }
-                    if market_order.is_filled() {
-                        break;
+    /// Volume `order` could trade at prices no worse than `limit_price` (at any price
+    /// when `None`), hidden iceberg reserves included. The order's own resting orders
+    /// never count: under self-trade prevention it either cancels them or stops at them.
+    pub fn fill_or_kill_liquidity(&self, order: &Order, limit_price: Option<Tick>) -> Qty {
+        let owner = self.owners.get(order.user_id()).copied().unwrap_or(0);
+        let prevention = order.self_trade_prevention.unwrap_or_default();
+        let levels: Vec<&Limit> = match order.bid_or_ask {
+            BidOrAsk::Bid => self.asks.range(..=limit_price.unwrap_or(Tick::MAX)).map(|(_, limit)| limit).collect(),
+            BidOrAsk::Ask => self.bids.range(limit_price.unwrap_or(Tick::MIN)..).rev().map(|(_, limit)| limit).collect(),
+        };
+
+        let mut liquidity = 0;
+        for limit in levels {
+            let (qty, stopped) = limit.tradable_qty(owner, prevention);
+            liquidity += qty;
+            if stopped || liquidity >= order.size {
+                break;
+            }
+        }
+        liquidity
+    }
+
+    fn match_against_book<F>(&mut self, order: &mut Order, limit_price: Option<Tick>, on_fill: &mut F) -> FillReport
+    where
+        F: FnMut(Fill),
+    {
+        let side = order.bid_or_ask;
+        let (levels, capacity) = match side {
+            BidOrAsk::Bid => (&mut self.asks, &mut self.ask_capacity),
+            BidOrAsk::Ask => (&mut self.bids, &mut self.bid_capacity),
+        };
+        let order_index = &mut self.order_index;
+        // A user without resting orders here can't trade with themselves
+        let owner = self.owners.get(order.user_id()).copied().unwrap_or(0);
+        let prevention = order.self_trade_prevention.unwrap_or_default();
+
+        let mut fills_total = 0;
+        let mut resting_orders_consumed = 0;
+        let mut levels_crossed = 0;
+        let mut total_matched_qty = 0;
+        let mut self_trade_canceled_qty = 0;
+        let mut self_trade_cancels = Vec::new();
+
+        while !order.is_filled() {
+            // Asks are matched lowest to highest, bids highest to lowest
+            let best_price = match side {
+                BidOrAsk::Bid => levels.keys().next().copied(),
+                BidOrAsk::Ask => levels.keys().next_back().copied(),
+            };
+            let price = match best_price {
+                Some(price) => price,
+                None => break,
+            };
+            let crosses = match (side, limit_price) {
+                (_, None) => true,
+                (BidOrAsk::Bid, Some(limit_price)) => price <= limit_price,
+                (BidOrAsk::Ask, Some(limit_price)) => price >= limit_price,
+            };
+            if !crosses {
+                break;
+            }
+
+            let limit = match levels.get_mut(&price) {
+                Some(limit) => limit,
+                None => break,
+            };
+            let stats = limit.fill_order_preventing_self_trade(
+                order,
+                owner,
+                prevention,
+                &mut |order_id, filled_qty, resting_qty| {
+                    if resting_qty == 0 {
+                        order_index.remove(&order_id);
                     }
-                }
+                    on_fill(Fill { maker_order_id: order_id, price, qty: filled_qty });
+                },
+                &mut |cancel: SelfTradeCancel| self_trade_cancels.push(cancel),
+            );
+            if stats.fills_total > 0 {
+                levels_crossed += 1;
+                self.last_trade_price = Some(price);
+            }
+            fills_total += stats.fills_total;
+            resting_orders_consumed += stats.resting_orders_consumed;
+            total_matched_qty += stats.total_matched_qty;
+            self_trade_canceled_qty += stats.self_trade_canceled_qty;
 
-                // Remove empty price levels
-                for price in prices_to_remove {
-                    self.bids.remove(&price);
-                }
+            // Remove empty price levels
+            if limit.is_empty() {
+                levels.remove(&price);
+            }
+        }
 
-                self.bid_capacity -= total_matched_qty;
-                FillReport {
-                    insufficient_liquidity: false,
-                    fully_filled: market_order.is_filled(),
-                    filled_qty: total_matched_qty,
-                    remaining_qty: market_order.size,
-                    fills_total,
-                    resting_orders_consumed,
-                    levels_crossed,
-                    total_matched_qty,
-                }
-            },
+        for cancel in &self_trade_cancels {
+            if cancel.resting_qty == 0 {
+                order_index.remove(&cancel.order_id);
+            }
+            *capacity -= cancel.canceled_qty;
+        }
+        *capacity -= total_matched_qty;
+        FillReport {
+            insufficient_liquidity: false,
+            fully_filled: order.is_filled() && self_trade_canceled_qty == 0,
+            filled_qty: total_matched_qty,
+            remaining_qty: order.size,
+            fills_total,
+            resting_orders_consumed,
+            levels_crossed,
+            total_matched_qty,
+            canceled_qty: 0,
+            self_trade_canceled_qty,
+            self_trade_cancels,
         }
     }
 
@@ -221,41 +551,112 @@ impl OrderBook {
             BidOrAsk::Ask => &mut self.asks,
         };
 
-        match limit_map.get_mut(&price) {
-            Some(limit) => {
-                limit.add_order(order);
-               
-            }
-            None => {
-                let mut lim: Limit = Limit::new(price);
-                lim.add_order(order);
-                limit_map.insert(price, lim);
-                
-            }
+        let order_id = order.id();
+        let slot = limit_map
+            .entry(price)
+            .or_insert_with(|| Limit::new(price))
+            .add_order(order);
+        if order_id != 0 {
+            self.order_index.insert(order_id, OrderLocation { side: bid_or_ask, price, slot });
         }
     }
     pub fn add_limit_order(&mut self, price: Tick, order: Order) {
         
+        let owner = self.owner_for(order.user_id());
         match order.bid_or_ask {
             BidOrAsk::Ask => {
                 let order_size = order.size();
-                let resting_order = RestingOrder::new(order.id(), order_size);
+                let resting_order =
+                    RestingOrder::with_owner(order.id(), order_size, owner).with_display_qty(order.display_qty);
                 self.add_order_from_price_in_bids_or_asks(price, resting_order, BidOrAsk::Ask);
                 self.ask_capacity += order_size;
             }
             BidOrAsk::Bid => {
                 let order_size = order.size();
-                let resting_order = RestingOrder::new(order.id(), order_size);
+                let resting_order =
+                    RestingOrder::with_owner(order.id(), order_size, owner).with_display_qty(order.display_qty);
                 self.add_order_from_price_in_bids_or_asks(price, resting_order, BidOrAsk::Bid);
                 self.bid_capacity += order_size
             }
     }
-}}
+}
+
+    pub fn contains_order(&self, order_id: OrderId) -> bool {
+        self.order_index.contains_key(&order_id)
+    }
+
+    pub fn order_location(&self, order_id: OrderId) -> Option<OrderLocation> {
+        self.order_index.get(&order_id).copied()
+    }
+
+    pub fn resting_order(&self, order_id: OrderId) -> Option<&RestingOrder> {
+        let location = self.order_index.get(&order_id)?;
+        let limit_map = match location.side {
+            BidOrAsk::Bid => &self.bids,
+            BidOrAsk::Ask => &self.asks,
+        };
+        limit_map.get(&location.price)?.order_at(location.slot)
+    }
+
+    /// Removes a resting order from the book.
+    /// Returns the quantity that was still resting, or `None` if the order is not in the book.
+    pub fn cancel_order(&mut self, order_id: OrderId) -> Option<Qty> {
+        let location = self.order_index.remove(&order_id)?;
+        let limit_map: &mut BTreeMap<Tick, Limit> = match location.side {
+            BidOrAsk::Bid => &mut self.bids,
+            BidOrAsk::Ask => &mut self.asks,
+        };
+
+        let limit = limit_map.get_mut(&location.price)?;
+        let removed = limit.remove_order(location.slot)?;
+        if limit.is_empty() {
+            limit_map.remove(&location.price);
+        }
+
+        match location.side {
+            BidOrAsk::Bid => self.bid_capacity -= removed.total_qty(),
+            BidOrAsk::Ask => self.ask_capacity -= removed.total_qty(),
+        }
+        Some(removed.total_qty())
+    }
+
+    /// Reduces a resting order by `reduce_by` without touching its time priority; an iceberg
+    /// order loses hidden quantity first. Reducing to zero (or more) cancels the order.
+    /// Returns the quantity left resting.
+    pub fn reduce_order(&mut self, order_id: OrderId, reduce_by: Qty) -> Option<Qty> {
+        let location = self.order_index.get(&order_id).copied()?;
+        let resting_qty = self.resting_order(order_id)?.total_qty();
+        if reduce_by >= resting_qty {
+            self.cancel_order(order_id)?;
+            return Some(0);
+        }
+
+        let limit_map: &mut BTreeMap<Tick, Limit> = match location.side {
+            BidOrAsk::Bid => &mut self.bids,
+            BidOrAsk::Ask => &mut self.asks,
+        };
+        let remaining = limit_map
+            .get_mut(&location.price)?
+            .reduce_order(location.slot, reduce_by)?;
+
+        match location.side {
+            BidOrAsk::Bid => self.bid_capacity -= reduce_by,
+            BidOrAsk::Ask => self.ask_capacity -= reduce_by,
+        }
+        Some(remaining)
+    }
+
+    pub fn resting_orders_count(&self) -> usize {
+        self.order_index.len()
+    }
+}
 
 
 #[derive(Debug, Hash, Clone, Copy, Serialize,Deserialize)]
 pub enum BidOrAsk {
+    #[serde(alias = "buy")]
     Bid,
+    #[serde(alias = "sell")]
     Ask  
 }
 
@@ -263,42 +664,76 @@ pub enum BidOrAsk {
 pub struct Order {
     id: OrderId,
     user_id: String,
-    size: f64,
+    size: Qty,
     bid_or_ask: BidOrAsk, 
-    
+    #[serde(default)]
+    time_in_force: TimeInForce,
+    /// Id the client chose for the order, echoed back in its snapshot.
+    #[serde(default)]
+    client_order_id: Option<String>,
+    /// Overrides the market's self-trade prevention when set.
+    #[serde(default)]
+    self_trade_prevention: Option<SelfTradePrevention>,
+    #[serde(default)]
+    post_only: PostOnly,
+    /// Visible peak of an iceberg order; the rest of a resting order stays hidden.
+    #[serde(default)]
+    display_qty: Option<Qty>,
 }
 
 impl Order {
-    pub fn new(size: f64, bid_or_ask: BidOrAsk) -> Order {
+    pub fn new(size: Qty, bid_or_ask: BidOrAsk) -> Order {
         Order {
             id: 0,
             user_id: String::new(),
-            size: size,
-            bid_or_ask: bid_or_ask
+            size,
+            bid_or_ask,
+            time_in_force: TimeInForce::default(),
+            client_order_id: None,
+            self_trade_prevention: None,
+            post_only: PostOnly::Off,
+            display_qty: None,
         }}
-    pub fn new_with_meta(id: OrderId, user_id: String, size: f64, bid_or_ask: BidOrAsk) -> Order {
+    pub fn new_with_meta(id: OrderId, user_id: String, size: Qty, bid_or_ask: BidOrAsk) -> Order {
         Order {
             id,
             user_id,
             size,
             bid_or_ask,
+            time_in_force: TimeInForce::default(),
+            client_order_id: None,
+            self_trade_prevention: None,
+            post_only: PostOnly::Off,
+            display_qty: None,
         }
     }
     pub fn id(&self) -> OrderId { self.id }
     pub fn user_id(&self) -> &str { self.user_id.as_str() }
     pub fn set_id(&mut self, id: OrderId) { self.id = id; }
     pub fn set_user_id(&mut self, user_id: String) { self.user_id = user_id; }
+    pub fn time_in_force(&self) -> TimeInForce { self.time_in_force }
+    pub fn set_time_in_force(&mut self, time_in_force: TimeInForce) { self.time_in_force = time_in_force; }
+    pub fn client_order_id(&self) -> Option<&str> { self.client_order_id.as_deref() }
+    pub fn set_client_order_id(&mut self, client_order_id: Option<String>) { self.client_order_id = client_order_id; }
+    pub fn self_trade_prevention(&self) -> Option<SelfTradePrevention> { self.self_trade_prevention }
+    pub fn set_self_trade_prevention(&mut self, prevention: Option<SelfTradePrevention>) {
+        self.self_trade_prevention = prevention;
+    }
+    pub fn post_only(&self) -> PostOnly { self.post_only }
+    pub fn set_post_only(&mut self, post_only: PostOnly) { self.post_only = post_only; }
+    pub fn display_qty(&self) -> Option<Qty> { self.display_qty }
+    pub fn set_display_qty(&mut self, display_qty: Option<Qty>) { self.display_qty = display_qty; }
     pub fn is_filled(&self) -> bool {
-        self.size == 0.0
+        self.size == 0
         
     }
-    pub fn size(&self) -> f64 {
+    pub fn size(&self) -> Qty {
         self.size}
     pub fn bid_or_ask(&self) -> BidOrAsk {self.bid_or_ask} 
     pub fn get_bid_or_ask(&self) -> String {  match self.bid_or_ask {
-        BidOrAsk::Ask => return "Ask".to_string(),
-        BidOrAsk::Bid => return "Bid".to_string()
-    };
+        BidOrAsk::Ask => "Ask".to_string(),
+        BidOrAsk::Bid => "Bid".to_string()
+    }
     }
     }
     
@@ -307,53 +742,223 @@ impl Order {
 
 
 
-#[derive(Debug,Serialize,Deserialize)]
+#[derive(Debug, Clone)]
+struct OrderNode {
+    order: RestingOrder,
+    prev: Option<SlotId>,
+    next: Option<SlotId>,
+}
+
+/// A price level. Resting orders live in a slab of slots linked into a FIFO list,
+/// so an order can be removed from the middle of the queue by its slot in O(1).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "LimitData")]
 pub struct Limit {
     price: Tick,
-    orders: VecDeque<RestingOrder>,
-    total_volume: f64,
+    slots: Vec<Option<OrderNode>>,
+    free_slots: Vec<SlotId>,
+    head: Option<SlotId>,
+    tail: Option<SlotId>,
+    len: usize,
+    // Visible quantity only; hidden iceberg reserves are in `hidden_volume`
+    total_volume: Qty,
+    hidden_volume: Qty,
+}
+
+// Limits are (de)serialized as a plain queue of orders; the slab layout is internal.
+#[derive(Deserialize)]
+struct LimitData {
+    price: Tick,
+    orders: Vec<RestingOrder>,
+    total_volume: Qty,
+}
+
+impl From<LimitData> for Limit {
+    fn from(data: LimitData) -> Limit {
+        let mut limit = Limit::new(data.price);
+        for order in data.orders {
+            limit.add_order(order);
+        }
+        limit.total_volume = data.total_volume;
+        limit
+    }
+}
 
+impl Serialize for Limit {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Limit", 3)?;
+        state.serialize_field("price", &self.price)?;
+        state.serialize_field("orders", &self.iter().collect::<Vec<_>>())?;
+        state.serialize_field("total_volume", &self.total_volume)?;
+        state.end()
+    }
 }
 
 impl Limit {        
     pub fn new(price: Tick) -> Limit { 
-        let total_volume: f64 = 0.0;
+        let total_volume: Qty = 0;
         Limit{
             
             price,
-            orders: VecDeque::new(),
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+            head: None,
+            tail: None,
+            len: 0,
             total_volume,
+            hidden_volume: 0,
         }}
     
     pub fn price(&self) -> Tick {
         self.price
     }
 
-    pub fn total_volume(&self) -> f64 { self.total_volume}
+    /// Visible quantity at this price.
+    pub fn total_volume(&self) -> Qty { self.total_volume}
+
+    /// Quantity hidden in the reserves of iceberg orders at this price.
+    pub fn hidden_volume(&self) -> Qty { self.hidden_volume }
+
+    /// Visible plus hidden quantity.
+    pub fn total_qty(&self) -> Qty { self.total_volume + self.hidden_volume }
+
+    pub fn len(&self) -> usize { self.len }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Quantity an incoming order of `owner` would trade at this level under `prevention`,
+    /// and whether it would stop here at one of `owner`'s orders. Only the visible part of
+    /// the orders ahead of that one counts: refilled iceberg peaks go behind it.
+    pub fn tradable_qty(&self, owner: OwnerId, prevention: SelfTradePrevention) -> (Qty, bool) {
+        if owner == 0 || prevention == SelfTradePrevention::Allow {
+            return (self.total_qty(), false);
+        }
+        let (mut total, mut visible) = (0, 0);
+        for order in self.iter() {
+            if order.owner() != owner {
+                total += order.total_qty();
+                visible += order.qty();
+            } else if prevention != SelfTradePrevention::CancelOldest {
+                // The other modes cancel or decrement the incoming order here
+                return (visible, true);
+            }
+        }
+        (total, false)
+    }
+
+    pub fn order_at(&self, slot: SlotId) -> Option<&RestingOrder> {
+        self.slots.get(slot)?.as_ref().map(|node| &node.order)
+    }
+
+    /// Orders in time priority (front of the queue first).
+    pub fn iter(&self) -> impl Iterator<Item = &RestingOrder> + '_ {
+        self.iter_slots().map(|(_, order)| order)
+    }
+
+    pub fn iter_slots(&self) -> impl Iterator<Item = (SlotId, &RestingOrder)> + '_ {
+        let mut cursor = self.head;
+        std::iter::from_fn(move || {
+            let slot = cursor?;
+            let node = self.slots[slot].as_ref()?;
+            cursor = node.next;
+            Some((slot, &node.order))
+        })
+    }
 
     pub fn fill_order<F>(&mut self, market_order: &mut Order, on_fill: &mut F) -> FillStats
     where
-        F: FnMut(OrderId, f64),
+        F: FnMut(OrderId, Qty),
+    {
+        self.fill_order_tracked(market_order, &mut |order_id, filled_qty, _resting_qty| {
+            on_fill(order_id, filled_qty)
+        })
+    }
+
+    /// Like `fill_order`, but also reports the quantity left resting on each filled order
+    /// (0 once the order has been consumed and removed from the level).
+    pub fn fill_order_tracked<F>(&mut self, market_order: &mut Order, on_fill: &mut F) -> FillStats
+    where
+        F: FnMut(OrderId, Qty, Qty),
+    {
+        self.fill_order_preventing_self_trade(market_order, 0, SelfTradePrevention::Allow, on_fill, &mut |_| {})
+    }
+
+    /// Like `fill_order_tracked`, but resting orders of `owner` are handled by `prevention`
+    /// instead of traded with. Every resting order it cancels or reduces goes to `on_self_trade`.
+    pub fn fill_order_preventing_self_trade<F, S>(
+        &mut self,
+        market_order: &mut Order,
+        owner: OwnerId,
+        prevention: SelfTradePrevention,
+        on_fill: &mut F,
+        on_self_trade: &mut S,
+    ) -> FillStats
+    where
+        F: FnMut(OrderId, Qty, Qty),
+        S: FnMut(SelfTradeCancel),
     {
         let mut stats = FillStats::default();
-        while let Some(mut limit_order) = self.orders.pop_front() {
-            if market_order.size >= limit_order.qty() {
+        while let Some(head) = self.head {
+            let limit_order = match self.slots[head].as_ref() {
+                Some(node) => node.order,
+                None => break,
+            };
+            if owner != 0 && limit_order.owner() == owner && prevention != SelfTradePrevention::Allow {
+                let cancel_resting = |limit: &mut Limit, canceled_qty: Qty, on_self_trade: &mut S| {
+                    let resting_qty = if canceled_qty >= limit_order.total_qty() {
+                        limit.remove_order(head);
+                        0
+                    } else {
+                        limit.reduce_order(head, canceled_qty).unwrap_or(0)
+                    };
+                    on_self_trade(SelfTradeCancel { order_id: limit_order.id(), canceled_qty, resting_qty });
+                };
+                match prevention {
+                    SelfTradePrevention::CancelNewest => {
+                        stats.self_trade_canceled_qty += market_order.size;
+                        market_order.size = 0;
+                    }
+                    SelfTradePrevention::CancelOldest => {
+                        cancel_resting(self, limit_order.total_qty(), on_self_trade);
+                    }
+                    SelfTradePrevention::CancelBoth => {
+                        cancel_resting(self, limit_order.total_qty(), on_self_trade);
+                        stats.self_trade_canceled_qty += market_order.size;
+                        market_order.size = 0;
+                    }
+                    SelfTradePrevention::DecrementAndCancel => {
+                        let decrement = market_order.size.min(limit_order.total_qty());
+                        cancel_resting(self, decrement, on_self_trade);
+                        stats.self_trade_canceled_qty += decrement;
+                        market_order.size -= decrement;
+                    }
+                    SelfTradePrevention::Allow => unreachable!(),
+                }
+            } else if market_order.size >= limit_order.qty() && limit_order.reserve() > 0 {
+                // The peak is consumed: refill it from the reserve at the back of the queue
+                let filled_qty = limit_order.qty();
+                market_order.size -= filled_qty;
+                let resting_qty = self.refill_order(head).unwrap_or(0);
+                stats.fills_total += 1;
+                stats.total_matched_qty += filled_qty;
+                on_fill(limit_order.id(), filled_qty, resting_qty);
+            } else if market_order.size >= limit_order.qty() {
                 let filled_qty = limit_order.qty();
                 market_order.size -= limit_order.qty();
-                self.total_volume -= limit_order.qty();
+                self.remove_order(head);
                 stats.fills_total += 1;
                 stats.resting_orders_consumed += 1;
                 stats.total_matched_qty += filled_qty;
-                on_fill(limit_order.id(), filled_qty);
+                on_fill(limit_order.id(), filled_qty, 0);
             } else {
                 let filled_qty = market_order.size;
-                limit_order.set_qty(limit_order.qty() - market_order.size);
-                self.total_volume -= market_order.size;
+                let resting_qty = self.fill_visible(head, filled_qty).unwrap_or(0);
                 stats.fills_total += 1;
                 stats.total_matched_qty += filled_qty;
-                on_fill(limit_order.id(), filled_qty);
-                market_order.size = 0.0;
-                self.orders.push_front(limit_order);
+                on_fill(limit_order.id(), filled_qty, resting_qty);
+                market_order.size = 0;
             }
 
             if market_order.is_filled() {
@@ -362,11 +967,96 @@ impl Limit {
         }
         stats
     }
-    pub fn add_order(&mut self, order: RestingOrder) {
+
+    /// Appends an order to the back of the queue and returns the slot it was stored in.
+    pub fn add_order(&mut self, order: RestingOrder) -> SlotId {
         let order_size = order.qty();
-        self.orders.push_back(order);
+        self.hidden_volume += order.reserve();
+        let node = OrderNode { order, prev: self.tail, next: None };
+        let slot = match self.free_slots.pop() {
+            Some(slot) => {
+                self.slots[slot] = Some(node);
+                slot
+            }
+            None => {
+                self.slots.push(Some(node));
+                self.slots.len() - 1
+            }
+        };
+        match self.tail {
+            Some(tail) => {
+                if let Some(tail_node) = self.slots[tail].as_mut() {
+                    tail_node.next = Some(slot);
+                }
+            }
+            None => self.head = Some(slot),
+        }
+        self.tail = Some(slot);
+        self.len += 1;
         self.total_volume += order_size;
-        
+        slot
+    }
+
+    /// Unlinks the order stored in `slot` from the queue.
+    pub fn remove_order(&mut self, slot: SlotId) -> Option<RestingOrder> {
+        let node = self.slots.get_mut(slot)?.take()?;
+        match node.prev {
+            Some(prev) => {
+                if let Some(prev_node) = self.slots[prev].as_mut() {
+                    prev_node.next = node.next;
+                }
+            }
+            None => self.head = node.next,
+        }
+        match node.next {
+            Some(next) => {
+                if let Some(next_node) = self.slots[next].as_mut() {
+                    next_node.prev = node.prev;
+                }
+            }
+            None => self.tail = node.prev,
+        }
+        self.free_slots.push(slot);
+        self.len -= 1;
+        self.total_volume -= node.order.qty();
+        self.hidden_volume -= node.order.reserve();
+        Some(node.order)
+    }
+
+    /// Refills the consumed peak of the iceberg order in `slot` from its reserve and moves
+    /// it to the back of the queue. The order keeps its slot. Returns its total quantity.
+    pub fn refill_order(&mut self, slot: SlotId) -> Option<Qty> {
+        let mut order = self.remove_order(slot)?;
+        let refill = order.peak.min(order.reserve);
+        order.reserve -= refill;
+        order.qty = refill;
+
+        // `remove_order` just freed `slot`, so `add_order` reuses it
+        let reused = self.add_order(order);
+        debug_assert_eq!(reused, slot);
+        Some(order.total_qty())
+    }
+
+    /// Takes a partial fill out of the visible quantity of the order in `slot`.
+    /// Returns the quantity left on the order.
+    fn fill_visible(&mut self, slot: SlotId, filled_qty: Qty) -> Option<Qty> {
+        let node = self.slots.get_mut(slot)?.as_mut()?;
+        node.order.qty -= filled_qty;
+        self.total_volume -= filled_qty;
+        Some(node.order.total_qty())
+    }
+
+    /// Reduces the order in `slot` in place, keeping its queue position. An iceberg order
+    /// gives up hidden quantity before visible. Returns the quantity left on the order.
+    pub fn reduce_order(&mut self, slot: SlotId, reduce_by: Qty) -> Option<Qty> {
+        let node = self.slots.get_mut(slot)?.as_mut()?;
+        let from_reserve = reduce_by.min(node.order.reserve);
+        let from_visible = reduce_by - from_reserve;
+        node.order.reserve -= from_reserve;
+        node.order.qty -= from_visible;
+        self.hidden_volume -= from_reserve;
+        self.total_volume -= from_visible;
+        Some(node.order.total_qty())
     }
 
 }
@@ -0,0 +1,190 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::engine::{EngineState, MatchEngine};
+use super::journal::JournalRecord;
+
+pub type SharedEngine = Arc<Mutex<MatchEngine>>;
+
+/// Followers connected to this engine, by peer address, with the last sequence each acked.
+pub type Followers = Arc<Mutex<BTreeMap<String, u64>>>;
+
+/// Where an engine is in its command sequence and the hash of its state there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateHash {
+    pub sequence: u64,
+    pub state_hash: u64,
+}
+
+impl StateHash {
+    pub fn of(engine: &MatchEngine) -> StateHash {
+        StateHash { sequence: engine.last_sequence(), state_hash: engine.state_hash() }
+    }
+}
+
+/// Replication protocol, one JSON message per line. A connection opens with `Follow`,
+/// to be streamed the engine's commands, or `GetStateHash`, answered once.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Message {
+    /// Follower to leader: stream what comes after `sequence`.
+    Follow { sequence: u64 },
+    /// Leader to follower: the leader's whole state, sent first unless the follower is
+    /// already at the leader's sequence.
+    State(Box<EngineState>),
+    /// Leader to follower: the next command.
+    Record(Box<JournalRecord>),
+    /// Follower to leader: every command up to `sequence` is applied.
+    Ack { sequence: u64 },
+    GetStateHash,
+    StateHash(StateHash),
+}
+
+fn send(stream: &mut TcpStream, message: &Message) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    stream.write_all(&line)
+}
+
+// None once the peer closed the connection
+fn receive(reader: &mut impl BufRead) -> io::Result<Option<Message>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    serde_json::from_str(&line).map(Some).map_err(|err| invalid(format!("bad replication message: {}", err)))
+}
+
+/// Accepts followers and state hash queries on `listener`, each connection on its own thread.
+pub fn serve(listener: TcpListener, engine: SharedEngine, followers: Followers) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let (engine, followers) = (engine.clone(), followers.clone());
+            match stream {
+                Ok(stream) => {
+                    thread::spawn(move || {
+                        if let Err(err) = handle(stream, engine, followers) {
+                            eprintln!("replication connection failed: {}", err);
+                        }
+                    });
+                }
+                Err(err) => eprintln!("replication accept failed: {}", err),
+            }
+        }
+    })
+}
+
+fn handle(mut stream: TcpStream, engine: SharedEngine, followers: Followers) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    match receive(&mut reader)? {
+        Some(Message::GetStateHash) => {
+            let hash = StateHash::of(&engine.lock().unwrap());
+            send(&mut stream, &Message::StateHash(hash))
+        }
+        Some(Message::Follow { sequence }) => stream_to_follower(stream, reader, engine, followers, sequence),
+        Some(message) => Err(invalid(format!("unexpected opening message {:?}", message))),
+        None => Ok(()),
+    }
+}
+
+fn stream_to_follower(
+    mut stream: TcpStream,
+    mut reader: BufReader<TcpStream>,
+    engine: SharedEngine,
+    followers: Followers,
+    sequence: u64,
+) -> io::Result<()> {
+    // Subscribing and copying the state under one lock, so no command falls in between
+    let (state, records) = {
+        let mut engine = engine.lock().unwrap();
+        let records = engine.subscribe();
+        let state = (engine.last_sequence() != sequence).then(|| Box::new(engine.state()));
+        (state, records)
+    };
+    let peer = stream.peer_addr()?.to_string();
+    followers.lock().unwrap().insert(peer.clone(), sequence);
+    thread::spawn(move || {
+        while let Ok(Some(Message::Ack { sequence })) = receive(&mut reader) {
+            followers.lock().unwrap().insert(peer.clone(), sequence);
+        }
+        followers.lock().unwrap().remove(&peer);
+    });
+
+    if let Some(state) = state {
+        send(&mut stream, &Message::State(state))?;
+    }
+    // Ends when the follower goes away and a write fails
+    for record in records {
+        send(&mut stream, &Message::Record(Box::new(record)))?;
+    }
+    Ok(())
+}
+
+/// Follows the leader at `addr`: loads its state if it's elsewhere in the sequence, then
+/// applies and acks every command it streams. Returns when the leader closes the
+/// connection or the engine is promoted; the engine must be following.
+pub fn follow(addr: impl ToSocketAddrs, engine: &SharedEngine) -> io::Result<()> {
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let sequence = engine.lock().unwrap().last_sequence();
+    send(&mut stream, &Message::Follow { sequence })?;
+    while let Some(message) = receive(&mut reader)? {
+        let sequence = {
+            let mut engine = engine.lock().unwrap();
+            if !engine.is_following() {
+                return Ok(());
+            }
+            match message {
+                Message::State(state) => engine.load_state(*state).map_err(|err| io::Error::other(err.to_string()))?,
+                Message::Record(record) => {
+                    if record.sequence != engine.last_sequence() + 1 {
+                        return Err(invalid(format!(
+                            "leader sent command {} after command {}",
+                            record.sequence,
+                            engine.last_sequence()
+                        )));
+                    }
+                    // A command the leader failed fails the same way here
+                    let _ = engine.replicate(&record);
+                }
+                message => return Err(invalid(format!("unexpected message from the leader {:?}", message))),
+            }
+            engine.last_sequence()
+        };
+        send(&mut stream, &Message::Ack { sequence })?;
+    }
+    Ok(())
+}
+
+/// Asks the engine serving replication at `addr` for its state hash.
+pub fn request_state_hash(addr: impl ToSocketAddrs) -> io::Result<StateHash> {
+    let mut stream = TcpStream::connect(addr)?;
+    send(&mut stream, &Message::GetStateHash)?;
+    match receive(&mut BufReader::new(stream))? {
+        Some(Message::StateHash(hash)) => Ok(hash),
+        other => Err(invalid(format!("expected a state hash, got {:?}", other))),
+    }
+}
+
+/// State hashes of the engines at `addrs`, taken once they all report the same sequence,
+/// or the last ones seen when they don't within `timeout`. The engines are in sync when
+/// every hash is the same.
+pub fn check(addrs: &[String], timeout: Duration) -> io::Result<Vec<StateHash>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let hashes = addrs.iter().map(request_state_hash).collect::<io::Result<Vec<StateHash>>>()?;
+        let same_sequence = hashes.windows(2).all(|pair| pair[0].sequence == pair[1].sequence);
+        if same_sequence || Instant::now() >= deadline {
+            return Ok(hashes);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
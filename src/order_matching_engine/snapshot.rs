@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::engine::EngineState;
+
+/// Format version written into every snapshot header.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+// Snapshot files are named after the sequence they were taken at, zero-padded so they
+// sort in sequence order
+const PREFIX: &str = "snapshot-";
+const SUFFIX: &str = ".json";
+
+/// First line of a snapshot file; the serialized `EngineState` follows on the second.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotHeader {
+    pub version: u32,
+    pub sequence: u64,
+    /// FNV-1a hash of the state line, in hex.
+    pub checksum: String,
+}
+
+/// A snapshot that was written.
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotInfo {
+    pub path: PathBuf,
+    pub sequence: u64,
+    pub checksum: String,
+}
+
+/// Outcome of `MatchEngine::restore`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Restored {
+    /// Snapshot the state was loaded from, `None` when there was none.
+    pub snapshot: Option<PathBuf>,
+    /// Sequence of the last command applied once the journal tail was replayed.
+    pub sequence: u64,
+    /// Journal records replayed on top of the snapshot.
+    pub replayed: u64,
+}
+
+/// 64-bit FNV-1a. Not cryptographic: it catches torn and corrupted files, not tampering.
+pub fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Checksum of the serialized state.
+pub fn state_checksum(state: &EngineState) -> u64 {
+    checksum(&serde_json::to_vec(state).expect("engine state serializes"))
+}
+
+pub fn snapshot_path(dir: &Path, sequence: u64) -> PathBuf {
+    dir.join(format!("{}{:020}{}", PREFIX, sequence, SUFFIX))
+}
+
+/// Writes `state` to a new snapshot file in `dir`. The file only appears under its final
+/// name once it is complete and synced, so a crash leaves no half-written snapshot behind.
+pub fn write(dir: &Path, state: &EngineState) -> io::Result<SnapshotInfo> {
+    fs::create_dir_all(dir)?;
+    let body = serde_json::to_vec(state)?;
+    let header = SnapshotHeader {
+        version: SNAPSHOT_VERSION,
+        sequence: state.sequence(),
+        checksum: format!("{:016x}", checksum(&body)),
+    };
+    let path = snapshot_path(dir, state.sequence());
+    let partial = path.with_extension("json.partial");
+    {
+        let mut file = File::create(&partial)?;
+        let mut contents = serde_json::to_vec(&header)?;
+        contents.push(b'\n');
+        contents.extend_from_slice(&body);
+        contents.push(b'\n');
+        file.write_all(&contents)?;
+        file.sync_all()?;
+    }
+    fs::rename(&partial, &path)?;
+    Ok(SnapshotInfo { path, sequence: header.sequence, checksum: header.checksum })
+}
+
+/// Reads the snapshot at `path`, refusing other versions and states that don't match
+/// their checksum.
+pub fn read(path: &Path) -> io::Result<EngineState> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut header_line = String::new();
+    reader.read_line(&mut header_line)?;
+    let header: SnapshotHeader = serde_json::from_str(&header_line).map_err(|err| invalid(format!("bad snapshot header: {}", err)))?;
+    if header.version != SNAPSHOT_VERSION {
+        return Err(invalid(format!("snapshot has version {}, expected {}", header.version, SNAPSHOT_VERSION)));
+    }
+    let mut body = Vec::new();
+    reader.read_to_end(&mut body)?;
+    if body.last() == Some(&b'\n') {
+        body.pop();
+    }
+    let actual = format!("{:016x}", checksum(&body));
+    if actual != header.checksum {
+        return Err(invalid(format!("snapshot checksum is {}, expected {}", actual, header.checksum)));
+    }
+    let state: EngineState = serde_json::from_slice(&body).map_err(|err| invalid(format!("bad snapshot state: {}", err)))?;
+    if state.sequence() != header.sequence {
+        return Err(invalid(format!("snapshot state is at {}, header says {}", state.sequence(), header.sequence)));
+    }
+    Ok(state)
+}
+
+/// The newest snapshot in `dir` that reads back intact, with its path. Snapshots that
+/// don't are passed over for older ones.
+pub fn latest(dir: &Path) -> io::Result<Option<(PathBuf, EngineState)>> {
+    if !dir.exists() {
+        return Ok(None);
+    }
+    let mut snapshots: Vec<(u64, PathBuf)> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let sequence = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(PREFIX)?.strip_suffix(SUFFIX)?.parse::<u64>().ok());
+        if let Some(sequence) = sequence {
+            snapshots.push((sequence, path));
+        }
+    }
+    snapshots.sort_unstable();
+    for (_, path) in snapshots.into_iter().rev() {
+        if let Ok(state) = read(&path) {
+            return Ok(Some((path, state)));
+        }
+    }
+    Ok(None)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
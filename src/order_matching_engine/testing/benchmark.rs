@@ -2,9 +2,10 @@
 // Run with: cargo test --release bench_order_insertion -- --nocapture --test-threads=1
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 pub mod benchmark {
     use crate::order_matching_engine::engine::{EngineStats, MatchEngine, MarketId, TradingPair, PRICE_SCALE};
-    use crate::order_matching_engine::orderbook::{BidOrAsk, Order, Tick};
+    use crate::order_matching_engine::orderbook::{BidOrAsk, Order, Qty, Tick};
     use std::hint::black_box;
     use std::time::Instant;
 
@@ -43,8 +44,9 @@ pub mod benchmark {
         println!("============================================================\n");
     }
 
+    // High enough that a bid ladder one level per iteration stays above zero
     fn base_tick() -> Tick {
-        100 * PRICE_SCALE
+        1_000_000 * PRICE_SCALE
     }
 
     fn spread_tick() -> Tick {
@@ -80,7 +82,7 @@ pub mod benchmark {
             if i % 2 == 0 {
                 let price = bid_ticks[bid_idx];
                 bid_idx += 1;
-                let order = Order::new(1.0, BidOrAsk::Bid);
+                let order = Order::new(1, BidOrAsk::Bid);
                 let result = engine.place_limit_order_raw_by_id_tick(market_id, black_box(price), order);
                 black_box(&result);
                 if result.is_err() {
@@ -89,7 +91,7 @@ pub mod benchmark {
             } else {
                 let price = ask_ticks[ask_idx];
                 ask_idx += 1;
-                let order = Order::new(1.0, BidOrAsk::Ask);
+                let order = Order::new(1, BidOrAsk::Ask);
                 let result = engine.place_limit_order_raw_by_id_tick(market_id, black_box(price), order);
                 black_box(&result);
                 if result.is_err() {
@@ -120,8 +122,8 @@ pub mod benchmark {
         let iterations = env_usize("BENCH_MARKET_ITERS", 10_000);
         let levels = 100usize;
         let orders_per_level = (iterations / levels) + 1;
-        let limit_size = 1.0_f64;
-        let market_size = 1.0_f64;
+        let limit_size: Qty = 1;
+        let market_size: Qty = 1;
         let base = base_tick();
 
         // Add initial liquidity across 100 price levels
@@ -168,11 +170,11 @@ pub mod benchmark {
         let market_id: MarketId = engine.add_new_market(TradingPair::new("btc".to_string(), "usd".to_string()));
 
         let limit_price = base_tick();
-        let small_limit_size = 0.1_f64;
-        let market_order_size = 20.0_f64;
+        let small_limit_size: Qty = 1;
+        let market_order_size: Qty = 200;
         let market_order_target = env_usize("BENCH_DENSE_SWEEP_MARKETS", 10);
         let small_limit_count =
-            (((market_order_target as f64 * market_order_size) / small_limit_size).ceil() as usize) + 1;
+            (market_order_target * market_order_size as usize).div_ceil(small_limit_size as usize) + 1;
 
         // Many small sell orders at the same price level.
         for _ in 0..small_limit_count {
@@ -213,8 +215,8 @@ pub mod benchmark {
 
         println!("\n========== LARGE MARKET SWEEP BENCHMARK RESULTS ==========");
         println!("Small limit orders at one level: {}", small_limit_count);
-        println!("Small limit size: {:.4}", small_limit_size);
-        println!("Market order size: {:.4}", market_order_size);
+        println!("Small limit size: {}", small_limit_size);
+        println!("Market order size: {}", market_order_size);
         println!("Market orders executed: {}", market_order_target);
         println!("Elapsed: {:.6} seconds", elapsed_total);
         println!("Worst latency per market order: {:.6} s", worst_latency);
@@ -238,8 +240,8 @@ pub mod benchmark {
         let market_id: MarketId = engine.add_new_market(TradingPair::new("btc".to_string(), "usd".to_string()));
 
         let limit_price = base_tick();
-        let small_limit_size = 1.0_f64;
-        let heavy_market_size = 50.0_f64;
+        let small_limit_size: Qty = 1;
+        let heavy_market_size: Qty = 50;
         let limits_per_market = (heavy_market_size / small_limit_size) as usize;
 
         let prefill_markets: usize = std::env::var("HEAVY_PREFILL_MARKETS")
@@ -330,9 +332,9 @@ pub mod benchmark {
         // Prefill some liquidity on both sides to avoid early rejections
         for i in 0..1_000usize {
             let price = base + spread + (i as Tick % 10) * PRICE_SCALE;
-            let _ = engine.place_limit_order_raw_by_id_tick(market_id, price, Order::new(1.0, BidOrAsk::Ask));
+            let _ = engine.place_limit_order_raw_by_id_tick(market_id, price, Order::new(2, BidOrAsk::Ask));
             let price = base - spread - (i as Tick % 10) * PRICE_SCALE;
-            let _ = engine.place_limit_order_raw_by_id_tick(market_id, price, Order::new(1.0, BidOrAsk::Bid));
+            let _ = engine.place_limit_order_raw_by_id_tick(market_id, price, Order::new(2, BidOrAsk::Bid));
         }
 
         engine.reset_stats();
@@ -348,10 +350,10 @@ pub mod benchmark {
             let rand_val = i % 10;
             if rand_val < 7 {
                 // 70% - Add limit order
-                if limit_order_count % 2 == 0 {
+                if limit_order_count.is_multiple_of(2) {
                     let price = bid_ticks[bid_idx];
                     bid_idx += 1;
-                    let order = Order::new(1.0, BidOrAsk::Bid);
+                    let order = Order::new(2, BidOrAsk::Bid);
                     let result = engine.place_limit_order_raw_by_id_tick(market_id, price, order);
                     if result.is_err() {
                         errors += 1;
@@ -359,7 +361,7 @@ pub mod benchmark {
                 } else {
                     let price = ask_ticks[ask_idx];
                     ask_idx += 1;
-                    let order = Order::new(1.0, BidOrAsk::Ask);
+                    let order = Order::new(2, BidOrAsk::Ask);
                     let result = engine.place_limit_order_raw_by_id_tick(market_id, price, order);
                     if result.is_err() {
                         errors += 1;
@@ -368,12 +370,12 @@ pub mod benchmark {
                 limit_order_count += 1;
             } else {
                 // 30% - Execute market order
-                let side = if market_order_count % 2 == 0 {
+                let side = if market_order_count.is_multiple_of(2) {
                     BidOrAsk::Bid
                 } else {
                     BidOrAsk::Ask
                 };
-                let mut market_order = Order::new(0.5, side);
+                let mut market_order = Order::new(1, side);
                 let result = engine.fill_market_order_raw_by_id(market_id, &mut market_order);
                 if result.is_err() {
                     errors += 1;
@@ -410,9 +412,9 @@ pub mod benchmark {
         let market_id: MarketId = engine.add_new_market(TradingPair::new("btc".to_string(), "usd".to_string()));
 
         let limit_price = base_tick();
-        let small_limit_size = 1.0_f64;
+        let small_limit_size: Qty = 1;
         let small_limit_count = env_usize("BENCH_TAIL_SWEEP_LIMITS", 100_000);
-        let market_order_size = small_limit_count as f64 * small_limit_size;
+        let market_order_size = small_limit_count as Qty * small_limit_size;
 
         for _ in 0..small_limit_count {
             let order = Order::new(small_limit_size, BidOrAsk::Ask);
@@ -432,8 +434,8 @@ pub mod benchmark {
 
         println!("\n========== TAIL LATENCY SINGLE-LEVEL SWEEP ==========");
         println!("Small limit orders: {}", small_limit_count);
-        println!("Small limit size: {:.6}", small_limit_size);
-        println!("Market order size: {:.6}", market_order_size);
+        println!("Small limit size: {}", small_limit_size);
+        println!("Market order size: {}", market_order_size);
         println!("Elapsed: {:.6} seconds", elapsed);
         println!("Fills total: {}", stats.fills_total);
         println!("Fills/sec: {:.0}", stats.fills_total as f64 / elapsed.max(1e-12));
@@ -441,4 +443,141 @@ pub mod benchmark {
 
         assert!(result.is_ok(), "Tail sweep failed: {:?}", result);
     }
+
+    fn avg_cancel_latency(resting_orders: usize) -> f64 {
+        let mut engine: MatchEngine = MatchEngine::new();
+        let market_id: MarketId = engine.add_new_market(TradingPair::new("btc".to_string(), "usd".to_string()));
+        let limit_price = base_tick();
+
+        // Worst case for a queue scan: everything rests on a single level.
+        let mut order_ids = Vec::with_capacity(resting_orders);
+        for _ in 0..resting_orders {
+            let order = Order::new(1, BidOrAsk::Ask);
+            let response = engine
+                .place_limit_order_with_response_by_id_tick(market_id, limit_price, order)
+                .expect("Failed to prefill cancel benchmark");
+            order_ids.push(response.order.id);
+        }
+
+        // Cancel in a scattered order so cancels hit the middle of the queue.
+        let stride = 7_919usize;
+        let start = Instant::now();
+        let mut errors = 0usize;
+        for i in 0..resting_orders {
+            let order_id = order_ids[(i * stride) % resting_orders];
+            let result = engine.cancel_order(black_box(order_id));
+            black_box(&result);
+            if result.is_err() {
+                errors += 1;
+            }
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+
+        assert!(errors == 0, "Cancel errors: {}", errors);
+        assert_eq!(engine.get_limits_for_market(market_id).unwrap().ask_capacity(), 0);
+        elapsed / resting_orders as f64
+    }
+
+    // Compares wall-clock timings, so it only runs on request on a quiet machine:
+    // cargo test --release bench_cancel_latency -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_cancel_latency_flat_across_book_sizes() {
+        let max_orders = env_usize("BENCH_CANCEL_MAX_ORDERS", 100_000);
+        let sizes = [max_orders / 100, max_orders / 10, max_orders];
+
+        let latencies: Vec<f64> = sizes.iter().map(|&size| avg_cancel_latency(size)).collect();
+
+        println!("\n========== CANCEL LATENCY VS BOOK SIZE ==========");
+        for (size, latency) in sizes.iter().zip(latencies.iter()) {
+            println!("Resting orders: {:>10} | avg cancel latency: {:.3} µs", size, latency * 1e6);
+        }
+        println!("=================================================\n");
+
+        // A queue scan would grow ~100x between the smallest and largest book.
+        let smallest = latencies[0].max(1e-9);
+        let largest = latencies[latencies.len() - 1];
+        assert!(
+            largest / smallest < 10.0,
+            "Cancel latency grew {:.1}x with a {}x larger book",
+            largest / smallest,
+            sizes[sizes.len() - 1] / sizes[0].max(1)
+        );
+    }
+
+    #[test]
+    fn bench_cancel_heavy_workload() {
+        let mut engine: MatchEngine = MatchEngine::new();
+        let market_id: MarketId = engine.add_new_market(TradingPair::new("btc".to_string(), "usd".to_string()));
+
+        let resting_orders = env_usize("BENCH_CANCEL_HEAVY_RESTING", 100_000);
+        let iterations = env_usize("BENCH_CANCEL_HEAVY_ITERS", 200_000);
+        let base = base_tick();
+        let spread = spread_tick();
+
+        // Keep a deep book on both sides; every command cancels an old order and replaces it.
+        let mut live_ids = Vec::with_capacity(resting_orders);
+        for i in 0..resting_orders {
+            let (side, price) = if i % 2 == 0 {
+                (BidOrAsk::Bid, base - spread - (i as Tick % 50) * PRICE_SCALE)
+            } else {
+                (BidOrAsk::Ask, base + spread + (i as Tick % 50) * PRICE_SCALE)
+            };
+            let response = engine
+                .place_limit_order_with_response_by_id_tick(market_id, price, Order::new(1, side))
+                .expect("Failed to prefill cancel-heavy benchmark");
+            live_ids.push(response.order.id);
+        }
+
+        engine.reset_stats();
+
+        let start = Instant::now();
+        let mut cancel_count = 0usize;
+        let mut limit_order_count = 0usize;
+        let mut errors = 0usize;
+        for i in 0..iterations {
+            let victim = (i * 7_919) % resting_orders;
+            if engine.cancel_order(live_ids[victim]).is_err() {
+                errors += 1;
+            }
+            cancel_count += 1;
+
+            let (side, price) = if victim.is_multiple_of(2) {
+                (BidOrAsk::Bid, base - spread - (i as Tick % 50) * PRICE_SCALE)
+            } else {
+                (BidOrAsk::Ask, base + spread + (i as Tick % 50) * PRICE_SCALE)
+            };
+            match engine.place_limit_order_with_response_by_id_tick(market_id, price, Order::new(1, side)) {
+                Ok(response) => live_ids[victim] = response.order.id,
+                Err(_) => errors += 1,
+            }
+            limit_order_count += 1;
+        }
+
+        let elapsed = start.elapsed().as_secs_f64();
+        let total_commands = cancel_count + limit_order_count;
+        let stats = engine.stats();
+
+        println!("\n========== CANCEL-HEAVY WORKLOAD BENCHMARK RESULTS ==========");
+        println!("Resting orders kept in book: {}", resting_orders);
+        println!("  - Cancels: {}", cancel_count);
+        println!("  - Limit orders: {}", limit_order_count);
+        println!("Cancels/sec: {:.0}", cancel_count as f64 / elapsed.max(1e-12));
+        println!("Elapsed: {:.6} seconds", elapsed);
+        println!("=============================================================\n");
+
+        print_throughput(
+            "CANCEL-HEAVY WORKLOAD THROUGHPUT",
+            elapsed,
+            total_commands,
+            0,
+            &stats,
+        );
+
+        assert!(errors == 0, "Cancel-heavy workload errors: {}", errors);
+        assert_eq!(
+            engine.get_limits_for_market(market_id).unwrap().resting_orders_count(),
+            resting_orders
+        );
+    }
 }
@@ -2,9 +2,19 @@
 // Tests the scenario that was previously failing
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod correctness_tests {
-    use crate::order_matching_engine::orderbook::{Order, BidOrAsk};
-    use crate::order_matching_engine::engine::{TradingPair, MatchEngine, price_to_tick};
+    use crate::order_matching_engine::orderbook::{Order, BidOrAsk, MarketOrderPolicy, PostOnly, Qty, SelfTradePrevention, TimeInForce};
+    use crate::order_matching_engine::market_spec::{FeeSchedule, FeeTier, MarketSpec, OrderRejection};
+    use crate::order_matching_engine::error::EngineError;
+    use crate::order_matching_engine::engine::{TradingPair, MatchEngine, NewOrderRequest, OrderStatus, OrderType, Liquidity, price_to_tick, DEFAULT_LOT_SIZE, FEE_VOLUME_WINDOW};
+    use crate::order_matching_engine::accounts::HOUSE_ACCOUNT;
+    use crate::order_matching_engine::clock::{ManualClock, MILLIS_PER_DAY};
+    use crate::order_matching_engine::ledger::{EntryKind, Ledger, LedgerAccount, Posting};
+    use crate::order_matching_engine::trigger_book::TrailingOffset;
+    use crate::order_matching_engine::journal::{Command, FsyncPolicy, Journal, JournalRecord};
+    use crate::order_matching_engine::snapshot;
+    use crate::order_matching_engine::replication::{self, Followers, StateHash};
     use rust_decimal::Decimal;
     use rust_decimal_macros::dec;
 
@@ -26,26 +36,26 @@ mod correctness_tests {
         engine.add_new_market(btc_usd.clone());
 
         // Add asks in non-sorted order
-        let ask_11_3 = Order::new(40.0, BidOrAsk::Ask);
+        let ask_11_3 = Order::new(40, BidOrAsk::Ask);
         let _ = engine.place_limit_order(&btc_usd, dec!(11.3), ask_11_3);
 
-        let ask_12_0 = Order::new(50.0, BidOrAsk::Ask);
+        let ask_12_0 = Order::new(50, BidOrAsk::Ask);
         let _ = engine.place_limit_order(&btc_usd, dec!(12.0), ask_12_0);
 
-        let ask_11_0 = Order::new(1.0, BidOrAsk::Ask);
+        let ask_11_0 = Order::new(1, BidOrAsk::Ask);
         let _ = engine.place_limit_order(&btc_usd, dec!(11.0), ask_11_0);
 
         // Add bid
-        let bid_10_0 = Order::new(100.0, BidOrAsk::Bid);
+        let bid_10_0 = Order::new(100, BidOrAsk::Bid);
         let _ = engine.place_limit_order(&btc_usd, dec!(10.0), bid_10_0);
 
         // Execute market buy for 5
-        let mut market_buy = Order::new(5.0, BidOrAsk::Bid);
+        let mut market_buy = Order::new(5, BidOrAsk::Bid);
         let _ = engine.fill_market_order(&btc_usd, &mut market_buy);
 
         // Verify the market order was fully filled
         assert!(market_buy.is_filled(), "Market order should be fully filled");
-        assert_eq!(market_buy.size(), 0.0, "Market order size should be 0 after filling");
+        assert_eq!(market_buy.size(), 0, "Market order size should be 0 after filling");
 
         // Get the orderbook to check remaining orders
         let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
@@ -63,11 +73,11 @@ mod correctness_tests {
         for limit in ask_limits {
             if limit.price() == price_to_tick(dec!(11.3)) {
                 ask_11_3_found = true;
-                assert_eq!(limit.total_volume(), 36.0, "11.3 ask should have 36.0 remaining (40 - 4)");
+                assert_eq!(limit.total_volume(), 36, "11.3 ask should have 36.0 remaining (40 - 4)");
             }
             if limit.price() == price_to_tick(dec!(12.0)) {
                 ask_12_0_found = true;
-                assert_eq!(limit.total_volume(), 50.0, "12.0 ask should have 50.0 remaining");
+                assert_eq!(limit.total_volume(), 50, "12.0 ask should have 50.0 remaining");
             }
         }
 
@@ -78,7 +88,7 @@ mod correctness_tests {
         let bid_limits = orderbook.bid_limits();
         assert_eq!(bid_limits.len(), 1, "Should have 1 bid level");
         assert_eq!(bid_limits[0].price(), price_to_tick(dec!(10.0)), "Bid should be at 10.0");
-        assert_eq!(bid_limits[0].total_volume(), 100.0, "Bid should still have 100.0");
+        assert_eq!(bid_limits[0].total_volume(), 100, "Bid should still have 100.0");
     }
 
     #[test]
@@ -92,7 +102,7 @@ mod correctness_tests {
         let prices = vec![dec!(105.0), dec!(100.0), dec!(102.0), dec!(101.0), dec!(104.0)];
 
         for price in prices {
-            let order = Order::new(10.0, BidOrAsk::Ask);
+            let order = Order::new(10, BidOrAsk::Ask);
             let _ = engine.place_limit_order(&btc_usd, price, order);
         }
 
@@ -119,7 +129,7 @@ mod correctness_tests {
         let prices = vec![dec!(95.0), dec!(100.0), dec!(98.0), dec!(99.0), dec!(96.0)];
 
         for price in prices {
-            let order = Order::new(10.0, BidOrAsk::Bid);
+            let order = Order::new(10, BidOrAsk::Bid);
             let _ = engine.place_limit_order(&btc_usd, price, order);
         }
 
@@ -144,7 +154,7 @@ mod correctness_tests {
 
         // Add asks: 100@10, 100@11, 100@12, 100@13
         for price in 10..=13 {
-            let order = Order::new(100.0, BidOrAsk::Ask);
+            let order = Order::new(100, BidOrAsk::Ask);
             let _ = engine.place_limit_order(&btc_usd, Decimal::from(price), order);
         }
 
@@ -152,7 +162,7 @@ mod correctness_tests {
         // 100 @ 10
         // 100 @ 11
         // 50 @ 12
-        let mut market_buy = Order::new(250.0, BidOrAsk::Bid);
+        let mut market_buy = Order::new(250, BidOrAsk::Bid);
         let _ = engine.fill_market_order(&btc_usd, &mut market_buy);
 
         assert!(market_buy.is_filled(), "Market order should be fully filled");
@@ -169,11 +179,11 @@ mod correctness_tests {
         for ask in asks {
             if ask.price() == price_to_tick(Decimal::from(12)) {
                 ask_12_found = true;
-                assert_eq!(ask.total_volume(), 50.0, "Ask at 12 should have 50.0 left");
+                assert_eq!(ask.total_volume(), 50, "Ask at 12 should have 50.0 left");
             }
             if ask.price() == price_to_tick(Decimal::from(13)) {
                 ask_13_found = true;
-                assert_eq!(ask.total_volume(), 100.0, "Ask at 13 should have 100.0 left");
+                assert_eq!(ask.total_volume(), 100, "Ask at 13 should have 100.0 left");
             }
         }
 
@@ -188,11 +198,11 @@ mod correctness_tests {
         engine.add_new_market(btc_usd.clone());
 
         // Add only 100 worth of asks
-        let order = Order::new(100.0, BidOrAsk::Ask);
+        let order = Order::new(100, BidOrAsk::Ask);
         let _ = engine.place_limit_order(&btc_usd, dec!(10.0), order);
 
         // Try to buy 200
-        let mut market_buy = Order::new(200.0, BidOrAsk::Bid);
+        let mut market_buy = Order::new(200, BidOrAsk::Bid);
         let result = engine.fill_market_order(&btc_usd, &mut market_buy);
 
         match result {
@@ -200,10 +210,1440 @@ mod correctness_tests {
                 // Should still return an error message about insufficient liquidity
                 assert!(msg.contains("Not enough"), "Should mention insufficient liquidity");
             }
-            Err(msg) => {
-                assert!(msg.contains("Not enough"), "Should mention insufficient liquidity");
+            Err(err) => {
+                assert!(err.to_string().contains("Not enough"), "Should mention insufficient liquidity");
             }
         }
     }
-}
 
+    #[test]
+    fn test_marketable_limit_order_matches_then_rests() {
+        // A buy limit above the best ask takes liquidity up to its price and rests the rest:
+        // ASKS: 1.0 @ 11.0, 40.0 @ 11.3, 50.0 @ 12.0
+        // Limit BUY 50 @ 11.3 fills 1.0 @ 11.0 and 40.0 @ 11.3, then rests 9.0 @ 11.3
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        engine.add_new_market(btc_usd.clone());
+
+        let ask_11_0 = engine.place_limit_order_with_response(&btc_usd, dec!(11.0), Order::new(1, BidOrAsk::Ask)).unwrap();
+        let ask_11_3 = engine.place_limit_order_with_response(&btc_usd, dec!(11.3), Order::new(40, BidOrAsk::Ask)).unwrap();
+        let _ = engine.place_limit_order(&btc_usd, dec!(12.0), Order::new(50, BidOrAsk::Ask));
+
+        let response = engine
+            .place_limit_order_with_response(&btc_usd, dec!(11.3), Order::new(50, BidOrAsk::Bid))
+            .expect("Crossing limit orders should be accepted");
+
+        assert!(matches!(response.order.status, OrderStatus::PartiallyFilled));
+        assert_eq!(response.order.filled_size, 41);
+        assert_eq!(response.order.remaining_size, 9);
+        assert!(matches!(engine.get_order(ask_11_0.order.id).unwrap().status, OrderStatus::Filled));
+        assert!(matches!(engine.get_order(ask_11_3.order.id).unwrap().status, OrderStatus::Filled));
+
+        let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
+        let asks = orderbook.ask_limits();
+        assert_eq!(asks.len(), 1, "Limit order must not trade through its price");
+        assert_eq!(asks[0].price(), price_to_tick(dec!(12.0)));
+        assert_eq!(orderbook.ask_capacity(), 50);
+
+        let bids = orderbook.bid_limits();
+        assert_eq!(bids.len(), 1);
+        assert_eq!(bids[0].price(), price_to_tick(dec!(11.3)));
+        assert_eq!(bids[0].total_volume(), 9);
+        assert_eq!(engine.stats().fills_total, 2);
+    }
+
+    #[test]
+    fn test_marketable_limit_order_fully_filled_does_not_rest() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        engine.add_new_market(btc_usd.clone());
+
+        let bid = engine.place_limit_order_with_response(&btc_usd, dec!(10.0), Order::new(100, BidOrAsk::Bid)).unwrap();
+
+        // Sell limit below the best bid executes at the resting bid's price
+        let response = engine
+            .place_limit_order_with_response(&btc_usd, dec!(9.5), Order::new(30, BidOrAsk::Ask))
+            .unwrap();
+        assert!(matches!(response.order.status, OrderStatus::Filled));
+        assert_eq!(response.order.remaining_size, 0);
+
+        let maker = engine.get_order(bid.order.id).unwrap();
+        assert!(matches!(maker.status, OrderStatus::PartiallyFilled));
+        assert_eq!(maker.remaining_size, 70);
+
+        let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
+        assert!(orderbook.ask_limits().is_empty(), "Fully filled limit order must not rest");
+        assert_eq!(orderbook.bid_capacity(), 70);
+    }
+
+    #[test]
+    fn test_market_order_immediate_or_cancel_fills_available_liquidity() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        engine.add_new_market(btc_usd.clone());
+
+        let _ = engine.place_limit_order(&btc_usd, dec!(10.0), Order::new(60, BidOrAsk::Ask));
+        let _ = engine.place_limit_order(&btc_usd, dec!(11.0), Order::new(40, BidOrAsk::Ask));
+
+        // Default policy is immediate-or-cancel: take the whole book, cancel the rest
+        let mut market_buy = Order::new(150, BidOrAsk::Bid);
+        let response = engine.fill_market_order_with_response(&btc_usd, &mut market_buy).unwrap();
+
+        assert!(matches!(response.order.status, OrderStatus::Canceled));
+        assert_eq!(response.order.filled_size, 100);
+        assert_eq!(response.order.canceled_size, 50);
+        assert_eq!(response.order.remaining_size, 0);
+        assert!(response.message.contains("Not enough"));
+
+        let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
+        assert_eq!(orderbook.ask_capacity(), 0);
+        assert!(orderbook.bid_limits().is_empty(), "Market order remainder must never rest");
+    }
+
+    #[test]
+    fn test_market_order_fill_or_kill_is_all_or_nothing() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        engine.add_new_market(btc_usd.clone());
+
+        let _ = engine.place_limit_order(&btc_usd, dec!(10.0), Order::new(100, BidOrAsk::Bid));
+
+        let mut market_sell = Order::new(150, BidOrAsk::Ask);
+        let response = engine
+            .fill_market_order_with_policy(&btc_usd, &mut market_sell, MarketOrderPolicy::FillOrKill)
+            .unwrap();
+        assert!(matches!(response.order.status, OrderStatus::Rejected));
+        assert_eq!(response.order.filled_size, 0);
+        assert_eq!(response.order.canceled_size, 150);
+        assert_eq!(engine.get_limits_for_a_pair(&btc_usd).unwrap().bid_capacity(), 100);
+
+        // The engine-wide default can be switched to fill-or-kill too
+        engine.set_market_order_policy(MarketOrderPolicy::FillOrKill);
+        let mut market_sell = Order::new(150, BidOrAsk::Ask);
+        let response = engine.fill_market_order_with_response(&btc_usd, &mut market_sell).unwrap();
+        assert!(matches!(response.order.status, OrderStatus::Rejected));
+
+        let mut market_sell = Order::new(100, BidOrAsk::Ask);
+        let response = engine.fill_market_order_with_response(&btc_usd, &mut market_sell).unwrap();
+        assert!(matches!(response.order.status, OrderStatus::Filled));
+        assert_eq!(response.order.canceled_size, 0);
+    }
+
+    fn order_for(user_id: &str, size: Qty, side: BidOrAsk) -> Order {
+        let mut order = Order::new(size, side);
+        order.set_user_id(user_id.to_string());
+        order
+    }
+
+    #[test]
+    fn test_market_order_emits_trades_at_maker_prices() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        engine.add_new_market(btc_usd.clone());
+
+        let first = engine
+            .place_limit_order_with_response(&btc_usd, dec!(11.0), order_for("alice", 1, BidOrAsk::Ask))
+            .unwrap();
+        let second = engine
+            .place_limit_order_with_response(&btc_usd, dec!(11.3), order_for("bob", 40, BidOrAsk::Ask))
+            .unwrap();
+        assert!(first.trades.is_empty() && second.trades.is_empty());
+
+        let mut market_buy = order_for("carol", 5, BidOrAsk::Bid);
+        let response = engine.fill_market_order_with_response(&btc_usd, &mut market_buy).unwrap();
+
+        assert_eq!(response.trades.len(), 2);
+        let (a, b) = (&response.trades[0], &response.trades[1]);
+        assert_eq!((a.price, a.qty), (price_to_tick(dec!(11.0)), 1));
+        assert_eq!((b.price, b.qty), (price_to_tick(dec!(11.3)), 4));
+        assert_eq!(a.maker_order_id, first.order.id);
+        assert_eq!(b.maker_order_id, second.order.id);
+        assert_eq!((a.maker_user_id.as_str(), b.maker_user_id.as_str()), ("alice", "bob"));
+        for trade in &response.trades {
+            assert_eq!(trade.taker_order_id, response.order.id);
+            assert_eq!(trade.taker_user_id, "carol");
+            assert!(matches!(trade.aggressor_side, BidOrAsk::Bid));
+        }
+        assert_eq!((a.sequence, b.sequence), (1, 2));
+        assert!(a.trade_id < b.trade_id);
+
+        let history = engine.get_trades_for_pair(&btc_usd).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].trade_id, b.trade_id);
+    }
+
+    #[test]
+    fn test_crossing_limit_order_emits_trades_with_per_market_sequence() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        let btc_eth = TradingPair::new("btc".to_string(), "eth".to_string());
+        engine.add_new_market(btc_usd.clone());
+        engine.add_new_market(btc_eth.clone());
+
+        let _ = engine.place_limit_order(&btc_usd, dec!(10.0), order_for("alice", 10, BidOrAsk::Bid));
+        let _ = engine.place_limit_order(&btc_eth, dec!(20.0), order_for("alice", 10, BidOrAsk::Bid));
+
+        let usd_sell = engine
+            .place_limit_order_with_response(&btc_usd, dec!(9.0), order_for("bob", 4, BidOrAsk::Ask))
+            .unwrap();
+        let eth_sell = engine
+            .place_limit_order_with_response(&btc_eth, dec!(20.0), order_for("bob", 3, BidOrAsk::Ask))
+            .unwrap();
+        let usd_sell_again = engine
+            .place_limit_order_with_response(&btc_usd, dec!(10.0), order_for("bob", 2, BidOrAsk::Ask))
+            .unwrap();
+
+        // Crossing sells execute at the resting bid's price, not their own limit
+        assert_eq!(usd_sell.trades.len(), 1);
+        assert_eq!(usd_sell.trades[0].price, price_to_tick(dec!(10.0)));
+        assert!(matches!(usd_sell.trades[0].aggressor_side, BidOrAsk::Ask));
+        assert_eq!(usd_sell.trades[0].taker_order_id, usd_sell.order.id);
+
+        // Sequences count per market, trade ids across the whole engine
+        assert_eq!(usd_sell.trades[0].sequence, 1);
+        assert_eq!(eth_sell.trades[0].sequence, 1);
+        assert_eq!(usd_sell_again.trades[0].sequence, 2);
+        assert!(eth_sell.trades[0].trade_id > usd_sell.trades[0].trade_id);
+        assert!(usd_sell_again.trades[0].trade_id > eth_sell.trades[0].trade_id);
+
+        assert_eq!(engine.get_trades_for_pair(&btc_usd).unwrap().len(), 2);
+        assert_eq!(engine.get_trades_for_pair(&btc_eth).unwrap().len(), 1);
+        let unknown = TradingPair::new("eth".to_string(), "usd".to_string());
+        assert!(engine.get_trades_for_pair(&unknown).is_none());
+    }
+
+    #[test]
+    fn test_many_fractional_fills_conserve_quantity_exactly() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        engine.add_new_market_with_lot_size(btc_usd.clone(), dec!(0.001)).unwrap();
+
+        // 0.1 + 0.2 style sizes that never add up exactly as f64
+        let mut placed = 0;
+        let mut makers = Vec::new();
+        for i in 0..200u64 {
+            let size = dec!(0.1) + Decimal::new(i as i64 % 7, 3);
+            let lots = engine.size_to_lots(&btc_usd, size).unwrap();
+            let price = dec!(100) + Decimal::new(i as i64 % 5, 1);
+            let response = engine
+                .place_limit_order_with_response(&btc_usd, price, Order::new(lots, BidOrAsk::Ask))
+                .unwrap();
+            makers.push(response.order.id);
+            placed += lots;
+        }
+
+        let taker_lots = engine.size_to_lots(&btc_usd, dec!(0.007)).unwrap();
+        let mut traded = 0;
+        for _ in 0..1_000 {
+            let mut market_buy = Order::new(taker_lots, BidOrAsk::Bid);
+            let response = engine.fill_market_order_with_response(&btc_usd, &mut market_buy).unwrap();
+            traded += response.trades.iter().map(|trade| trade.qty).sum::<Qty>();
+        }
+
+        let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
+        let level_volume: Qty = orderbook.ask_limits().iter().map(|limit| limit.total_volume()).sum();
+        let queued_volume: Qty = orderbook
+            .ask_limits()
+            .iter()
+            .flat_map(|limit| limit.iter())
+            .map(|order| order.qty())
+            .sum();
+        assert_eq!(traded, taker_lots * 1_000);
+        assert_eq!(orderbook.ask_capacity() + traded, placed);
+        assert_eq!(level_volume, orderbook.ask_capacity());
+        assert_eq!(queued_volume, orderbook.ask_capacity());
+        assert_eq!(engine.stats().total_matched_qty, traded);
+        for id in &makers {
+            let maker = engine.get_order(*id).unwrap();
+            assert_eq!(maker.filled_size + maker.remaining_size, maker.original_size);
+        }
+
+        // Sweeping the rest leaves nothing behind, not even dust
+        let mut sweep = Order::new(placed, BidOrAsk::Bid);
+        let response = engine.fill_market_order_with_response(&btc_usd, &mut sweep).unwrap();
+        assert_eq!(response.order.filled_size, placed - traded);
+        let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
+        assert_eq!(orderbook.ask_capacity(), 0);
+        assert!(orderbook.ask_limits().is_empty());
+        assert!(makers.iter().all(|id| matches!(engine.get_order(*id).unwrap().status, OrderStatus::Filled)));
+    }
+
+    #[test]
+    fn test_sizes_are_parsed_into_whole_lots() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        engine.add_new_market_with_lot_size(btc_usd.clone(), dec!(0.001)).unwrap();
+
+        assert_eq!(engine.size_to_lots(&btc_usd, dec!(1.25)), Ok(1_250));
+        assert_eq!(engine.size_to_lots(&btc_usd, dec!(0.001)), Ok(1));
+        assert!(engine.size_to_lots(&btc_usd, dec!(0.0015)).is_err(), "sizes off the lot grid are refused");
+        assert!(engine.size_to_lots(&btc_usd, dec!(-1)).is_err());
+        assert_eq!(engine.lots_to_size(&btc_usd, 1_250), Some(dec!(1.25)));
+
+        // Markets keep their own lot size; the default matches the price precision
+        let btc_eth = TradingPair::new("btc".to_string(), "eth".to_string());
+        let market_id = engine.add_new_market(btc_eth.clone());
+        assert_eq!(engine.lot_size(market_id), Some(DEFAULT_LOT_SIZE));
+        assert_eq!(engine.size_to_lots(&btc_eth, dec!(0.0015)), Ok(15));
+        assert!(engine.add_new_market_with_lot_size(TradingPair::new("eth".to_string(), "usd".to_string()), dec!(0)).is_err());
+
+        let response = engine
+            .place_limit_order_with_response(&btc_usd, dec!(10), Order::new(1_250, BidOrAsk::Ask))
+            .unwrap();
+        assert!(response.message.contains("size 1.250"), "messages show sizes, not lots: {}", response.message);
+    }
+
+    #[test]
+    fn test_orders_are_validated_against_the_market_spec() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        let spec = MarketSpec {
+            tick_size: dec!(0.5),
+            lot_size: dec!(0.01),
+            min_qty: dec!(0.1),
+            max_qty: Some(dec!(100)),
+            min_notional: dec!(10),
+            price_precision: 1,
+            ..MarketSpec::default()
+        };
+        let market_id = engine.add_market_with_spec(btc_usd.clone(), spec.clone()).unwrap();
+        assert_eq!(engine.market_spec_for_pair(&btc_usd), Some(&spec));
+
+        let tick = |price| price_to_tick(price);
+        assert_eq!(engine.validate_order(market_id, Some(tick(dec!(100.5))), 10), Ok(()));
+        assert_eq!(engine.validate_order(market_id, Some(tick(dec!(100))), 0), Err(OrderRejection::ZeroSize));
+        assert_eq!(
+            engine.validate_order(market_id, Some(tick(dec!(100.2))), 10),
+            Err(OrderRejection::PriceNotOnTick { price: dec!(100.2), tick_size: dec!(0.5) })
+        );
+        assert_eq!(
+            engine.validate_order(market_id, Some(tick(dec!(100))), 9),
+            Err(OrderRejection::SizeBelowMinimum { size: dec!(0.09), min_qty: dec!(0.1) })
+        );
+        assert_eq!(
+            engine.validate_order(market_id, None, 10_001),
+            Err(OrderRejection::SizeAboveMaximum { size: dec!(100.01), max_qty: dec!(100) })
+        );
+        assert_eq!(
+            engine.validate_order(market_id, Some(tick(dec!(50))), 10),
+            Err(OrderRejection::NotionalBelowMinimum { notional: dec!(5), min_notional: dec!(10) })
+        );
+        // Market orders have no price to check a notional against
+        assert_eq!(engine.validate_order(market_id, None, 10), Ok(()));
+        // Zero and negative prices are on every tick but can't rest in the book
+        assert_eq!(
+            engine.validate_order(market_id, Some(0), 10),
+            Err(OrderRejection::PriceNotPositive { price: dec!(0) })
+        );
+        let err = engine.place_limit_order(&btc_usd, dec!(-100), Order::new(10, BidOrAsk::Bid)).unwrap_err();
+        assert_eq!(err, EngineError::Rejected(OrderRejection::PriceNotPositive { price: dec!(-100) }));
+
+        // Rejected orders never reach the book or get an id
+        let err = engine.place_limit_order(&btc_usd, dec!(100.2), Order::new(10, BidOrAsk::Ask)).unwrap_err();
+        assert_eq!(err, EngineError::Rejected(OrderRejection::PriceNotOnTick { price: dec!(100.2), tick_size: dec!(0.5) }));
+        // Prices finer than the engine can represent are refused, not rounded onto a tick
+        let err = engine.place_limit_order(&btc_usd, dec!(100.00001), Order::new(10, BidOrAsk::Ask)).unwrap_err();
+        assert_eq!(err.code(), "price_not_on_tick");
+        let mut market_buy = Order::new(10_001, BidOrAsk::Bid);
+        assert!(engine.fill_market_order_with_response(&btc_usd, &mut market_buy).is_err());
+        assert_eq!(market_buy.id(), 0);
+        assert!(engine.get_limits_for_a_pair(&btc_usd).unwrap().ask_limits().is_empty());
+
+        let response = engine
+            .place_limit_order_with_response(&btc_usd, dec!(100.5), Order::new(10, BidOrAsk::Ask))
+            .unwrap();
+        assert!(response.message.contains("on price 100.5"), "{}", response.message);
+    }
+
+    #[test]
+    fn test_invalid_market_specs_are_refused() {
+        let mut engine = MatchEngine::new();
+        let pair = |base: &str| TradingPair::new(base.to_string(), "usd".to_string());
+
+        let finer_than_engine = MarketSpec { tick_size: dec!(0.00001), ..MarketSpec::default() };
+        assert!(engine.add_market_with_spec(pair("btc"), finer_than_engine).is_err());
+        let no_lot = MarketSpec { lot_size: dec!(0), ..MarketSpec::default() };
+        assert!(engine.add_market_with_spec(pair("eth"), no_lot).is_err());
+        let inverted = MarketSpec { min_qty: dec!(2), max_qty: Some(dec!(1)), ..MarketSpec::default() };
+        assert!(engine.add_market_with_spec(pair("sol"), inverted).is_err());
+        assert!(engine.get_orderbooks().is_empty());
+
+        // add_new_market registers the default spec
+        let market_id = engine.add_new_market(pair("btc"));
+        assert_eq!(engine.market_spec(market_id), Some(&MarketSpec::default()));
+    }
+
+    #[test]
+    fn test_errors_are_typed_with_stable_codes() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        let market_id = engine.add_new_market(btc_usd.clone());
+
+        let unknown = TradingPair::new("doge".to_string(), "usd".to_string());
+        let err = engine.place_limit_order(&unknown, dec!(1), Order::new(1, BidOrAsk::Bid)).unwrap_err();
+        assert_eq!(err, EngineError::UnknownMarket(unknown.clone()));
+        assert_eq!(err.code(), "unknown_market");
+        assert_eq!(engine.fill_market_order_raw_by_id(7, &mut Order::new(1, BidOrAsk::Bid)), Err(EngineError::UnknownMarketId(7)));
+
+        assert_eq!(engine.cancel_order(42).unwrap_err(), EngineError::UnknownOrder(42));
+        let mut market_buy = Order::new(1, BidOrAsk::Bid);
+        let _ = engine.fill_market_order_with_response(&btc_usd, &mut market_buy).unwrap();
+        let err = engine.cancel_order(market_buy.id()).unwrap_err();
+        assert_eq!(err, EngineError::OrderNotResting(market_buy.id()));
+        assert_eq!(err.code(), "order_not_resting");
+
+        // The raw path has no response to carry a rejected status, so it reports the error
+        let _ = engine.place_limit_order(&btc_usd, dec!(10), Order::new(3, BidOrAsk::Ask));
+        let mut market_sell = Order::new(5, BidOrAsk::Ask);
+        assert_eq!(
+            engine.fill_market_order_raw_by_id(market_id, &mut market_sell),
+            Err(EngineError::InsufficientLiquidity { requested: 5, available: 0 })
+        );
+
+        let err = engine.size_to_lots(&btc_usd, dec!(0.00001)).unwrap_err();
+        assert_eq!(err.code(), "invalid_size");
+        let err = engine.place_limit_order(&btc_usd, dec!(10), Order::new(0, BidOrAsk::Ask)).unwrap_err();
+        assert_eq!(err, EngineError::Rejected(OrderRejection::ZeroSize));
+
+        // Usable wherever a std error is expected
+        let boxed: Box<dyn std::error::Error> = Box::new(err);
+        assert_eq!(boxed.to_string(), "order size must be positive");
+    }
+
+    #[test]
+    fn test_maker_and_taker_snapshots_record_their_fills() {
+        let clock = ManualClock::new(1_000);
+        let mut engine = MatchEngine::with_clock(Box::new(clock.clone()));
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        engine.add_new_market(btc_usd.clone());
+
+        let cheap = engine
+            .place_limit_order_with_response(&btc_usd, dec!(10), order_for("alice", 10, BidOrAsk::Ask))
+            .unwrap();
+        let dear = engine
+            .place_limit_order_with_response(&btc_usd, dec!(11), order_for("bob", 30, BidOrAsk::Ask))
+            .unwrap();
+        assert!(cheap.order.fills.is_empty());
+        assert_eq!(cheap.order.average_price, None);
+
+        // Sweeps both levels: 10 @ 10 and 20 @ 11
+        clock.set(2_000);
+        let mut first = order_for("carol", 30, BidOrAsk::Bid);
+        let taker = engine.fill_market_order_with_response(&btc_usd, &mut first).unwrap().order;
+        assert_eq!(taker.fills.len(), 2);
+        assert!(taker.fills.iter().all(|fill| fill.liquidity == Liquidity::Taker));
+        assert_eq!(taker.fills[0].counterparty_order_id, cheap.order.id);
+        assert_eq!(taker.fills[1].counterparty_user_id, "bob");
+        // (10 * 10 + 20 * 11) lots of 0.0001 over 30 lots
+        assert_eq!(taker.filled_notional, dec!(0.032));
+        assert_eq!(taker.average_price.unwrap().round_dp(6), dec!(10.666667));
+        assert_eq!(taker.last_fill_at, Some(2_000));
+
+        // The makers see the same executions from their side
+        clock.set(3_000);
+        let mut second = order_for("dave", 5, BidOrAsk::Bid);
+        let second = engine.fill_market_order_with_response(&btc_usd, &mut second).unwrap().order;
+        let maker = engine.get_order(dear.order.id).unwrap();
+        assert!(matches!(maker.status, OrderStatus::PartiallyFilled));
+        assert_eq!((maker.filled_size, maker.remaining_size), (25, 5));
+        let fills = engine.get_order_fills(dear.order.id).unwrap();
+        assert_eq!(fills.len(), 2);
+        assert!(fills.iter().all(|fill| fill.liquidity == Liquidity::Maker && fill.price == price_to_tick(dec!(11))));
+        assert_eq!((fills[0].counterparty_order_id, fills[0].qty), (taker.id, 20));
+        assert_eq!((fills[1].counterparty_user_id.as_str(), fills[1].qty), ("dave", 5));
+        assert_eq!(fills[1].trade_id, second.fills[0].trade_id);
+        assert_eq!(maker.average_price, Some(dec!(11)));
+        assert_eq!(maker.last_fill_at, Some(3_000));
+
+        let maker = engine.get_order(cheap.order.id).unwrap();
+        assert!(matches!(maker.status, OrderStatus::Filled));
+        assert_eq!(maker.fills.len(), 1);
+        assert_eq!(maker.last_fill_at, Some(2_000));
+        assert!(engine.get_order_fills(999).is_none());
+    }
+
+    #[test]
+    fn test_crossing_limit_order_records_taker_fills() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        engine.add_new_market(btc_usd.clone());
+        let maker = engine
+            .place_limit_order_with_response(&btc_usd, dec!(20), order_for("alice", 4, BidOrAsk::Bid))
+            .unwrap();
+
+        let response = engine
+            .place_limit_order_with_response(&btc_usd, dec!(19), order_for("bob", 10, BidOrAsk::Ask))
+            .unwrap();
+        assert!(matches!(response.order.status, OrderStatus::PartiallyFilled));
+        assert_eq!(response.order.fills.len(), 1);
+        assert_eq!(response.order.fills[0].counterparty_order_id, maker.order.id);
+        assert_eq!(response.order.average_price, Some(dec!(20)), "a crossing limit trades at the maker's price");
+        // The resting remainder keeps its fills
+        assert_eq!(engine.get_order(response.order.id).unwrap().fills.len(), 1);
+    }
+
+    fn funded_engine() -> (MatchEngine, TradingPair) {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        engine.add_new_market_with_lot_size(btc_usd.clone(), dec!(1)).unwrap();
+        engine.set_enforce_balances(true);
+        engine.deposit("alice", "usd", dec!(1000)).unwrap();
+        engine.deposit("bob", "btc", dec!(10)).unwrap();
+        (engine, btc_usd)
+    }
+
+    #[test]
+    fn test_balances_are_locked_settled_and_released() {
+        let (mut engine, btc_usd) = funded_engine();
+
+        engine
+            .place_limit_order_raw(&btc_usd, dec!(100), order_for("bob", 4, BidOrAsk::Ask))
+            .unwrap();
+        assert_eq!(engine.balance("bob", "btc").available, dec!(6));
+        assert_eq!(engine.balance("bob", "btc").locked, dec!(4));
+
+        // Locks 6 * 105, buys 4 @ 100 and gets the 4 * 5 price improvement back
+        let buy = engine
+            .place_limit_order_with_response(&btc_usd, dec!(105), order_for("alice", 6, BidOrAsk::Bid))
+            .unwrap();
+        let alice_usd = engine.balance("alice", "usd");
+        assert_eq!((alice_usd.available, alice_usd.locked), (dec!(390), dec!(210)));
+        assert_eq!(engine.balance("alice", "btc").available, dec!(4));
+        let bob_btc = engine.balance("bob", "btc");
+        assert_eq!((bob_btc.available, bob_btc.locked), (dec!(6), dec!(0)));
+        assert_eq!(engine.balance("bob", "usd").available, dec!(400));
+
+        engine.cancel_order(buy.order.id).unwrap();
+        let alice_usd = engine.balance("alice", "usd");
+        assert_eq!((alice_usd.available, alice_usd.locked), (dec!(600), dec!(0)));
+        assert_eq!(engine.accounts().asset_total("usd"), dec!(1000));
+        assert_eq!(engine.accounts().asset_total("btc"), dec!(10));
+        engine.check_ledger().unwrap();
+    }
+
+    #[test]
+    fn test_orders_the_user_cannot_cover_are_refused() {
+        let (mut engine, btc_usd) = funded_engine();
+
+        let err = engine
+            .place_limit_order_raw(&btc_usd, dec!(100), order_for("alice", 11, BidOrAsk::Bid))
+            .unwrap_err();
+        assert_eq!(err.code(), "insufficient_funds");
+        assert!(engine.get_limits_for_a_pair(&btc_usd).unwrap().bid_limits().is_empty());
+        assert_eq!(engine.balance("alice", "usd").available, dec!(1000));
+
+        let mut sell = order_for("carol", 1, BidOrAsk::Ask);
+        assert!(matches!(
+            engine.fill_market_order_raw(&btc_usd, &mut sell),
+            Err(EngineError::InsufficientFunds { .. })
+        ));
+
+        // A market buy locks what the book would cost: 1 @ 100 + 1 @ 120
+        engine.place_limit_order_raw(&btc_usd, dec!(100), order_for("bob", 1, BidOrAsk::Ask)).unwrap();
+        engine.place_limit_order_raw(&btc_usd, dec!(120), order_for("bob", 5, BidOrAsk::Ask)).unwrap();
+        engine.withdraw("alice", "usd", dec!(750)).unwrap();
+        let mut buy = order_for("alice", 2, BidOrAsk::Bid);
+        engine.fill_market_order_raw(&btc_usd, &mut buy).unwrap();
+        let alice_usd = engine.balance("alice", "usd");
+        assert_eq!((alice_usd.available, alice_usd.locked), (dec!(30), dec!(0)));
+        assert_eq!(engine.balance("alice", "btc").available, dec!(2));
+
+        let mut buy = order_for("alice", 1, BidOrAsk::Bid);
+        let err = engine.fill_market_order_raw(&btc_usd, &mut buy).unwrap_err();
+        assert_eq!(
+            err,
+            EngineError::InsufficientFunds { asset: "usd".to_string(), required: dec!(120), available: dec!(30) }
+        );
+    }
+
+    #[test]
+    fn test_ledger_reconciles_with_balances_after_every_step() {
+        let (mut engine, btc_usd) = funded_engine();
+        engine.check_ledger().unwrap();
+
+        for round in 0..20u64 {
+            let price = Decimal::from(90 + round % 7);
+            engine
+                .place_limit_order_raw(&btc_usd, price, order_for("bob", 1, BidOrAsk::Ask))
+                .ok();
+            engine
+                .place_limit_order_raw(&btc_usd, dec!(95), order_for("alice", 1 + round % 2, BidOrAsk::Bid))
+                .ok();
+            engine.check_ledger().unwrap();
+        }
+        engine.withdraw("bob", "usd", dec!(50)).unwrap();
+        engine.check_ledger().unwrap();
+
+        let ledger = engine.ledger();
+        assert_eq!(ledger.net_deposits("usd"), dec!(950));
+        assert_eq!(ledger.net_deposits("btc"), dec!(10));
+        assert!(matches!(ledger.entries()[0].kind, EntryKind::Deposit));
+        assert!(matches!(ledger.entries().last().unwrap().kind, EntryKind::Withdrawal));
+        let trades = engine.get_trades_for_pair(&btc_usd).unwrap();
+        assert!(!trades.is_empty());
+        let settled = ledger
+            .entries()
+            .iter()
+            .filter(|entry| matches!(entry.kind, EntryKind::Trade { .. }))
+            .count();
+        assert_eq!(settled, trades.len());
+
+        // A journal that disagrees with the balances is caught
+        let mut ledger = Ledger::new();
+        let unbalanced = vec![Posting::new(LedgerAccount::User("alice".to_string()), "usd", dec!(5))];
+        assert!(ledger.post(EntryKind::Deposit, 0, unbalanced).is_err());
+        assert!(ledger.reconcile(engine.accounts()).is_err());
+    }
+
+    #[test]
+    fn test_fees_follow_volume_tiers_and_go_to_the_house() {
+        let clock = ManualClock::new(1_000);
+        let mut engine = MatchEngine::with_clock(Box::new(clock.clone()));
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        let fees = FeeSchedule {
+            tiers: vec![
+                FeeTier { min_volume: dec!(0), maker_rate: dec!(0.001), taker_rate: dec!(0.002) },
+                FeeTier { min_volume: dec!(1000), maker_rate: dec!(-0.0005), taker_rate: dec!(0.001) },
+            ],
+        };
+        let spec = MarketSpec { lot_size: dec!(1), fees, ..MarketSpec::default() };
+        let market_id = engine.add_market_with_spec(btc_usd.clone(), spec).unwrap();
+        engine.set_enforce_balances(true);
+        engine.deposit("alice", "usd", dec!(10000)).unwrap();
+        engine.deposit("bob", "btc", dec!(100)).unwrap();
+
+        let trade = |engine: &mut MatchEngine, qty: Qty| {
+            engine.place_limit_order_raw(&btc_usd, dec!(100), order_for("bob", qty, BidOrAsk::Ask)).unwrap();
+            engine
+                .place_limit_order_with_response(&btc_usd, dec!(100), order_for("alice", qty, BidOrAsk::Bid))
+                .unwrap()
+        };
+
+        // First tier: 500 notional, taker pays 1, maker 0.5
+        let response = trade(&mut engine, 5);
+        assert_eq!((response.trades[0].maker_fee, response.trades[0].taker_fee), (dec!(0.5), dec!(1)));
+        assert_eq!(response.order.fees_paid, dec!(1));
+        assert_eq!(response.order.fills[0].fee, dec!(1));
+        let maker = engine.get_order(response.trades[0].maker_order_id).unwrap();
+        assert_eq!(maker.fees_paid, dec!(0.5));
+        assert_eq!(engine.balance("alice", "usd").available, dec!(9499));
+        assert_eq!(engine.balance("alice", "usd").locked, dec!(0));
+        assert_eq!(engine.balance("bob", "usd").available, dec!(499.5));
+
+        // Volume before the trade (500) still counts as the first tier
+        let response = trade(&mut engine, 6);
+        assert_eq!((response.trades[0].maker_fee, response.trades[0].taker_fee), (dec!(0.6), dec!(1.2)));
+        assert_eq!(engine.trailing_volume("alice", market_id), dec!(1100));
+
+        // Second tier: the maker earns a rebate
+        let response = trade(&mut engine, 1);
+        assert_eq!((response.trades[0].maker_fee, response.trades[0].taker_fee), (dec!(-0.05), dec!(0.1)));
+        assert_eq!(engine.balance(HOUSE_ACCOUNT, "usd").available, dec!(1.5) + dec!(1.8) + dec!(0.05));
+
+        // Old volume drops out of the window
+        clock.set(1_000 + FEE_VOLUME_WINDOW);
+        assert_eq!(engine.trailing_volume("alice", market_id), dec!(0));
+        let response = trade(&mut engine, 1);
+        assert_eq!(response.trades[0].taker_fee, dec!(0.2));
+
+        // Volume counts by day: a trade stays in the window through its 30th day
+        clock.advance(MILLIS_PER_DAY);
+        trade(&mut engine, 2);
+        assert_eq!(engine.trailing_volume("alice", market_id), dec!(300));
+        clock.advance(FEE_VOLUME_WINDOW - MILLIS_PER_DAY);
+        assert_eq!(engine.trailing_volume("alice", market_id), dec!(200));
+        clock.advance(MILLIS_PER_DAY);
+        assert_eq!(engine.trailing_volume("alice", market_id), dec!(0));
+
+        engine.check_ledger().unwrap();
+        assert_eq!(engine.accounts().asset_total("usd"), dec!(10000));
+
+        let generous = FeeSchedule::flat(dec!(-0.003), dec!(0.002));
+        assert!(matches!(
+            engine.set_fee_schedule(market_id, generous),
+            Err(EngineError::InvalidMarketSpec(_))
+        ));
+    }
+
+    fn order_with_prevention(user_id: &str, size: Qty, side: BidOrAsk, prevention: SelfTradePrevention) -> Order {
+        let mut order = order_for(user_id, size, side);
+        order.set_self_trade_prevention(Some(prevention));
+        order
+    }
+
+    #[test]
+    fn test_self_trade_prevention_cancel_newest_and_oldest() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        let spec = MarketSpec { self_trade_prevention: SelfTradePrevention::CancelOldest, ..MarketSpec::default() };
+        engine.add_market_with_spec(btc_usd.clone(), spec).unwrap();
+        let own_ask = engine
+            .place_limit_order_with_response(&btc_usd, dec!(100), order_for("alice", 5, BidOrAsk::Ask))
+            .unwrap();
+        let bob_ask = engine
+            .place_limit_order_with_response(&btc_usd, dec!(101), order_for("bob", 4, BidOrAsk::Ask))
+            .unwrap();
+
+        // Per order: the incoming buy is canceled before it reaches bob, alice's ask stays
+        let newest = engine
+            .place_limit_order_with_response(
+                &btc_usd,
+                dec!(101),
+                order_with_prevention("alice", 8, BidOrAsk::Bid, SelfTradePrevention::CancelNewest),
+            )
+            .unwrap();
+        assert!(newest.trades.is_empty());
+        assert!(matches!(newest.order.status, OrderStatus::Canceled));
+        assert_eq!((newest.order.filled_size, newest.order.canceled_size), (0, 8));
+        assert_eq!(newest.order.self_trade_canceled_size, 8);
+        assert!(newest.message.contains("canceled by self-trade prevention"));
+        assert_eq!(engine.get_limits_for_a_pair(&btc_usd).unwrap().ask_capacity(), 9);
+
+        // Market default: alice's resting ask is canceled and the buy trades with bob
+        let mut buy = order_for("alice", 6, BidOrAsk::Bid);
+        let oldest = engine.fill_market_order_with_response(&btc_usd, &mut buy).unwrap();
+        assert_eq!(oldest.trades.len(), 1);
+        assert_eq!(oldest.trades[0].maker_order_id, bob_ask.order.id);
+        assert_eq!((oldest.order.filled_size, oldest.order.self_trade_canceled_size), (4, 0));
+        let canceled = engine.get_order(own_ask.order.id).unwrap();
+        assert!(matches!(canceled.status, OrderStatus::Canceled));
+        assert_eq!((canceled.remaining_size, canceled.self_trade_canceled_size), (0, 5));
+        assert_eq!(engine.get_limits_for_a_pair(&btc_usd).unwrap().ask_capacity(), 0);
+        assert!(matches!(engine.cancel_order(own_ask.order.id), Err(EngineError::OrderNotResting(_))));
+    }
+
+    #[test]
+    fn test_self_trade_prevention_decrement_and_cancel_releases_funds() {
+        let (mut engine, btc_usd) = funded_engine();
+        engine.deposit("alice", "btc", dec!(3)).unwrap();
+        engine.place_limit_order_raw(&btc_usd, dec!(100), order_for("bob", 2, BidOrAsk::Ask)).unwrap();
+        let own_ask = engine
+            .place_limit_order_with_response(&btc_usd, dec!(100), order_for("alice", 3, BidOrAsk::Ask))
+            .unwrap();
+
+        let buy = engine
+            .place_limit_order_with_response(
+                &btc_usd,
+                dec!(100),
+                order_with_prevention("alice", 10, BidOrAsk::Bid, SelfTradePrevention::DecrementAndCancel),
+            )
+            .unwrap();
+        assert!(matches!(buy.order.status, OrderStatus::PartiallyFilled));
+        assert_eq!(
+            (buy.order.filled_size, buy.order.self_trade_canceled_size, buy.order.remaining_size),
+            (2, 3, 5)
+        );
+        let own_ask = engine.get_order(own_ask.order.id).unwrap();
+        assert!(matches!(own_ask.status, OrderStatus::Canceled));
+        assert_eq!(own_ask.self_trade_canceled_size, 3);
+
+        // 200 paid for bob's 2, the 3 decremented are unlocked and 5 @ 100 stay locked
+        let alice_usd = engine.balance("alice", "usd");
+        assert_eq!((alice_usd.available, alice_usd.locked), (dec!(300), dec!(500)));
+        let alice_btc = engine.balance("alice", "btc");
+        assert_eq!((alice_btc.available, alice_btc.locked), (dec!(5), dec!(0)));
+        engine.check_ledger().unwrap();
+
+        engine.cancel_order(buy.order.id).unwrap();
+        assert_eq!(engine.balance("alice", "usd").available, dec!(800));
+    }
+
+    #[test]
+    fn test_fill_or_kill_does_not_count_the_takers_own_orders() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        let spec = MarketSpec { self_trade_prevention: SelfTradePrevention::CancelOldest, ..MarketSpec::default() };
+        engine.add_market_with_spec(btc_usd.clone(), spec).unwrap();
+        let bob_ask = engine
+            .place_limit_order_with_response(&btc_usd, dec!(100), order_for("bob", 2, BidOrAsk::Ask))
+            .unwrap();
+        let own_ask = engine
+            .place_limit_order_with_response(&btc_usd, dec!(100.5), order_for("alice", 5, BidOrAsk::Ask))
+            .unwrap();
+        engine.place_limit_order_raw(&btc_usd, dec!(101), order_for("carol", 4, BidOrAsk::Ask)).unwrap();
+
+        // 11 rest up to 101 but only 6 are someone else's: the order is killed untouched
+        let mut fok = order_for("alice", 8, BidOrAsk::Bid);
+        fok.set_time_in_force(TimeInForce::FillOrKill);
+        let killed = engine.place_limit_order_with_response(&btc_usd, dec!(101), fok).unwrap();
+        assert!(matches!(killed.order.status, OrderStatus::Rejected));
+        assert!(killed.trades.is_empty());
+        assert_eq!((killed.order.filled_size, killed.order.canceled_size), (0, 8));
+        assert!(matches!(engine.get_order(own_ask.order.id).unwrap().status, OrderStatus::Open));
+        assert_eq!(engine.get_limits_for_a_pair(&btc_usd).unwrap().ask_capacity(), 11);
+
+        // Stopped by its own ask at 100.5, a market order only reaches bob's 2
+        let mut market_buy = order_with_prevention("alice", 3, BidOrAsk::Bid, SelfTradePrevention::CancelNewest);
+        let killed = engine
+            .fill_market_order_with_policy(&btc_usd, &mut market_buy, MarketOrderPolicy::FillOrKill)
+            .unwrap();
+        assert!(matches!(killed.order.status, OrderStatus::Rejected));
+        assert_eq!((killed.order.filled_size, killed.order.self_trade_canceled_size), (0, 0));
+        assert_eq!(engine.get_limits_for_a_pair(&btc_usd).unwrap().ask_capacity(), 11);
+
+        // What the others have is enough: alice's ask is canceled on the way and the order fills
+        let mut fok = order_for("alice", 6, BidOrAsk::Bid);
+        fok.set_time_in_force(TimeInForce::FillOrKill);
+        let filled = engine.place_limit_order_with_response(&btc_usd, dec!(101), fok).unwrap();
+        assert!(matches!(filled.order.status, OrderStatus::Filled));
+        assert_eq!(filled.trades.len(), 2);
+        assert_eq!(filled.trades[0].maker_order_id, bob_ask.order.id);
+        assert!(matches!(engine.get_order(own_ask.order.id).unwrap().status, OrderStatus::Canceled));
+        assert_eq!(engine.get_limits_for_a_pair(&btc_usd).unwrap().ask_capacity(), 0);
+    }
+
+    #[test]
+    fn test_post_only_orders_never_take_liquidity() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        let spec = MarketSpec { tick_size: dec!(0.5), ..MarketSpec::default() };
+        engine.add_market_with_spec(btc_usd.clone(), spec).unwrap();
+        engine.place_limit_order_raw(&btc_usd, dec!(100), order_for("bob", 5, BidOrAsk::Ask)).unwrap();
+        engine.place_limit_order_raw(&btc_usd, dec!(98), order_for("bob", 5, BidOrAsk::Bid)).unwrap();
+
+        let mut crossing = order_for("alice", 3, BidOrAsk::Bid);
+        crossing.set_post_only(PostOnly::Reject);
+        let response = engine.place_limit_order_with_response(&btc_usd, dec!(101), crossing.clone()).unwrap();
+        assert!(response.trades.is_empty());
+        assert!(matches!(response.order.status, OrderStatus::Rejected));
+        assert_eq!(response.order.canceled_size, 3);
+        assert_eq!(
+            response.rejection,
+            Some(OrderRejection::WouldCross { price: dec!(101), best_price: dec!(100) })
+        );
+        assert!(response.message.contains("would cross the book at 100"));
+        let err = engine.place_limit_order_raw(&btc_usd, dec!(100), crossing.clone()).unwrap_err();
+        assert_eq!(err.code(), "post_only_would_cross");
+        assert_eq!(engine.get_limits_for_a_pair(&btc_usd).unwrap().ask_capacity(), 5);
+
+        // Below the touch it simply rests
+        let passive = engine.place_limit_order_with_response(&btc_usd, dec!(99), crossing).unwrap();
+        assert!(matches!(passive.order.status, OrderStatus::Open));
+        assert_eq!(passive.rejection, None);
+
+        // Sliding reprices one tick away from the best opposite price
+        let mut slide_buy = order_for("alice", 2, BidOrAsk::Bid);
+        slide_buy.set_post_only(PostOnly::Slide);
+        let slid = engine.place_limit_order_with_response(&btc_usd, dec!(105), slide_buy).unwrap();
+        assert!(slid.trades.is_empty());
+        assert!(matches!(slid.order.status, OrderStatus::Open));
+        assert_eq!(slid.order.price, Some(price_to_tick(dec!(99.5))));
+        assert!(slid.message.contains("repriced from 105"));
+
+        let mut slide_sell = order_for("carol", 2, BidOrAsk::Ask);
+        slide_sell.set_post_only(PostOnly::Slide);
+        let slid = engine.place_limit_order_with_response(&btc_usd, dec!(90), slide_sell).unwrap();
+        assert_eq!(slid.order.price, Some(price_to_tick(dec!(100))), "one tick above the 99.5 bid");
+        assert!(engine.get_trades_for_pair(&btc_usd).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_stop_orders_trigger_on_the_last_trade_and_cascade() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        engine.add_new_market(btc_usd.clone());
+        for price in [dec!(100), dec!(101), dec!(102), dec!(103)] {
+            engine.place_limit_order_raw(&btc_usd, price, order_for("bob", 1, BidOrAsk::Ask)).unwrap();
+        }
+
+        let stop_market = engine.place_stop_order(&btc_usd, dec!(101), None, order_for("carol", 1, BidOrAsk::Bid)).unwrap();
+        assert!(matches!(stop_market.order.status, OrderStatus::New));
+        assert!(matches!(stop_market.order.order_type, OrderType::StopMarket));
+        assert_eq!(stop_market.order.trigger_price, Some(price_to_tick(dec!(101))));
+        let stop_limit = engine
+            .place_stop_order(&btc_usd, dec!(102), Some(dec!(103)), order_for("dave", 1, BidOrAsk::Bid))
+            .unwrap();
+        let stop_sell = engine.place_stop_order(&btc_usd, dec!(90), None, order_for("erin", 1, BidOrAsk::Ask)).unwrap();
+        assert_eq!(engine.pending_stops(0).unwrap().len(), 3);
+
+        // 100 is below both buy stops
+        engine.fill_market_order_raw(&btc_usd, &mut order_for("alice", 1, BidOrAsk::Bid)).unwrap();
+        assert_eq!(engine.pending_stops(0).unwrap().len(), 3);
+
+        // Trading at 101 fires carol's stop, whose fill at 102 fires dave's
+        engine.place_limit_order_raw(&btc_usd, dec!(101), order_for("alice", 1, BidOrAsk::Bid)).unwrap();
+        let prices: Vec<_> = engine.get_trades_for_pair(&btc_usd).unwrap().iter().map(|trade| trade.price).collect();
+        assert_eq!(prices, [dec!(100), dec!(101), dec!(102), dec!(103)].map(price_to_tick));
+
+        let carol = engine.get_order(stop_market.order.id).unwrap();
+        assert!(matches!(carol.status, OrderStatus::Triggered));
+        let carol_market = engine.get_order(carol.triggered_order_id.unwrap()).unwrap();
+        assert!(matches!(carol_market.order_type, OrderType::Market));
+        assert!(matches!(carol_market.status, OrderStatus::Filled));
+        assert_eq!(carol_market.user_id, "carol");
+
+        let dave = engine.get_order(stop_limit.order.id).unwrap();
+        assert!(matches!(dave.status, OrderStatus::Triggered));
+        let dave_limit = engine.get_order(dave.triggered_order_id.unwrap()).unwrap();
+        assert!(matches!(dave_limit.order_type, OrderType::Limit));
+        assert_eq!(dave_limit.price, Some(price_to_tick(dec!(103))));
+        assert!(matches!(dave_limit.status, OrderStatus::Filled));
+
+        // A stop that hasn't triggered can be canceled, a triggered one can't
+        let canceled = engine.cancel_order(stop_sell.order.id).unwrap();
+        assert!(matches!(canceled.order.status, OrderStatus::Canceled));
+        assert!(engine.pending_stops(0).unwrap().is_empty());
+        assert!(engine.cancel_order(stop_market.order.id).is_err());
+    }
+
+    #[test]
+    fn test_stops_triggered_together_fire_in_a_deterministic_order() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        engine.add_new_market(btc_usd.clone());
+        for price in [dec!(96), dec!(95), dec!(94), dec!(93)] {
+            engine.place_limit_order_raw(&btc_usd, price, order_for("bob", 1, BidOrAsk::Bid)).unwrap();
+        }
+        for (user_id, stop) in [("alice", dec!(97)), ("carol", dec!(98)), ("dave", dec!(98))] {
+            engine.place_stop_order(&btc_usd, stop, None, order_for(user_id, 1, BidOrAsk::Ask)).unwrap();
+        }
+
+        engine.fill_market_order_raw(&btc_usd, &mut order_for("erin", 1, BidOrAsk::Ask)).unwrap();
+
+        // Highest sell stop first, earlier arrival first on the same stop
+        let sellers: Vec<_> = engine
+            .get_trades_for_pair(&btc_usd)
+            .unwrap()
+            .iter()
+            .map(|trade| (trade.taker_user_id.as_str(), trade.price))
+            .collect();
+        assert_eq!(
+            sellers,
+            [
+                ("erin", price_to_tick(dec!(96))),
+                ("carol", price_to_tick(dec!(95))),
+                ("dave", price_to_tick(dec!(94))),
+                ("alice", price_to_tick(dec!(93))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trailing_stops_follow_the_last_trade_price() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        let spec = MarketSpec { tick_size: dec!(1), ..MarketSpec::default() };
+        engine.add_market_with_spec(btc_usd.clone(), spec).unwrap();
+        assert_eq!(
+            engine
+                .place_trailing_stop_order(&btc_usd, TrailingOffset::Ticks(3), order_for("erin", 1, BidOrAsk::Ask))
+                .unwrap_err()
+                .code(),
+            "invalid_request",
+            "nothing to trail in an empty market"
+        );
+        for price in [dec!(100), dec!(105), dec!(110)] {
+            engine.place_limit_order_raw(&btc_usd, price, order_for("carol", 1, BidOrAsk::Ask)).unwrap();
+        }
+        engine.fill_market_order_raw(&btc_usd, &mut order_for("dave", 1, BidOrAsk::Bid)).unwrap();
+
+        let sell = engine
+            .place_trailing_stop_order(&btc_usd, TrailingOffset::Ticks(3), order_for("erin", 1, BidOrAsk::Ask))
+            .unwrap();
+        assert!(matches!(sell.order.order_type, OrderType::TrailingStop));
+        assert_eq!(sell.order.trigger_price, Some(price_to_tick(dec!(97))));
+        let buy = engine
+            .place_trailing_stop_order(&btc_usd, TrailingOffset::Percent(dec!(10)), order_for("frank", 1, BidOrAsk::Bid))
+            .unwrap();
+        assert_eq!(buy.order.trigger_price, Some(price_to_tick(dec!(110))));
+
+        // Rising trades drag the sell stop up, the buy stop stays where it is
+        engine.place_limit_order_raw(&btc_usd, dec!(105), order_for("bob", 1, BidOrAsk::Bid)).unwrap();
+        assert_eq!(engine.get_order(sell.order.id).unwrap().trigger_price, Some(price_to_tick(dec!(102))));
+        engine.place_limit_order_raw(&btc_usd, dec!(110), order_for("bob", 1, BidOrAsk::Bid)).unwrap();
+        assert_eq!(engine.get_order(sell.order.id).unwrap().trigger_price, Some(price_to_tick(dec!(107))));
+        assert!(matches!(engine.get_order(buy.order.id).unwrap().status, OrderStatus::Triggered));
+
+        // Falling trades don't move it back, and reaching it triggers the stop
+        for price in [dec!(108), dec!(106), dec!(104)] {
+            engine.place_limit_order_raw(&btc_usd, price, order_for("bob", 1, BidOrAsk::Bid)).unwrap();
+        }
+        engine.fill_market_order_raw(&btc_usd, &mut order_for("gina", 1, BidOrAsk::Ask)).unwrap();
+        let pending = engine.get_order(sell.order.id).unwrap();
+        assert!(matches!(pending.status, OrderStatus::New));
+        assert_eq!(pending.trigger_price, Some(price_to_tick(dec!(107))));
+
+        engine.fill_market_order_raw(&btc_usd, &mut order_for("gina", 1, BidOrAsk::Ask)).unwrap();
+        let triggered = engine.get_order(sell.order.id).unwrap();
+        assert!(matches!(triggered.status, OrderStatus::Triggered));
+        let last = engine.get_trades_for_pair(&btc_usd).unwrap().last().unwrap();
+        assert_eq!((last.taker_user_id.as_str(), last.price), ("erin", price_to_tick(dec!(104))));
+    }
+
+    #[test]
+    fn test_iceberg_orders_hide_their_reserve_but_trade_it() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        engine.add_new_market(btc_usd.clone());
+        let mut iceberg = order_for("whale", 10, BidOrAsk::Ask);
+        iceberg.set_display_qty(Some(2));
+        let iceberg = engine.place_limit_order_with_response(&btc_usd, dec!(100), iceberg).unwrap();
+        assert_eq!(iceberg.order.display_size, Some(2));
+        engine.place_limit_order_raw(&btc_usd, dec!(100), order_for("bob", 1, BidOrAsk::Ask)).unwrap();
+
+        let public = engine.get_limits_for_a_pair(&btc_usd).unwrap().public_view();
+        assert_eq!(public.ask_limits()[0].total_volume(), 3);
+        assert_eq!(public.ask_capacity(), 3);
+
+        // Fill-or-kill counts the hidden reserve as liquidity
+        let response = engine
+            .fill_market_order_with_policy(&btc_usd, &mut order_for("alice", 6, BidOrAsk::Bid), MarketOrderPolicy::FillOrKill)
+            .unwrap();
+        assert!(matches!(response.order.status, OrderStatus::Filled));
+        let makers: Vec<_> = response.trades.iter().map(|trade| (trade.maker_user_id.as_str(), trade.qty)).collect();
+        assert_eq!(makers, [("whale", 2), ("bob", 1), ("whale", 2), ("whale", 1)]);
+
+        let snapshot = engine.get_order(iceberg.order.id).unwrap();
+        assert_eq!((snapshot.filled_size, snapshot.remaining_size), (5, 5));
+        let canceled = engine.cancel_order(iceberg.order.id).unwrap();
+        assert_eq!(canceled.order.canceled_size, 5);
+        assert_eq!(engine.get_limits_for_a_pair(&btc_usd).unwrap().ask_capacity(), 0);
+    }
+
+    #[test]
+    fn test_amend_reduces_in_place_or_replaces_the_order() {
+        let (mut engine, btc_usd) = funded_engine();
+        engine.deposit("carol", "usd", dec!(1000)).unwrap();
+        let first = engine.place_limit_order_with_response(&btc_usd, dec!(100), order_for("alice", 5, BidOrAsk::Bid)).unwrap();
+        engine.place_limit_order_raw(&btc_usd, dec!(100), order_for("carol", 5, BidOrAsk::Bid)).unwrap();
+        let queue = |engine: &MatchEngine| -> Vec<u64> {
+            engine.get_limits_for_a_pair(&btc_usd).unwrap().bid_limits()[0].iter().map(|order| order.id()).collect()
+        };
+        let carol_id = queue(&engine)[1];
+
+        // Reducing keeps alice ahead of carol and unlocks the difference
+        let reduced = engine.amend_order(first.order.id, None, Some(dec!(3))).unwrap();
+        assert!(matches!(reduced.order.status, OrderStatus::Open));
+        assert_eq!((reduced.order.remaining_size, reduced.order.canceled_size), (3, 2));
+        assert_eq!(queue(&engine), vec![first.order.id, carol_id]);
+        assert_eq!(engine.balance("alice", "usd").locked, dec!(300));
+
+        assert_eq!(engine.amend_order(first.order.id, None, Some(dec!(3))).unwrap_err().code(), "invalid_request");
+        assert_eq!(engine.amend_order(first.order.id, None, Some(dec!(0))).unwrap_err().code(), "invalid_size");
+
+        // Raising the size replaces the order at the back of the queue
+        let raised = engine.amend_order(first.order.id, None, Some(dec!(4))).unwrap();
+        let raised_id = raised.order.id;
+        assert_ne!(raised_id, first.order.id);
+        assert_eq!(raised.order.replaces_order_id, Some(first.order.id));
+        assert_eq!(queue(&engine), vec![carol_id, raised_id]);
+        let old = engine.get_order(first.order.id).unwrap();
+        assert!(matches!(old.status, OrderStatus::Replaced));
+        assert_eq!(old.replaced_by_order_id, Some(raised_id));
+        assert_eq!(engine.balance("alice", "usd").locked, dec!(400));
+
+        // A new price is matched against the other side like a new order
+        engine.place_limit_order_raw(&btc_usd, dec!(102), order_for("bob", 2, BidOrAsk::Ask)).unwrap();
+        let repriced = engine.amend_order(raised_id, Some(dec!(102)), None).unwrap();
+        assert_eq!(repriced.trades.len(), 1);
+        assert_eq!((repriced.order.filled_size, repriced.order.remaining_size), (2, 2));
+        assert_eq!(engine.get_order(repriced.order.id).unwrap().replaces_order_id, Some(raised_id));
+        assert!(engine.amend_order(raised_id, None, Some(dec!(1))).is_err(), "replaced orders can't be amended");
+        engine.check_ledger().unwrap();
+    }
+
+    #[test]
+    fn test_failed_amend_leaves_the_order_as_it_was() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        let spec = MarketSpec { lot_size: dec!(1), min_qty: dec!(2), ..MarketSpec::default() };
+        engine.add_market_with_spec(btc_usd.clone(), spec).unwrap();
+        engine.set_enforce_balances(true);
+        engine.deposit("alice", "usd", dec!(1000)).unwrap();
+        let first = engine.place_limit_order_with_response(&btc_usd, dec!(100), order_for("alice", 5, BidOrAsk::Bid)).unwrap();
+
+        // 1250 needed, 1000 there once the order's 500 are released: nothing is replaced
+        let err = engine.amend_order(first.order.id, Some(dec!(250)), None).unwrap_err();
+        assert_eq!(err, EngineError::InsufficientFunds { asset: "usd".to_string(), required: dec!(1250), available: dec!(1000) });
+        let order = engine.get_order(first.order.id).unwrap();
+        assert!(matches!(order.status, OrderStatus::Open));
+        assert_eq!((order.remaining_size, order.replaced_by_order_id), (5, None));
+        assert!(engine.get_limits_for_a_pair(&btc_usd).unwrap().contains_order(first.order.id));
+        assert_eq!(engine.balance("alice", "usd").locked, dec!(500));
+
+        // Reducing in place is held to the spec like a new order
+        let err = engine.amend_order(first.order.id, None, Some(dec!(1))).unwrap_err();
+        assert_eq!(err, EngineError::Rejected(OrderRejection::SizeBelowMinimum { size: dec!(1), min_qty: dec!(2) }));
+        assert_eq!(engine.get_order(first.order.id).unwrap().remaining_size, 5);
+
+        // A post-only order amended across the book is rejected before it's replaced
+        let mut post_only = order_for("alice", 2, BidOrAsk::Bid);
+        post_only.set_post_only(PostOnly::Reject);
+        let second = engine.place_limit_order_with_response(&btc_usd, dec!(99), post_only).unwrap();
+        engine.deposit("bob", "btc", dec!(5)).unwrap();
+        let ask = engine.place_limit_order_with_response(&btc_usd, dec!(102), order_for("bob", 2, BidOrAsk::Ask)).unwrap();
+        let bids = |engine: &MatchEngine| -> Vec<u64> {
+            let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
+            orderbook.bid_limits().iter().flat_map(|limit| limit.iter().map(|order| order.id())).collect()
+        };
+        let bids_before = bids(&engine);
+        let err = engine.amend_order(second.order.id, Some(dec!(103)), None).unwrap_err();
+        assert_eq!(err, EngineError::Rejected(OrderRejection::WouldCross { price: dec!(103), best_price: dec!(102) }));
+        let order = engine.get_order(second.order.id).unwrap();
+        assert!(matches!(order.status, OrderStatus::Open));
+        assert_eq!(order.replaced_by_order_id, None);
+        assert_eq!(bids(&engine), bids_before);
+        engine.cancel_order(second.order.id).unwrap();
+        engine.cancel_order(ask.order.id).unwrap();
+
+        // Funds the replaced order releases count towards its replacement
+        let replaced = engine.amend_order(first.order.id, Some(dec!(200)), None).unwrap();
+        assert!(matches!(replaced.order.status, OrderStatus::Open));
+        assert_eq!(engine.balance("alice", "usd").locked, dec!(1000));
+        engine.check_ledger().unwrap();
+    }
+
+    fn limit_request(pair: &TradingPair, user_id: &str, client_order_id: &str, price: Decimal, size: Decimal) -> NewOrderRequest {
+        NewOrderRequest {
+            market: pair.clone(),
+            user_id: user_id.to_string(),
+            side: BidOrAsk::Bid,
+            order_type: OrderType::Limit,
+            price: Some(price),
+            stop_price: None,
+            trailing_offset: None,
+            display_size: None,
+            size,
+            time_in_force: TimeInForce::default(),
+            client_order_id: Some(client_order_id.to_string()),
+            self_trade_prevention: None,
+            post_only: PostOnly::Off,
+        }
+    }
+
+    #[test]
+    fn test_client_order_ids_make_submission_idempotent() {
+        let mut engine = MatchEngine::new();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        engine.add_new_market(btc_usd.clone());
+
+        let request = limit_request(&btc_usd, "alice", "a-1", dec!(100), dec!(1));
+        let first = engine.submit_order(&request).unwrap();
+        let retried = engine.submit_order(&request).unwrap();
+        assert_eq!(retried.order.id, first.order.id);
+        assert_eq!(retried.message, first.message);
+        let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
+        assert_eq!(orderbook.resting_orders_count(), 1);
+
+        // The id is only unique per user
+        let bob = engine.submit_order(&limit_request(&btc_usd, "bob", "a-1", dec!(100), dec!(1))).unwrap();
+        assert_ne!(bob.order.id, first.order.id);
+
+        // A failed submission doesn't claim the id
+        let off_tick = limit_request(&btc_usd, "alice", "a-2", dec!(100.00001), dec!(1));
+        assert!(engine.submit_order(&off_tick).is_err());
+        let fixed = engine.submit_order(&limit_request(&btc_usd, "alice", "a-2", dec!(99), dec!(1))).unwrap();
+        assert!(matches!(fixed.order.status, OrderStatus::Open));
+        assert!(engine.submit_order(&limit_request(&btc_usd, "alice", "", dec!(99), dec!(1))).is_err());
+
+        // Lookups follow the order through cancel/replace
+        assert_eq!(engine.get_order_by_client_id("alice", "a-1").unwrap().id, first.order.id);
+        let replaced = engine.amend_order(first.order.id, Some(dec!(98)), None).unwrap();
+        let current = engine.get_order_by_client_id("alice", "a-1").unwrap();
+        assert_eq!(current.id, replaced.order.id);
+        assert_eq!(current.client_order_id.as_deref(), Some("a-1"));
+
+        let canceled = engine.cancel_order_by_client_id("alice", "a-1").unwrap();
+        assert_eq!(canceled.order.id, replaced.order.id);
+        assert!(matches!(canceled.order.status, OrderStatus::Canceled));
+        assert_eq!(engine.cancel_order_by_client_id("alice", "a-3").unwrap_err().code(), "unknown_order");
+        assert_eq!(engine.get_order_by_client_id("carol", "a-1").unwrap_err().code(), "unknown_order");
+    }
+
+    #[test]
+    fn test_commands_are_journaled_in_sequence_before_they_apply() {
+        let path = std::env::temp_dir().join(format!("journal-{}-sequence.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let clock = ManualClock::new(1_000);
+        let mut engine = MatchEngine::with_clock(Box::new(clock.clone()));
+        engine.set_journal(Journal::open(&path, FsyncPolicy::Always).unwrap()).unwrap();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+
+        engine.execute(Command::AddMarket { pair: btc_usd.clone(), spec: MarketSpec::default() }).unwrap();
+        clock.advance(5);
+        engine.execute(Command::SubmitOrder(limit_request(&btc_usd, "alice", "a-1", dec!(100), dec!(1)))).unwrap();
+        // Failed commands are journaled too, replaying them fails the same way
+        assert!(engine.execute(Command::CancelOrder { order_id: 99 }).is_err());
+        assert_eq!(engine.last_sequence(), 3);
+
+        let records = Journal::read(&path).unwrap();
+        let sequences: Vec<u64> = records.iter().map(|record| record.sequence).collect();
+        assert_eq!(sequences, vec![1, 2, 3]);
+        assert_eq!(records[1].timestamp, 1_005);
+        assert!(matches!(&records[1].command, Command::SubmitOrder(request) if request.user_id == "alice"));
+        assert!(matches!(records[2].command, Command::CancelOrder { order_id: 99 }));
+
+        // A record torn by a crash is dropped when the journal is opened again
+        drop(engine);
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| std::io::Write::write_all(&mut file, b"{\"version\":1,\"seq"))
+            .unwrap();
+        let journal = Journal::open(&path, FsyncPolicy::Batch(10)).unwrap();
+        assert_eq!(journal.last_sequence(), 3);
+        assert_eq!(Journal::read(&path).unwrap().len(), 3);
+
+        // So is half a record from a write that failed, before the next one goes in
+        let mut journal = journal;
+        let record = JournalRecord::new(4, 1_010, Command::CancelOrder { order_id: 98 });
+        let err = journal
+            .append_with(&record, |file, line| {
+                std::io::Write::write_all(file, &line[..line.len() / 2])?;
+                Err(std::io::Error::other("disk full"))
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "disk full");
+        assert_eq!(journal.last_sequence(), 3);
+        journal.append(&record).unwrap();
+        let records = Journal::read(&path).unwrap();
+        assert_eq!(records.len(), 4);
+        assert!(matches!(records[3].command, Command::CancelOrder { order_id: 98 }));
+
+        // An engine that hasn't replayed the journal can't append to it
+        let err = MatchEngine::new().set_journal(journal).unwrap_err();
+        assert_eq!(err.code(), "invalid_request");
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!("always".parse(), Ok(FsyncPolicy::Always));
+        assert_eq!("never".parse(), Ok(FsyncPolicy::Never));
+        assert_eq!("100".parse(), Ok(FsyncPolicy::Batch(100)));
+        assert!("0".parse::<FsyncPolicy>().is_err());
+    }
+
+    fn state_json(engine: &MatchEngine, pair: &TradingPair) -> Vec<String> {
+        let mut state = vec![
+            serde_json::to_string(engine.get_limits_for_a_pair(pair).unwrap()).unwrap(),
+            serde_json::to_string(&engine.get_orders()).unwrap(),
+            serde_json::to_string(engine.get_trades_for_pair(pair).unwrap()).unwrap(),
+            serde_json::to_string(&engine.stats()).unwrap(),
+            serde_json::to_string(engine.ledger().entries()).unwrap(),
+            engine.pending_stops(0).unwrap().len().to_string(),
+        ];
+        for user_id in ["alice", "bob", "carol", HOUSE_ACCOUNT] {
+            state.push(serde_json::to_string(&engine.balances(user_id)).unwrap());
+        }
+        state.push(engine.state_hash().to_string());
+        state
+    }
+
+    #[test]
+    fn test_replaying_the_journal_rebuilds_identical_state() {
+        let path = std::env::temp_dir().join(format!("journal-{}-replay.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let clock = ManualClock::new(1_000);
+        let mut live = MatchEngine::with_clock(Box::new(clock.clone()));
+        live.set_journal(Journal::open(&path, FsyncPolicy::Never).unwrap()).unwrap();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        let spec = MarketSpec {
+            tick_size: dec!(0.5),
+            lot_size: dec!(1),
+            fees: FeeSchedule::flat(dec!(0.001), dec!(0.002)),
+            ..MarketSpec::default()
+        };
+
+        let base = limit_request(&btc_usd, "alice", "", dec!(100), dec!(4));
+        let order = |user_id: &str, side: BidOrAsk, order_type: OrderType, price: Option<Decimal>, size: Decimal| {
+            NewOrderRequest { user_id: user_id.to_string(), side, order_type, price, size, client_order_id: None, ..base.clone() }
+        };
+        let mut session = vec![
+            Command::AddMarket { pair: btc_usd.clone(), spec },
+            Command::SetEnforceBalances { enabled: true },
+            Command::Deposit { user_id: "alice".to_string(), asset: "usd".to_string(), amount: dec!(10000) },
+            Command::Deposit { user_id: "bob".to_string(), asset: "btc".to_string(), amount: dec!(100) },
+            Command::Deposit { user_id: "carol".to_string(), asset: "btc".to_string(), amount: dec!(50) },
+            Command::SubmitOrder(NewOrderRequest {
+                display_size: Some(dec!(2)),
+                ..order("bob", BidOrAsk::Ask, OrderType::Limit, Some(dec!(101)), dec!(10))
+            }),
+            Command::SubmitOrder(NewOrderRequest {
+                time_in_force: TimeInForce::Day,
+                ..order("bob", BidOrAsk::Ask, OrderType::Limit, Some(dec!(102)), dec!(5))
+            }),
+            Command::SubmitOrder(NewOrderRequest {
+                stop_price: Some(dec!(99)),
+                ..order("carol", BidOrAsk::Ask, OrderType::StopMarket, None, dec!(1))
+            }),
+            Command::SubmitOrder(limit_request(&btc_usd, "alice", "a-1", dec!(100), dec!(4))),
+            Command::SubmitOrder(order("carol", BidOrAsk::Ask, OrderType::Market, None, dec!(2))),
+            Command::SubmitOrder(NewOrderRequest {
+                trailing_offset: Some(TrailingOffset::Ticks(2)),
+                ..order("alice", BidOrAsk::Bid, OrderType::TrailingStop, None, dec!(1))
+            }),
+            Command::SubmitOrder(order("alice", BidOrAsk::Bid, OrderType::Market, None, dec!(6))),
+            Command::CancelOrder { order_id: 999 },
+        ];
+        for command in session.drain(..) {
+            clock.advance(7);
+            let _ = live.execute(command);
+        }
+        let alice_bid = live.get_order_by_client_id("alice", "a-1").unwrap().id;
+        clock.advance(3);
+        live.execute(Command::AmendOrder { order_id: alice_bid, price: Some(dec!(99.5)), size: None }).unwrap();
+        live.execute(Command::PlaceLimitOrder { pair: btc_usd.clone(), price: dec!(99.5), order: order_for("carol", 3, BidOrAsk::Ask) }).unwrap();
+        clock.advance(MILLIS_PER_DAY);
+        live.execute(Command::ExpireOrders).unwrap();
+        live.execute(Command::FillMarketOrder {
+            pair: btc_usd.clone(),
+            order: order_for("bob", 1, BidOrAsk::Ask),
+            policy: MarketOrderPolicy::ImmediateOrCancel,
+        })
+        .unwrap();
+        assert!(live.get_trades_for_pair(&btc_usd).unwrap().len() >= 5);
+        assert!(live.get_orders().iter().any(|order| matches!(order.status, OrderStatus::Expired)));
+
+        let mut replayed = MatchEngine::new();
+        assert_eq!(replayed.replay_file(&path).unwrap(), live.last_sequence());
+        assert_eq!(replayed.last_sequence(), live.last_sequence());
+        assert_eq!(state_json(&replayed, &btc_usd), state_json(&live, &btc_usd));
+        replayed.check_ledger().unwrap();
+
+        // Records that don't continue the engine's sequence are refused
+        let records = Journal::read(&path).unwrap();
+        assert!(replayed.replay(&records).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_restore_loads_the_latest_snapshot_and_replays_the_journal_tail() {
+        let dir = std::env::temp_dir().join(format!("snapshots-{}-restore", std::process::id()));
+        let path = std::env::temp_dir().join(format!("journal-{}-restore.jsonl", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_file(&path);
+        let clock = ManualClock::new(1_000);
+        let mut live = MatchEngine::with_clock(Box::new(clock.clone()));
+        live.set_journal(Journal::open(&path, FsyncPolicy::Never).unwrap()).unwrap();
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        let spec = MarketSpec { lot_size: dec!(1), fees: FeeSchedule::flat(dec!(0), dec!(0.001)), ..MarketSpec::default() };
+        let base = limit_request(&btc_usd, "alice", "", dec!(100), dec!(1));
+        let ask = |price: Decimal, size: Decimal| NewOrderRequest {
+            user_id: "bob".to_string(),
+            side: BidOrAsk::Ask,
+            price: Some(price),
+            size,
+            client_order_id: None,
+            ..base.clone()
+        };
+
+        for command in [
+            Command::AddMarket { pair: btc_usd.clone(), spec },
+            Command::SetEnforceBalances { enabled: true },
+            Command::Deposit { user_id: "alice".to_string(), asset: "usd".to_string(), amount: dec!(10000) },
+            Command::Deposit { user_id: "bob".to_string(), asset: "btc".to_string(), amount: dec!(20) },
+            Command::SubmitOrder(NewOrderRequest { display_size: Some(dec!(1)), ..ask(dec!(101), dec!(5)) }),
+            Command::SubmitOrder(NewOrderRequest {
+                order_type: OrderType::StopLimit,
+                stop_price: Some(dec!(103)),
+                ..limit_request(&btc_usd, "alice", "stop", dec!(104), dec!(1))
+            }),
+        ] {
+            clock.advance(5);
+            live.execute(command).unwrap();
+        }
+        let older = live.write_snapshot(&dir).unwrap();
+        clock.advance(5);
+        live.execute(Command::SubmitOrder(limit_request(&btc_usd, "alice", "a-1", dec!(101), dec!(2)))).unwrap();
+        let latest = live.write_snapshot(&dir).unwrap();
+        assert_eq!(latest.sequence, 7);
+        assert_eq!(latest.path, snapshot::snapshot_path(&dir, 7));
+        for command in [
+            Command::SubmitOrder(ask(dec!(103), dec!(1))),
+            Command::SubmitOrder(limit_request(&btc_usd, "alice", "a-2", dec!(103), dec!(1))),
+            Command::CancelOrderByClientId { user_id: "alice".to_string(), client_order_id: "a-2".to_string() },
+        ] {
+            clock.advance(5);
+            let _ = live.execute(command);
+        }
+        assert!(live.get_trades_for_pair(&btc_usd).unwrap().len() >= 3);
+
+        let restore = || {
+            let mut engine = MatchEngine::with_clock(Box::new(clock.clone()));
+            let restored = engine.restore(&dir, Some(path.as_path())).unwrap();
+            (engine, restored)
+        };
+        let (mut restored, summary) = restore();
+        assert_eq!(summary.snapshot, Some(latest.path.clone()));
+        assert_eq!(summary.replayed, live.last_sequence() - 7);
+        assert_eq!(summary.sequence, live.last_sequence());
+        assert_eq!(state_json(&restored, &btc_usd), state_json(&live, &btc_usd));
+        assert_eq!(restored.state_hash(), live.state_hash());
+        restored.check_ledger().unwrap();
+
+        // Both carry on the same way
+        let next = Command::SubmitOrder(limit_request(&btc_usd, "alice", "a-3", dec!(101), dec!(1)));
+        live.execute(next.clone()).unwrap();
+        restored.execute(next).unwrap();
+        assert_eq!(restored.state_hash(), live.state_hash());
+
+        // A corrupted snapshot is refused and the one before it is used instead
+        let mut bytes = std::fs::read(&latest.path).unwrap();
+        let last = bytes.len() - 2;
+        bytes[last] = b' ';
+        std::fs::write(&latest.path, bytes).unwrap();
+        assert!(snapshot::read(&latest.path).is_err());
+        let (restored, summary) = restore();
+        assert_eq!(summary.snapshot, Some(older.path.clone()));
+        assert_eq!(summary.replayed, live.last_sequence() - older.sequence);
+        assert_eq!(restored.state_hash(), live.state_hash());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_follower_replicates_the_leader_over_tcp_and_can_be_promoted() {
+        let clock = ManualClock::new(1_000);
+        let leader = std::sync::Arc::new(std::sync::Mutex::new(MatchEngine::with_clock(Box::new(clock.clone()))));
+        let followers = Followers::default();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        replication::serve(listener, leader.clone(), followers.clone());
+        let btc_usd = TradingPair::new("btc".to_string(), "usd".to_string());
+        let execute = |engine: &std::sync::Arc<std::sync::Mutex<MatchEngine>>, command: Command| {
+            clock.advance(5);
+            engine.lock().unwrap().execute(command)
+        };
+
+        // Commands from before the follower connects reach it as the leader's state
+        for command in [
+            Command::AddMarket { pair: btc_usd.clone(), spec: MarketSpec { lot_size: dec!(1), ..MarketSpec::default() } },
+            Command::SetEnforceBalances { enabled: true },
+            Command::Deposit { user_id: "alice".to_string(), asset: "usd".to_string(), amount: dec!(1000) },
+            Command::Deposit { user_id: "bob".to_string(), asset: "btc".to_string(), amount: dec!(10) },
+            Command::PlaceLimitOrder { pair: btc_usd.clone(), price: dec!(100), order: order_for("bob", 5, BidOrAsk::Ask) },
+        ] {
+            execute(&leader, command).unwrap();
+        }
+        let follower = std::sync::Arc::new(std::sync::Mutex::new(MatchEngine::with_clock(Box::new(clock.clone()))));
+        follower.lock().unwrap().set_following(true);
+        let following = follower.clone();
+        let follower_addr = addr.clone();
+        std::thread::spawn(move || replication::follow(follower_addr, &following));
+
+        // ... and later ones as records, failed ones included
+        for command in [
+            Command::SubmitOrder(limit_request(&btc_usd, "alice", "a-1", dec!(100), dec!(2))),
+            Command::CancelOrder { order_id: 999 },
+            Command::SubmitOrder(limit_request(&btc_usd, "alice", "a-2", dec!(99), dec!(3))),
+        ] {
+            let _ = execute(&leader, command);
+        }
+        let sequence = leader.lock().unwrap().last_sequence();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while followers.lock().unwrap().values().next() != Some(&sequence) {
+            assert!(std::time::Instant::now() < deadline, "the follower never acked command {}", sequence);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let leader_hash = StateHash::of(&leader.lock().unwrap());
+        assert_eq!(StateHash::of(&follower.lock().unwrap()), leader_hash);
+        assert_eq!(replication::request_state_hash(&addr).unwrap(), leader_hash);
+        assert_eq!(follower.lock().unwrap().get_trades_for_pair(&btc_usd).unwrap().len(), 1);
+
+        // A follower takes no commands until promoted
+        let deposit = Command::Deposit { user_id: "carol".to_string(), asset: "usd".to_string(), amount: dec!(5) };
+        assert!(matches!(execute(&follower, deposit.clone()), Err(EngineError::NotLeader)));
+        follower.lock().unwrap().set_following(false);
+        execute(&follower, deposit).unwrap();
+        assert_eq!(follower.lock().unwrap().last_sequence(), sequence + 1);
+    }
+}
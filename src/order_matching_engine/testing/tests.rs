@@ -3,8 +3,9 @@
 
 #[cfg(test)]
 pub mod test {
-    use crate::order_matching_engine::orderbook::{Order, Limit,  OrderBook, BidOrAsk, RestingOrder};
-    use crate::order_matching_engine::engine::{TradingPair, MatchEngine, price_to_tick};
+    use crate::order_matching_engine::orderbook::{Order, Limit,  OrderBook, BidOrAsk, Qty, RestingOrder, PostOnly, SelfTradePrevention, SelfTradeCancel, TimeInForce};
+    use crate::order_matching_engine::engine::{TradingPair, MatchEngine, NewOrderRequest, OrderStatus, OrderType, price_to_tick};
+    use crate::order_matching_engine::clock::{ManualClock, MILLIS_PER_DAY};
     
     use rust_decimal::Decimal;
     use rust_decimal_macros::dec;
@@ -12,22 +13,22 @@ pub mod test {
     #[test]
     fn order_book_test() {
         let mut orderbook: OrderBook = OrderBook::new();
-        orderbook.add_limit_order(price_to_tick(dec!(500.0)), Order::new(10.0, BidOrAsk::Ask)) ;
-        orderbook.add_limit_order(price_to_tick(dec!(400.0)), Order::new(10.0, BidOrAsk::Ask)) ;
-        orderbook.add_limit_order(price_to_tick(dec!(200.0)), Order::new(10.0, BidOrAsk::Ask)) ;
-        orderbook.add_limit_order(price_to_tick(dec!(300.0)), Order::new(10.0, BidOrAsk::Ask)) ;
+        orderbook.add_limit_order(price_to_tick(dec!(500.0)), Order::new(100, BidOrAsk::Ask)) ;
+        orderbook.add_limit_order(price_to_tick(dec!(400.0)), Order::new(100, BidOrAsk::Ask)) ;
+        orderbook.add_limit_order(price_to_tick(dec!(200.0)), Order::new(100, BidOrAsk::Ask)) ;
+        orderbook.add_limit_order(price_to_tick(dec!(300.0)), Order::new(100, BidOrAsk::Ask)) ;
 
-        let mut market_order: Order = Order::new(12.1, BidOrAsk::Bid);
+        let mut market_order: Order = Order::new(121, BidOrAsk::Bid);
         orderbook.fill_order_book(&mut market_order);
-        orderbook.add_limit_order(price_to_tick(dec!(200.0)), Order::new(10.0, BidOrAsk::Bid)) ;
+        orderbook.add_limit_order(price_to_tick(dec!(200.0)), Order::new(100, BidOrAsk::Bid)) ;
 
-        orderbook.add_limit_order(price_to_tick(dec!(10.0)), Order::new(10.0, BidOrAsk::Bid)) ;
-        let mut market_order2: Order = Order::new(12.1, BidOrAsk::Ask);
+        orderbook.add_limit_order(price_to_tick(dec!(10.0)), Order::new(100, BidOrAsk::Bid)) ;
+        let mut market_order2: Order = Order::new(121, BidOrAsk::Ask);
         orderbook.fill_order_book(&mut market_order2);
         println!("{:?}", orderbook);
         
-        assert_eq!(orderbook.ask_capacity(), 27.9);
-        assert_eq!(orderbook.bid_capacity(), 7.9);
+        assert_eq!(orderbook.ask_capacity(), 279);
+        assert_eq!(orderbook.bid_capacity(), 79);
 
     }
 
@@ -36,11 +37,11 @@ pub mod test {
     fn total_volume_test2() {
         let price: Decimal = dec!(1000.0);
         let mut limit: Limit = Limit::new(price_to_tick(price));
-        let buy_limit_order1: RestingOrder = RestingOrder::new(1, 50.0);
-        let buy_limit_order2: RestingOrder = RestingOrder::new(2, 48.0);
+        let buy_limit_order1: RestingOrder = RestingOrder::new(1, 50);
+        let buy_limit_order2: RestingOrder = RestingOrder::new(2, 48);
         limit.add_order(buy_limit_order1);
         limit.add_order(buy_limit_order2);
-        assert_eq!(limit.total_volume(), 98.0);
+        assert_eq!(limit.total_volume(), 98);
        
     }
 
@@ -48,16 +49,16 @@ pub mod test {
     fn total_volume_test() {
         let price: Decimal = dec!(1000.0);
         let mut limit: Limit = Limit::new(price_to_tick(price));
-        let buy_limit_order1: RestingOrder = RestingOrder::new(1, 50.0);
-        let buy_limit_order2: RestingOrder = RestingOrder::new(2, 48.0);
+        let buy_limit_order1: RestingOrder = RestingOrder::new(1, 50);
+        let buy_limit_order2: RestingOrder = RestingOrder::new(2, 48);
 
         let mut  market_sell_order: Order =
-         Order::new( 51.0, BidOrAsk::Ask);
+         Order::new( 51, BidOrAsk::Ask);
 
         limit.add_order(buy_limit_order1);
         limit.add_order(buy_limit_order2);
         limit.fill_order(&mut market_sell_order, &mut |_order_id, _qty| {});
-        assert_eq!(limit.total_volume(), 98.0 - 51.0);
+        assert_eq!(limit.total_volume(), 98 - 51);
        
     }
 
@@ -65,11 +66,11 @@ pub mod test {
     fn total_volume_test3() {
         let price: Decimal = dec!(1000.0);
         let mut limit: Limit = Limit::new(price_to_tick(price));
-        let buy_limit_order1: RestingOrder = RestingOrder::new(1, 50.0);
-        let buy_limit_order2: RestingOrder = RestingOrder::new(2, 48.0);
+        let buy_limit_order1: RestingOrder = RestingOrder::new(1, 50);
+        let buy_limit_order2: RestingOrder = RestingOrder::new(2, 48);
 
         let mut  market_sell_order: Order =
-         Order::new( 90.0, BidOrAsk::Ask);
+         Order::new( 90, BidOrAsk::Ask);
 
         limit.add_order(buy_limit_order1);
         limit.add_order(buy_limit_order2);
@@ -82,11 +83,11 @@ pub mod test {
         let price: Decimal = dec!(1000.0);
         let mut limit: Limit = Limit::new(price_to_tick(price)) ;
     
-        let buy_limit_order1: RestingOrder = RestingOrder::new(1, 50.0);
-        let buy_limit_order2: RestingOrder = RestingOrder::new(2, 48.0);
+        let buy_limit_order1: RestingOrder = RestingOrder::new(1, 50);
+        let buy_limit_order2: RestingOrder = RestingOrder::new(2, 48);
         
         let mut  market_sell_order: Order =
-         Order::new( 51.0, BidOrAsk::Ask);
+         Order::new( 51, BidOrAsk::Ask);
         limit.add_order(buy_limit_order1);
         limit.add_order(buy_limit_order2);
 
@@ -102,10 +103,10 @@ pub mod test {
         let btc_usd = TradingPair::new(String::from("btc"), String::from("usd"));
         let _btc_eth = TradingPair::new(String::from("btc"), String::from("eth"));
         engine.add_new_market(btc_usd.clone());
-        let order: Order = Order::new(10.4, BidOrAsk::Ask);
+        let order: Order = Order::new(104, BidOrAsk::Ask);
         let _ = engine.place_limit_order(&btc_usd, dec!(10.3), order);
 
-        let order2: Order = Order::new(10.5, BidOrAsk::Bid);
+        let order2: Order = Order::new(105, BidOrAsk::Bid);
         println!("{:?}",engine.place_limit_order(&btc_usd, dec!(10.5), order2));
         engine.get_orderbooks();
         println!("{:?}",engine.get_limits_for_a_pair(&btc_usd));
@@ -122,18 +123,18 @@ pub mod test {
         let btc_usd = TradingPair::new(String::from("btc"), String::from("usd"));
         let _btc_eth = TradingPair::new(String::from("btc"), String::from("eth"));
         engine.add_new_market(btc_usd.clone());
-        let order: Order = Order::new(10.4, BidOrAsk::Ask);
+        let order: Order = Order::new(104, BidOrAsk::Ask);
         let _ = engine.place_limit_order(&btc_usd, dec!(10.3), order);
 
-        let order2: Order = Order::new(10.5, BidOrAsk::Bid);
+        let order2: Order = Order::new(105, BidOrAsk::Bid);
         println!("{:?}",engine.place_limit_order(&btc_usd, dec!(10.2), order2));
 
-        let mut market_order = Order::new(10.5, BidOrAsk::Ask);
-        let mut market_order2 = Order::new(10.4, BidOrAsk::Bid);
+        let mut market_order = Order::new(105, BidOrAsk::Ask);
+        let mut market_order2 = Order::new(104, BidOrAsk::Bid);
         let _ = engine.fill_market_order(&btc_usd ,&mut market_order);
         let _ = engine.fill_market_order(&btc_usd ,&mut market_order2);
 
-        assert_eq!(engine.get_limits_for_a_pair(&btc_usd).unwrap().bid_capacity(), 0.0);  
+        assert_eq!(engine.get_limits_for_a_pair(&btc_usd).unwrap().bid_capacity(), 0);  
 
     
     }
@@ -148,14 +149,14 @@ pub mod test {
 
         // Add 100 sell orders at the same price level
         for i in 0..100 {
-            let order = Order::new(1.0, BidOrAsk::Ask);
+            let order = Order::new(1, BidOrAsk::Ask);
             let result = engine.place_limit_order(&btc_usd, dec!(100.0), order);
             assert!(result.is_ok(), "Failed to place order {}", i);
         }
 
         // Verify total capacity (Ask orders now correctly track ask_capacity)
         let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
-        assert_eq!(orderbook.ask_capacity(), 100.0);
+        assert_eq!(orderbook.ask_capacity(), 100);
     }
 
     #[test]
@@ -166,7 +167,7 @@ pub mod test {
 
         // Add 50 sell orders at different price levels (100-149)
         for i in 0..50 {
-            let order = Order::new(10.0, BidOrAsk::Ask);
+            let order = Order::new(10, BidOrAsk::Ask);
             let price = dec!(100) + Decimal::from(i);
             let result = engine.place_limit_order(&btc_usd, price, order);
             assert!(result.is_ok(), "Failed to place ask order at price {}", price);
@@ -174,7 +175,7 @@ pub mod test {
 
         // Add 50 buy orders at different price levels (99-50)
         for i in 0..50 {
-            let order = Order::new(10.0, BidOrAsk::Bid);
+            let order = Order::new(10, BidOrAsk::Bid);
             let price = dec!(99) - Decimal::from(i);
             let result = engine.place_limit_order(&btc_usd, price, order);
             assert!(result.is_ok(), "Failed to place bid order at price {}", price);
@@ -182,8 +183,8 @@ pub mod test {
 
         // Verify capacities
         let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
-        assert_eq!(orderbook.ask_capacity(), 500.0);
-        assert_eq!(orderbook.bid_capacity(), 500.0);
+        assert_eq!(orderbook.ask_capacity(), 500);
+        assert_eq!(orderbook.bid_capacity(), 500);
     }
 
     #[test]
@@ -193,23 +194,23 @@ pub mod test {
         engine.add_new_market(btc_usd.clone());
 
         // Add sell orders with increasing sizes: 1.0, 2.0, 3.0, ..., 100.0
-        let mut total_ask = 0.0;
+        let mut total_ask = 0;
         for i in 1..=100 {
-            let order = Order::new(i as f64, BidOrAsk::Ask);
-            let price = dec!(100.0) + Decimal::from(i);
+            let order = Order::new(i, BidOrAsk::Ask);
+            let price = dec!(200.0) + Decimal::from(i);
             let result = engine.place_limit_order(&btc_usd, price, order);
             assert!(result.is_ok(), "Failed to place ask order");
-            total_ask += i as f64;
+            total_ask += i;
         }
 
         // Add buy orders with decreasing sizes: 100.0, 99.0, ..., 1.0
-        let mut total_bid = 0.0;
+        let mut total_bid = 0;
         for i in (1..=100).rev() {
-            let order = Order::new(i as f64, BidOrAsk::Bid);
-            let price = dec!(99.0) - Decimal::from(101 - i);
+            let order = Order::new(i, BidOrAsk::Bid);
+            let price = dec!(199.0) - Decimal::from(101 - i);
             let result = engine.place_limit_order(&btc_usd, price, order);
             assert!(result.is_ok(), "Failed to place bid order");
-            total_bid += i as f64;
+            total_bid += i;
         }
 
         let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
@@ -226,7 +227,7 @@ pub mod test {
         // Add 500 sell orders in price range 2000-2499
         for price_int in 2000..2500 {
             let price = Decimal::from(price_int);
-            let sell_order = Order::new(5.0, BidOrAsk::Ask);
+            let sell_order = Order::new(5, BidOrAsk::Ask);
             let result1 = engine.place_limit_order(&btc_usd, price, sell_order);
             assert!(result1.is_ok());
         }
@@ -234,15 +235,15 @@ pub mod test {
         // Add 500 buy orders in price range 1000-1499 (below sell orders)
         for price_int in 1000..1500 {
             let price = Decimal::from(price_int);
-            let buy_order = Order::new(5.0, BidOrAsk::Bid);
+            let buy_order = Order::new(5, BidOrAsk::Bid);
             let result = engine.place_limit_order(&btc_usd, price, buy_order);
             assert!(result.is_ok());
         }
 
         let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
         // Verify that orders were placed
-        assert!(orderbook.ask_capacity() > 0.0);
-        assert!(orderbook.bid_capacity() > 0.0);
+        assert!(orderbook.ask_capacity() > 0);
+        assert!(orderbook.bid_capacity() > 0);
     }
 
     // ========== ROBUSTNESS TESTS: MANY MARKET ORDERS ==========
@@ -254,19 +255,19 @@ pub mod test {
         engine.add_new_market(btc_usd.clone());
 
         // Add a large sell order
-        let large_sell = Order::new(1000.0, BidOrAsk::Ask);
+        let large_sell = Order::new(1000, BidOrAsk::Ask);
         let _ = engine.place_limit_order(&btc_usd, dec!(100.0), large_sell);
 
         // Fill with many small market buy orders
         for i in 0..100 {
-            let mut market_order = Order::new(10.0, BidOrAsk::Bid);
+            let mut market_order = Order::new(10, BidOrAsk::Bid);
             let result = engine.fill_market_order(&btc_usd, &mut market_order);
             assert!(result.is_ok(), "Failed to fill market order {}", i);
         }
 
         // After 100 * 10 = 1000 units filled, the limit should be completely consumed
         let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
-        assert_eq!(orderbook.ask_capacity(), 0.0);
+        assert_eq!(orderbook.ask_capacity(), 0);
     }
 
     #[test]
@@ -277,28 +278,28 @@ pub mod test {
 
         // Add initial liquidity
         for i in 0..50 {
-            let buy_order = Order::new(10.0, BidOrAsk::Bid);
+            let buy_order = Order::new(10, BidOrAsk::Bid);
             let _ = engine.place_limit_order(&btc_usd, dec!(100) - Decimal::from(i), buy_order);
 
-            let sell_order = Order::new(10.0, BidOrAsk::Ask);
+            let sell_order = Order::new(10, BidOrAsk::Ask);
             let _ = engine.place_limit_order(&btc_usd, dec!(101) + Decimal::from(i), sell_order);
         }
 
         // Execute alternating market orders
         for i in 0..50 {
-            let mut market_buy = Order::new(5.0, BidOrAsk::Bid);
+            let mut market_buy = Order::new(5, BidOrAsk::Bid);
             let result1 = engine.fill_market_order(&btc_usd, &mut market_buy);
             assert!(result1.is_ok(), "Market buy failed at iteration {}", i);
 
-            let mut market_sell = Order::new(5.0, BidOrAsk::Ask);
+            let mut market_sell = Order::new(5, BidOrAsk::Ask);
             let result2 = engine.fill_market_order(&btc_usd, &mut market_sell);
             assert!(result2.is_ok(), "Market sell failed at iteration {}", i);
         }
 
-        // Verify order book is reduced but still has some liquidity
+        // Verify order book is reduced by exactly what was traded: 500 - 50 * 5 on each side
         let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
-        assert!(orderbook.bid_capacity() >= 0.0);
-        assert!(orderbook.ask_capacity() >= 0.0);
+        assert_eq!(orderbook.bid_capacity(), 250);
+        assert_eq!(orderbook.ask_capacity(), 250);
     }
 
     #[test]
@@ -309,20 +310,20 @@ pub mod test {
 
         // Add liquidity: 10 levels with 100 units each
         for level in 0..10 {
-            let buy_order = Order::new(100.0, BidOrAsk::Bid);
+            let buy_order = Order::new(100, BidOrAsk::Bid);
             let _ = engine.place_limit_order(&btc_usd, dec!(100) - Decimal::from(level), buy_order);
         }
 
         // Execute 5 large market orders that drain the liquidity
         for i in 0..5 {
-            let mut market_order = Order::new(200.0, BidOrAsk::Ask);
+            let mut market_order = Order::new(200, BidOrAsk::Ask);
             let result = engine.fill_market_order(&btc_usd, &mut market_order);
             assert!(result.is_ok(), "Market order {} failed", i);
         }
 
         // Should have drained 1000 units
         let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
-        assert_eq!(orderbook.bid_capacity(), 0.0);
+        assert_eq!(orderbook.bid_capacity(), 0);
     }
 
     #[test]
@@ -334,26 +335,26 @@ pub mod test {
         // Alternating pattern: add limit order, execute market order
         for i in 0..100 {
             // Add a limit sell order
-            let sell_order = Order::new(50.0, BidOrAsk::Ask);
+            let sell_order = Order::new(50, BidOrAsk::Ask);
             let sell_result = engine.place_limit_order(
                 &btc_usd,
-                dec!(100) + Decimal::from(i),
+                dec!(200) + Decimal::from(i),
                 sell_order
             );
             assert!(sell_result.is_ok());
 
             // Add a limit buy order
-            let buy_order = Order::new(50.0, BidOrAsk::Bid);
+            let buy_order = Order::new(50, BidOrAsk::Bid);
             let buy_result = engine.place_limit_order(
                 &btc_usd,
-                dec!(99) - Decimal::from(i),
+                dec!(199) - Decimal::from(i),
                 buy_order
             );
             assert!(buy_result.is_ok());
 
             // Execute a market order every 10 iterations
             if i % 10 == 0 && i > 0 {
-                let mut market_order = Order::new(25.0, BidOrAsk::Bid);
+                let mut market_order = Order::new(25, BidOrAsk::Bid);
                 let market_result = engine.fill_market_order(&btc_usd, &mut market_order);
                 assert!(market_result.is_ok());
             }
@@ -361,8 +362,8 @@ pub mod test {
 
         let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
         // Verify we still have a healthy order book
-        assert!(orderbook.bid_capacity() > 0.0);
-        assert!(orderbook.ask_capacity() > 0.0);
+        assert!(orderbook.bid_capacity() > 0);
+        assert!(orderbook.ask_capacity() > 0);
     }
 
     #[test]
@@ -371,35 +372,35 @@ pub mod test {
         let btc_usd = TradingPair::new(String::from("btc"), String::from("usd"));
         engine.add_new_market(btc_usd.clone());
 
-        let mut total_buy_volume = 0.0;
-        let mut total_sell_volume = 0.0;
-        let mut executed_buy_volume = 0.0;
-        let mut executed_sell_volume = 0.0;
+        let mut total_buy_volume = 0;
+        let mut total_sell_volume = 0;
+        let mut executed_buy_volume = 0;
+        let mut executed_sell_volume = 0;
 
         // Phase 1: Add many limit orders
         for i in 0..200 {
             if i % 2 == 0 {
-                let buy_order = Order::new(25.0, BidOrAsk::Bid);
+                let buy_order = Order::new(25, BidOrAsk::Bid);
                 let price = dec!(100) - Decimal::from(i / 2);
                 engine.place_limit_order(&btc_usd, price, buy_order).ok();
-                total_buy_volume += 25.0;
+                total_buy_volume += 25;
             } else {
-                let sell_order = Order::new(25.0, BidOrAsk::Ask);
+                let sell_order = Order::new(25, BidOrAsk::Ask);
                 let price = dec!(100) + Decimal::from(i / 2);
                 engine.place_limit_order(&btc_usd, price, sell_order).ok();
-                total_sell_volume += 25.0;
+                total_sell_volume += 25;
             }
         }
 
         // Phase 2: Execute market orders
         for i in 0..50 {
             if i % 2 == 0 {
-                let mut market_buy = Order::new(15.0, BidOrAsk::Bid);
+                let mut market_buy = Order::new(15, BidOrAsk::Bid);
                 if engine.fill_market_order(&btc_usd, &mut market_buy).is_ok() {
                     executed_buy_volume += market_buy.size();
                 }
             } else {
-                let mut market_sell = Order::new(15.0, BidOrAsk::Ask);
+                let mut market_sell = Order::new(15, BidOrAsk::Ask);
                 if engine.fill_market_order(&btc_usd, &mut market_sell).is_ok() {
                     executed_sell_volume += market_sell.size();
                 }
@@ -411,8 +412,8 @@ pub mod test {
         let remaining_sell = orderbook.ask_capacity();
 
         // Verify conservation: total volume = executed + remaining
-        assert!(executed_buy_volume + remaining_buy <= total_buy_volume + 0.1); // Small epsilon for floating point
-        assert!(executed_sell_volume + remaining_sell <= total_sell_volume + 0.1);
+        assert!(executed_buy_volume + remaining_buy <= total_buy_volume);
+        assert!(executed_sell_volume + remaining_sell <= total_sell_volume);
     }
 
     #[test]
@@ -431,10 +432,10 @@ pub mod test {
         // Add orders to each pair
         for pair in [&btc_usd, &eth_usd, &btc_eth] {
             for i in 0..50 {
-                let buy_order = Order::new(10.0, BidOrAsk::Bid);
+                let buy_order = Order::new(10, BidOrAsk::Bid);
                 engine.place_limit_order(pair, dec!(100) - Decimal::from(i), buy_order).ok();
 
-                let sell_order = Order::new(10.0, BidOrAsk::Ask);
+                let sell_order = Order::new(10, BidOrAsk::Ask);
                 engine.place_limit_order(pair, dec!(100) + Decimal::from(i), sell_order).ok();
             }
         }
@@ -442,7 +443,7 @@ pub mod test {
         // Execute market orders on each pair
         for pair in [&btc_usd, &eth_usd, &btc_eth] {
             for _ in 0..25 {
-                let mut market_order = Order::new(5.0, BidOrAsk::Bid);
+                let mut market_order = Order::new(5, BidOrAsk::Bid);
                 engine.fill_market_order(pair, &mut market_order).ok();
             }
         }
@@ -464,20 +465,20 @@ pub mod test {
         // price = 11.3 with amount 40.0
         // price = 12.0 with amount 50.0
         // price = 11.0 with amount 1.0
-        orderbook.add_limit_order(price_to_tick(dec!(11.3)), Order::new(40.0, BidOrAsk::Ask));
-        orderbook.add_limit_order(price_to_tick(dec!(12.0)), Order::new(50.0, BidOrAsk::Ask));
-        orderbook.add_limit_order(price_to_tick(dec!(11.0)), Order::new(1.0, BidOrAsk::Ask));
+        orderbook.add_limit_order(price_to_tick(dec!(11.3)), Order::new(40, BidOrAsk::Ask));
+        orderbook.add_limit_order(price_to_tick(dec!(12.0)), Order::new(50, BidOrAsk::Ask));
+        orderbook.add_limit_order(price_to_tick(dec!(11.0)), Order::new(1, BidOrAsk::Ask));
 
         // Setup initial bids (buyers):
         // price = 10.0 with amount 100.0
-        orderbook.add_limit_order(price_to_tick(dec!(10.0)), Order::new(100.0, BidOrAsk::Bid));
+        orderbook.add_limit_order(price_to_tick(dec!(10.0)), Order::new(100, BidOrAsk::Bid));
 
         // Before: ask_capacity = 40 + 50 + 1 = 91.0
-        assert_eq!(orderbook.ask_capacity(), 91.0);
+        assert_eq!(orderbook.ask_capacity(), 91);
 
         // Execute a buy order of 5.0 (should match against the lowest ask prices first)
         // Expected to match: 1.0 from 11.0, then 4.0 from 11.3
-        let mut market_order = Order::new(5.0, BidOrAsk::Bid);
+        let mut market_order = Order::new(5, BidOrAsk::Bid);
         let result = orderbook.fill_order_book(&mut market_order);
 
         println!("Market order result: {}", result);
@@ -488,13 +489,405 @@ pub mod test {
         // - The 40.0 at 11.3 should have 4.0 filled, leaving 36.0
         // - The 50.0 at 12.0 should remain unchanged
         // ask_capacity should now be: 36.0 + 50.0 = 86.0
-        assert_eq!(orderbook.ask_capacity(), 86.0,
+        assert_eq!(orderbook.ask_capacity(), 86,
                    "After matching 5.0 units, ask_capacity should be 86.0 (91.0 - 5.0)");
 
         // Verify the market order is filled
         assert!(market_order.is_filled(), "Market order should be completely filled");
-        assert_eq!(market_order.size(), 0.0, "Market order size should be 0 after filling");
+        assert_eq!(market_order.size(), 0, "Market order size should be 0 after filling");
     }
 
-}
+    // ========== ORDER CANCELLATION ==========
+
+    #[test]
+    fn cancel_resting_order_updates_book_and_snapshot() {
+        let mut engine: MatchEngine = MatchEngine::new();
+        let btc_usd = TradingPair::new(String::from("btc"), String::from("usd"));
+        engine.add_new_market(btc_usd.clone());
+
+        let first = engine.place_limit_order_with_response(&btc_usd, dec!(100.0), Order::new(10, BidOrAsk::Ask)).unwrap();
+        let second = engine.place_limit_order_with_response(&btc_usd, dec!(100.0), Order::new(5, BidOrAsk::Ask)).unwrap();
+        let other_level = engine.place_limit_order_with_response(&btc_usd, dec!(101.0), Order::new(7, BidOrAsk::Ask)).unwrap();
+
+        let response = engine.cancel_order(first.order.id).unwrap();
+        assert!(matches!(response.order.status, OrderStatus::Canceled));
+        assert!(matches!(engine.get_order(first.order.id).unwrap().status, OrderStatus::Canceled));
+
+        let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
+        assert_eq!(orderbook.ask_capacity(), 12);
+        assert_eq!(orderbook.ask_limits()[0].total_volume(), 5);
+
+        // Cancelling the last order on a level removes the level
+        engine.cancel_order(other_level.order.id).unwrap();
+        let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
+        assert_eq!(orderbook.ask_limits().len(), 1);
+        assert_eq!(orderbook.ask_capacity(), 5);
+
+        // The remaining order still matches first
+        let mut market_buy = Order::new(5, BidOrAsk::Bid);
+        engine.fill_market_order(&btc_usd, &mut market_buy).unwrap();
+        assert!(matches!(engine.get_order(second.order.id).unwrap().status, OrderStatus::Filled));
+        assert!(engine.get_limits_for_a_pair(&btc_usd).unwrap().ask_limits().is_empty());
+    }
+
+    #[test]
+    fn cancel_rejects_unknown_and_finished_orders() {
+        let mut engine: MatchEngine = MatchEngine::new();
+        let btc_usd = TradingPair::new(String::from("btc"), String::from("usd"));
+        engine.add_new_market(btc_usd.clone());
+
+        assert!(engine.cancel_order(42).is_err());
+
+        let resting = engine.place_limit_order_with_response(&btc_usd, dec!(99.0), Order::new(3, BidOrAsk::Bid)).unwrap();
+        assert!(engine.cancel_order(resting.order.id).is_ok());
+        assert!(engine.cancel_order(resting.order.id).is_err(), "double cancel must fail");
+        assert_eq!(engine.get_limits_for_a_pair(&btc_usd).unwrap().bid_capacity(), 0);
+
+        let filled = engine.place_limit_order_with_response(&btc_usd, dec!(99.0), Order::new(3, BidOrAsk::Bid)).unwrap();
+        let mut market_sell = Order::new(3, BidOrAsk::Ask);
+        engine.fill_market_order(&btc_usd, &mut market_sell).unwrap();
+        assert!(engine.cancel_order(filled.order.id).is_err(), "filled orders can not be canceled");
+        assert!(engine.cancel_order(market_sell.id()).is_err(), "market orders can not be canceled");
+    }
+
+    #[test]
+    fn cancel_from_middle_of_queue_keeps_time_priority() {
+        let mut orderbook: OrderBook = OrderBook::new();
+        let price = price_to_tick(dec!(100.0));
+        for id in 1..=5 {
+            orderbook.add_limit_order(price, Order::new_with_meta(id, String::from("u"), 4, BidOrAsk::Ask));
+        }
+
+        assert_eq!(orderbook.cancel_order(3), Some(4));
+        assert_eq!(orderbook.cancel_order(3), None);
+        assert_eq!(orderbook.reduce_order(2, 1), Some(3));
+        assert!(orderbook.resting_order(3).is_none());
+        assert_eq!(orderbook.resting_order(2).unwrap().qty(), 3);
+        assert_eq!(orderbook.resting_orders_count(), 4);
+        assert_eq!(orderbook.ask_capacity(), 15);
+
+        let queue: Vec<u64> = orderbook.ask_limits()[0].iter().map(|order| order.id()).collect();
+        assert_eq!(queue, vec![1, 2, 4, 5]);
+
+        // Fills consume in FIFO order and drop consumed orders from the index
+        let mut fills = Vec::new();
+        let mut market_buy = Order::new(8, BidOrAsk::Bid);
+        orderbook.fill_order_book_with_report(&mut market_buy, &mut |fill| fills.push((fill.maker_order_id, fill.qty)));
+        assert_eq!(fills, vec![(1, 4), (2, 3), (4, 1)]);
+        assert!(!orderbook.contains_order(1));
+        assert!(!orderbook.contains_order(2));
+        assert_eq!(orderbook.resting_order(4).unwrap().qty(), 3);
+
+        // New orders reuse freed slots but still join the back of the queue
+        orderbook.add_limit_order(price, Order::new_with_meta(6, String::from("u"), 4, BidOrAsk::Ask));
+        let queue: Vec<u64> = orderbook.ask_limits()[0].iter().map(|order| order.id()).collect();
+        assert_eq!(queue, vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn cancel_leaves_the_rest_of_a_long_queue_in_place() {
+        let mut orderbook: OrderBook = OrderBook::new();
+        let price = price_to_tick(dec!(100.0));
+        for id in 1..=10_000 {
+            orderbook.add_limit_order(price, Order::new_with_meta(id, String::from("u"), 1, BidOrAsk::Ask));
+        }
+        let slots_before: Vec<usize> = (1..=10_000).map(|id| orderbook.order_location(id).unwrap().slot).collect();
+
+        // A cancel is an index lookup and an unlink: no other order moves slot
+        let canceled = [5_000, 1, 10_000, 7_919];
+        let freed: Vec<usize> = canceled.iter().map(|&id| orderbook.order_location(id).unwrap().slot).collect();
+        for &id in &canceled {
+            assert_eq!(orderbook.cancel_order(id), Some(1));
+            assert!(orderbook.order_location(id).is_none());
+        }
+        for id in (1..=10_000u64).filter(|id| !canceled.contains(id)) {
+            assert_eq!(orderbook.order_location(id).unwrap().slot, slots_before[id as usize - 1]);
+        }
+        assert_eq!(orderbook.ask_limits()[0].len(), 9_996);
+
+        // Freed slots are reused instead of growing the level's slab
+        for id in 10_001..=10_004 {
+            orderbook.add_limit_order(price, Order::new_with_meta(id, String::from("u"), 1, BidOrAsk::Ask));
+            assert!(freed.contains(&orderbook.order_location(id).unwrap().slot));
+        }
+        let queue: Vec<u64> = orderbook.ask_limits()[0].iter().map(|order| order.id()).collect();
+        assert_eq!(queue.len(), 10_000);
+        assert_eq!(&queue[queue.len() - 4..], &[10_001, 10_002, 10_003, 10_004]);
+    }
+
+    #[test]
+    fn iceberg_refills_its_peak_at_the_back_of_the_queue() {
+        let mut orderbook: OrderBook = OrderBook::new();
+        let price = price_to_tick(dec!(100.0));
+        let mut iceberg = Order::new_with_meta(1, String::from("whale"), 10, BidOrAsk::Ask);
+        iceberg.set_display_qty(Some(3));
+        orderbook.add_limit_order(price, iceberg);
+        orderbook.add_limit_order(price, Order::new_with_meta(2, String::from("u"), 2, BidOrAsk::Ask));
+
+        let level = &orderbook.ask_limits()[0];
+        assert_eq!((level.total_volume(), level.hidden_volume()), (5, 7));
+        assert_eq!(orderbook.ask_capacity(), 12);
+        let resting = orderbook.resting_order(1).unwrap();
+        assert_eq!((resting.qty(), resting.reserve(), resting.total_qty()), (3, 7, 10));
+
+        // Consuming the peak refills it from the reserve behind order 2
+        let mut fills = Vec::new();
+        let mut market_buy = Order::new(4, BidOrAsk::Bid);
+        orderbook.fill_order_book_with_report(&mut market_buy, &mut |fill| fills.push((fill.maker_order_id, fill.qty)));
+        assert_eq!(fills, vec![(1, 3), (2, 1)]);
+        let queue: Vec<u64> = orderbook.ask_limits()[0].iter().map(|order| order.id()).collect();
+        assert_eq!(queue, vec![2, 1]);
+        let resting = orderbook.resting_order(1).unwrap();
+        assert_eq!((resting.qty(), resting.reserve()), (3, 4));
+
+        // The public view only shows the peaks
+        let view = orderbook.public_view();
+        assert_eq!(view.ask_capacity(), 4);
+        assert_eq!(view.resting_order(1).unwrap().total_qty(), 3);
+        let json = serde_json::to_string(&view).unwrap();
+        assert!(!json.contains("reserve"), "{}", json);
+        assert!(!json.contains("owner") && !json.contains("whale"), "{}", json);
+
+        // A large taker works through every refill
+        let mut fills = Vec::new();
+        let mut market_buy = Order::new(8, BidOrAsk::Bid);
+        orderbook.fill_order_book_with_report(&mut market_buy, &mut |fill| fills.push((fill.maker_order_id, fill.qty)));
+        assert_eq!(fills, vec![(2, 1), (1, 3), (1, 3), (1, 1)]);
+        assert_eq!(orderbook.ask_capacity(), 0);
+        assert!(!orderbook.contains_order(1));
+    }
+
+    #[test]
+    fn order_book_serde_round_trip_rebuilds_index() {
+        let mut orderbook: OrderBook = OrderBook::new();
+        orderbook.add_limit_order(price_to_tick(dec!(101.0)), Order::new_with_meta(1, String::from("u"), 2, BidOrAsk::Ask));
+        orderbook.add_limit_order(price_to_tick(dec!(99.0)), Order::new_with_meta(2, String::from("u"), 3, BidOrAsk::Bid));
+        orderbook.add_limit_order(price_to_tick(dec!(99.0)), Order::new_with_meta(3, String::from("u"), 4, BidOrAsk::Bid));
+        orderbook.cancel_order(2);
+
+        let json = serde_json::to_string(&orderbook).unwrap();
+        let mut restored: OrderBook = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(restored.resting_orders_count(), 2);
+        assert_eq!(restored.bid_capacity(), 4);
+        assert_eq!(restored.cancel_order(3), Some(4));
+        assert_eq!(restored.cancel_order(1), Some(2));
+        assert!(restored.bid_limits().is_empty());
+        assert!(restored.ask_limits().is_empty());
+    }
+
+    // ========== TIME IN FORCE ==========
 
+    fn order_with_tif(size: Qty, bid_or_ask: BidOrAsk, time_in_force: TimeInForce) -> Order {
+        let mut order = Order::new(size, bid_or_ask);
+        order.set_time_in_force(time_in_force);
+        order
+    }
+
+    #[test]
+    fn immediate_or_cancel_limit_never_rests() {
+        let mut engine: MatchEngine = MatchEngine::new();
+        let btc_usd = TradingPair::new(String::from("btc"), String::from("usd"));
+        engine.add_new_market(btc_usd.clone());
+        let _ = engine.place_limit_order(&btc_usd, dec!(100.0), Order::new(4, BidOrAsk::Ask));
+        let _ = engine.place_limit_order(&btc_usd, dec!(102.0), Order::new(4, BidOrAsk::Ask));
+
+        let response = engine
+            .place_limit_order_with_response(&btc_usd, dec!(101.0), order_with_tif(10, BidOrAsk::Bid, TimeInForce::ImmediateOrCancel))
+            .unwrap();
+        assert!(matches!(response.order.status, OrderStatus::Canceled));
+        assert_eq!(response.order.filled_size, 4);
+        assert_eq!(response.order.canceled_size, 6);
+
+        let orderbook = engine.get_limits_for_a_pair(&btc_usd).unwrap();
+        assert!(orderbook.bid_limits().is_empty());
+        assert_eq!(orderbook.ask_capacity(), 4);
+    }
+
+    #[test]
+    fn fill_or_kill_limit_only_executes_in_full() {
+        let mut engine: MatchEngine = MatchEngine::new();
+        let btc_usd = TradingPair::new(String::from("btc"), String::from("usd"));
+        engine.add_new_market(btc_usd.clone());
+        let _ = engine.place_limit_order(&btc_usd, dec!(100.0), Order::new(4, BidOrAsk::Ask));
+        let _ = engine.place_limit_order(&btc_usd, dec!(102.0), Order::new(4, BidOrAsk::Ask));
+
+        // Only 4.0 is available at or below 101
+        let response = engine
+            .place_limit_order_with_response(&btc_usd, dec!(101.0), order_with_tif(6, BidOrAsk::Bid, TimeInForce::FillOrKill))
+            .unwrap();
+        assert!(matches!(response.order.status, OrderStatus::Rejected));
+        assert_eq!(response.order.filled_size, 0);
+        assert_eq!(engine.get_limits_for_a_pair(&btc_usd).unwrap().ask_capacity(), 8);
+
+        let response = engine
+            .place_limit_order_with_response(&btc_usd, dec!(102.0), order_with_tif(6, BidOrAsk::Bid, TimeInForce::FillOrKill))
+            .unwrap();
+        assert!(matches!(response.order.status, OrderStatus::Filled));
+        assert_eq!(engine.get_limits_for_a_pair(&btc_usd).unwrap().ask_capacity(), 2);
+    }
+
+    #[test]
+    fn day_and_good_till_date_orders_expire_on_the_engine_clock() {
+        let start = 10 * MILLIS_PER_DAY + 1_000;
+        let clock = ManualClock::new(start);
+        let mut engine: MatchEngine = MatchEngine::with_clock(Box::new(clock.clone()));
+        let btc_usd = TradingPair::new(String::from("btc"), String::from("usd"));
+        engine.add_new_market(btc_usd.clone());
+
+        let day = engine
+            .place_limit_order_with_response(&btc_usd, dec!(99.0), order_with_tif(1, BidOrAsk::Bid, TimeInForce::Day))
+            .unwrap();
+        assert_eq!(day.order.expires_at, Some(11 * MILLIS_PER_DAY));
+
+        let gtd = engine
+            .place_limit_order_with_response(&btc_usd, dec!(101.0), order_with_tif(2, BidOrAsk::Ask, TimeInForce::GoodTillDate(start + 5_000)))
+            .unwrap();
+        let gtc = engine
+            .place_limit_order_with_response(&btc_usd, dec!(98.0), Order::new(3, BidOrAsk::Bid))
+            .unwrap();
+        assert!(engine
+            .place_limit_order(&btc_usd, dec!(101.0), order_with_tif(2, BidOrAsk::Ask, TimeInForce::GoodTillDate(start)))
+            .is_err(), "a deadline in the past is rejected");
+
+        clock.advance(4_999);
+        assert!(engine.expire_orders().is_empty());
+
+        clock.advance(1);
+        let expired = engine.expire_orders();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, gtd.order.id);
+        assert!(matches!(engine.get_order(gtd.order.id).unwrap().status, OrderStatus::Expired));
+        assert_eq!(engine.get_order(gtd.order.id).unwrap().canceled_size, 2);
+        assert_eq!(engine.get_limits_for_a_pair(&btc_usd).unwrap().ask_capacity(), 0);
+
+        // Past midnight the DAY order is gone even without an explicit sweep: new orders sweep first
+        clock.set(11 * MILLIS_PER_DAY);
+        let mut market_sell = Order::new(1, BidOrAsk::Ask);
+        let response = engine.fill_market_order_with_response(&btc_usd, &mut market_sell).unwrap();
+        assert!(matches!(engine.get_order(day.order.id).unwrap().status, OrderStatus::Expired));
+        assert!(matches!(response.order.status, OrderStatus::Filled));
+        assert!(matches!(engine.get_order(gtc.order.id).unwrap().status, OrderStatus::PartiallyFilled));
+        assert_eq!(engine.get_limits_for_a_pair(&btc_usd).unwrap().bid_capacity(), 2);
+    }
+
+    #[test]
+    fn canceled_or_filled_orders_do_not_expire() {
+        let clock = ManualClock::new(1_000);
+        let mut engine: MatchEngine = MatchEngine::with_clock(Box::new(clock.clone()));
+        let btc_usd = TradingPair::new(String::from("btc"), String::from("usd"));
+        engine.add_new_market(btc_usd.clone());
+
+        let canceled = engine
+            .place_limit_order_with_response(&btc_usd, dec!(99.0), order_with_tif(1, BidOrAsk::Bid, TimeInForce::GoodTillDate(2_000)))
+            .unwrap();
+        let filled = engine
+            .place_limit_order_with_response(&btc_usd, dec!(99.0), order_with_tif(1, BidOrAsk::Bid, TimeInForce::GoodTillDate(2_000)))
+            .unwrap();
+        engine.cancel_order(canceled.order.id).unwrap();
+        let mut market_sell = Order::new(1, BidOrAsk::Ask);
+        engine.fill_market_order(&btc_usd, &mut market_sell).unwrap();
+
+        clock.set(5_000);
+        assert!(engine.expire_orders().is_empty());
+        assert!(matches!(engine.get_order(canceled.order.id).unwrap().status, OrderStatus::Canceled));
+        assert!(matches!(engine.get_order(filled.order.id).unwrap().status, OrderStatus::Filled));
+    }
+
+    // ========== JSON ORDER ENTRY ==========
+
+    #[test]
+    fn json_order_requests_are_placed_in_base_units() {
+        let mut engine: MatchEngine = MatchEngine::new();
+        let btc_usd = TradingPair::new(String::from("btc"), String::from("usd"));
+        engine.add_new_market(btc_usd.clone());
+
+        let sell: NewOrderRequest = serde_json::from_str(
+            r#"{"market": {"base": "btc", "quote": "usd"}, "user_id": "alice", "side": "sell", "type": "Limit",
+                "price": "100.5", "size": "0.3", "client_order_id": "alice-1"}"#,
+        )
+        .unwrap();
+        let response = engine.submit_order(&sell).unwrap();
+        assert!(matches!(response.order.status, OrderStatus::Open));
+        assert_eq!(response.order.price, Some(price_to_tick(dec!(100.5))));
+        assert_eq!(response.order.original_size, 3_000);
+        assert_eq!(response.order.client_order_id.as_deref(), Some("alice-1"));
+
+        let buy: NewOrderRequest = serde_json::from_str(
+            r#"{"market": {"base": "btc", "quote": "usd"}, "user_id": "bob", "side": "Bid", "type": "Market",
+                "size": "0.5", "time_in_force": "FillOrKill"}"#,
+        )
+        .unwrap();
+        let response = engine.submit_order(&buy).unwrap();
+        assert!(matches!(response.order.status, OrderStatus::Rejected), "fill-or-kill applies to market requests");
+        assert_eq!(response.order.client_order_id, None);
+        assert_eq!(engine.get_limits_for_a_pair(&btc_usd).unwrap().ask_capacity(), 3_000);
+    }
+
+    #[test]
+    fn invalid_json_order_requests_are_refused() {
+        let mut engine: MatchEngine = MatchEngine::new();
+        let btc_usd = TradingPair::new(String::from("btc"), String::from("usd"));
+        engine.add_new_market(btc_usd.clone());
+        let request = NewOrderRequest {
+            market: btc_usd.clone(),
+            user_id: String::from("alice"),
+            side: BidOrAsk::Bid,
+            order_type: OrderType::Limit,
+            price: None,
+            stop_price: None,
+            trailing_offset: None,
+            display_size: None,
+            size: dec!(1),
+            time_in_force: TimeInForce::default(),
+            client_order_id: None,
+            self_trade_prevention: None,
+            post_only: PostOnly::Off,
+        };
+
+        assert_eq!(engine.submit_order(&request).unwrap_err().code(), "invalid_request");
+        let market_with_price = NewOrderRequest { order_type: OrderType::Market, price: Some(dec!(1)), ..request.clone() };
+        assert_eq!(engine.submit_order(&market_with_price).unwrap_err().code(), "invalid_request");
+        let stop_without_stop_price = NewOrderRequest { order_type: OrderType::StopMarket, ..request.clone() };
+        assert_eq!(engine.submit_order(&stop_without_stop_price).unwrap_err().code(), "invalid_request");
+        let limit_with_stop_price = NewOrderRequest { price: Some(dec!(1)), stop_price: Some(dec!(1)), ..request.clone() };
+        assert_eq!(engine.submit_order(&limit_with_stop_price).unwrap_err().code(), "invalid_request");
+        let off_lot = NewOrderRequest { price: Some(dec!(1)), size: dec!(0.00001), ..request.clone() };
+        assert_eq!(engine.submit_order(&off_lot).unwrap_err().code(), "invalid_size");
+        // Symbols with underscores are fine, they are never parsed out of a path
+        let unknown = NewOrderRequest { market: TradingPair::new(String::from("usd_c"), String::from("usd")), ..request };
+        assert_eq!(engine.submit_order(&unknown).unwrap_err().code(), "unknown_market");
+        assert_eq!(engine.get_orders_for_user("alice").len(), 0);
+    }
+
+    #[test]
+    fn self_trade_prevention_is_reported_by_the_book() {
+        let mut orderbook = OrderBook::new();
+        let alice_ask = |id, qty| Order::new_with_meta(id, String::from("alice"), qty, BidOrAsk::Ask);
+        orderbook.add_limit_order(100, alice_ask(1, 5));
+        orderbook.add_limit_order(100, Order::new_with_meta(2, String::from("bob"), 4, BidOrAsk::Ask));
+        orderbook.add_limit_order(101, alice_ask(3, 6));
+
+        let mut buy = Order::new_with_meta(4, String::from("alice"), 10, BidOrAsk::Bid);
+        buy.set_self_trade_prevention(Some(SelfTradePrevention::DecrementAndCancel));
+        let mut fills = Vec::new();
+        let report = orderbook.place_limit_order(101, &mut buy, &mut |fill| fills.push(fill));
+
+        // 5 decremented against order 1, 4 traded with bob, the last 1 decremented against order 3
+        assert_eq!(fills.len(), 1);
+        assert_eq!((fills[0].maker_order_id, fills[0].qty), (2, 4));
+        assert_eq!(report.self_trade_canceled_qty, 6);
+        assert!(!report.fully_filled);
+        assert_eq!(
+            report.self_trade_cancels,
+            vec![
+                SelfTradeCancel { order_id: 1, canceled_qty: 5, resting_qty: 0 },
+                SelfTradeCancel { order_id: 3, canceled_qty: 1, resting_qty: 5 },
+            ]
+        );
+        assert_eq!(orderbook.ask_capacity(), 5);
+        assert!(!orderbook.contains_order(1));
+        assert_eq!(orderbook.resting_order(3).unwrap().qty(), 5);
+        assert_eq!(orderbook.bid_capacity(), 0, "nothing is left of the incoming order to rest");
+    }
+}
@@ -0,0 +1,204 @@
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+
+use super::error::EngineError;
+use super::orderbook::{BidOrAsk, Order, OrderId, Tick};
+
+/// How far a trailing stop stays behind the best price traded since it was placed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TrailingOffset {
+    /// A number of the market's tick sizes.
+    Ticks(u64),
+    /// A percentage of the price, e.g. `1.5` for 1.5%.
+    Percent(Decimal),
+}
+
+impl TrailingOffset {
+    pub fn validate(&self) -> Result<(), EngineError> {
+        let valid = match *self {
+            TrailingOffset::Ticks(ticks) => ticks > 0,
+            TrailingOffset::Percent(percent) => percent > Decimal::ZERO && percent < Decimal::ONE_HUNDRED,
+        };
+        if !valid {
+            return Err(EngineError::InvalidRequest(format!("invalid trailing offset {:?}", self)));
+        }
+        Ok(())
+    }
+
+    /// Stop price `reference` puts a trailing stop on `side` at, rounded away from
+    /// `reference` onto a multiple of `tick_size`: below it for sells, above it for buys.
+    pub fn stop_for(&self, side: BidOrAsk, reference: Tick, tick_size: Tick) -> Tick {
+        let distance = match *self {
+            TrailingOffset::Ticks(ticks) => ticks as Tick * tick_size,
+            TrailingOffset::Percent(percent) => (Decimal::from(reference) * percent / Decimal::ONE_HUNDRED)
+                .ceil()
+                .to_i64()
+                .unwrap_or(Tick::MAX),
+        };
+        match side {
+            BidOrAsk::Ask => reference.saturating_sub(distance).div_euclid(tick_size) * tick_size,
+            BidOrAsk::Bid => (reference.saturating_add(distance) + tick_size - 1).div_euclid(tick_size) * tick_size,
+        }
+    }
+}
+
+/// A stop order waiting for the last trade price to reach `stop_price`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingStop {
+    pub order: Order,
+    pub stop_price: Tick,
+    /// Price of the limit order placed once triggered, a market order when `None`.
+    pub limit_price: Option<Tick>,
+    /// Set for trailing stops, whose `stop_price` follows the last trade price.
+    #[serde(default)]
+    pub trailing: Option<TrailingOffset>,
+}
+
+/// Stop orders of one market. Buy stops trigger once the last trade price rises to their
+/// stop price, sell stops once it falls to it.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(from = "TriggerBookData")]
+pub struct TriggerBook {
+    // (stop price, arrival) -> order id
+    buys: BTreeMap<(Tick, u64), OrderId>,
+    // (negated stop price, arrival) -> order id, so the highest stop comes first
+    sells: BTreeMap<(Tick, u64), OrderId>,
+    // order id -> (stop, arrival)
+    stops: HashMap<OrderId, (PendingStop, u64)>,
+    next_arrival: u64,
+}
+
+// Trigger books are (de)serialized as their stops in arrival order; the price indexes
+// are rebuilt from them.
+#[derive(Deserialize)]
+struct TriggerBookData {
+    stops: Vec<(PendingStop, u64)>,
+    next_arrival: u64,
+}
+
+impl From<TriggerBookData> for TriggerBook {
+    fn from(data: TriggerBookData) -> TriggerBook {
+        let mut book = TriggerBook::new();
+        for (stop, arrival) in data.stops {
+            let order_id = stop.order.id();
+            let key = Self::key(stop.order.bid_or_ask(), stop.stop_price, arrival);
+            book.side_mut(stop.order.bid_or_ask()).insert(key, order_id);
+            book.stops.insert(order_id, (stop, arrival));
+        }
+        book.next_arrival = data.next_arrival;
+        book
+    }
+}
+
+impl Serialize for TriggerBook {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut stops: Vec<&(PendingStop, u64)> = self.stops.values().collect();
+        stops.sort_unstable_by_key(|(_, arrival)| *arrival);
+        let mut state = serializer.serialize_struct("TriggerBook", 2)?;
+        state.serialize_field("stops", &stops)?;
+        state.serialize_field("next_arrival", &self.next_arrival)?;
+        state.end()
+    }
+}
+
+impl TriggerBook {
+    pub fn new() -> TriggerBook {
+        TriggerBook::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.stops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stops.is_empty()
+    }
+
+    pub fn get(&self, order_id: OrderId) -> Option<&PendingStop> {
+        self.stops.get(&order_id).map(|(stop, _)| stop)
+    }
+
+    fn key(side: BidOrAsk, stop_price: Tick, arrival: u64) -> (Tick, u64) {
+        match side {
+            BidOrAsk::Bid => (stop_price, arrival),
+            BidOrAsk::Ask => (-stop_price, arrival),
+        }
+    }
+
+    fn side_mut(&mut self, side: BidOrAsk) -> &mut BTreeMap<(Tick, u64), OrderId> {
+        match side {
+            BidOrAsk::Bid => &mut self.buys,
+            BidOrAsk::Ask => &mut self.sells,
+        }
+    }
+
+    pub fn insert(&mut self, stop: PendingStop) {
+        let arrival = self.next_arrival;
+        self.next_arrival += 1;
+        let order_id = stop.order.id();
+        let key = Self::key(stop.order.bid_or_ask(), stop.stop_price, arrival);
+        self.side_mut(stop.order.bid_or_ask()).insert(key, order_id);
+        self.stops.insert(order_id, (stop, arrival));
+    }
+
+    pub fn remove(&mut self, order_id: OrderId) -> Option<PendingStop> {
+        let (stop, arrival) = self.stops.remove(&order_id)?;
+        let key = Self::key(stop.order.bid_or_ask(), stop.stop_price, arrival);
+        self.side_mut(stop.order.bid_or_ask()).remove(&key);
+        Some(stop)
+    }
+
+    /// Moves a stop to `stop_price`, keeping its arrival order.
+    pub fn set_stop_price(&mut self, order_id: OrderId, stop_price: Tick) -> Option<()> {
+        let (stop, arrival) = self.stops.get_mut(&order_id)?;
+        let (side, old_key) = (stop.order.bid_or_ask(), Self::key(stop.order.bid_or_ask(), stop.stop_price, *arrival));
+        let new_key = Self::key(side, stop_price, *arrival);
+        stop.stop_price = stop_price;
+        let levels = self.side_mut(side);
+        levels.remove(&old_key);
+        levels.insert(new_key, order_id);
+        Some(())
+    }
+
+    /// Moves every trailing stop the last trade price `last` improves: up for sells, down for
+    /// buys, never back. Returns the moved stops with their new stop price, by order id.
+    pub fn trail(&mut self, last: Tick, tick_size: Tick) -> Vec<(OrderId, Tick)> {
+        let mut moved: Vec<(OrderId, Tick)> = self
+            .stops
+            .values()
+            .filter_map(|(stop, _)| {
+                let side = stop.order.bid_or_ask();
+                let candidate = stop.trailing?.stop_for(side, last, tick_size);
+                let improves = match side {
+                    BidOrAsk::Bid => candidate < stop.stop_price,
+                    BidOrAsk::Ask => candidate > stop.stop_price,
+                };
+                improves.then_some((stop.order.id(), candidate))
+            })
+            .collect();
+        moved.sort_unstable();
+        for (order_id, stop_price) in &moved {
+            self.set_stop_price(*order_id, *stop_price);
+        }
+        moved
+    }
+
+    /// Takes out every stop the last trade price `last` triggers, in the order they fire:
+    /// buy stops from the lowest stop price up, then sell stops from the highest down,
+    /// earlier arrivals first on equal prices.
+    pub fn take_triggered(&mut self, last: Tick) -> Vec<PendingStop> {
+        let mut triggered: Vec<OrderId> = self
+            .buys
+            .range(..=(last, u64::MAX))
+            .map(|(_, order_id)| *order_id)
+            .collect();
+        triggered.extend(self.sells.range(..=(-last, u64::MAX)).map(|(_, order_id)| *order_id));
+        triggered
+            .into_iter()
+            .filter_map(|order_id| self.remove(order_id))
+            .collect()
+    }
+}